@@ -1,13 +1,14 @@
-use cosmwasm_bignumber::Uint256;
-use cosmwasm_std::{Coin, CosmosMsg, Response, StdResult, SubMsg, to_binary, Uint128, WasmMsg};
+use cosmwasm_bignumber::{Decimal256, Uint256};
+use cosmwasm_std::{Coin, CosmosMsg, Deps, QueryRequest, Response, SubMsg, to_binary, Uint128, WasmMsg, WasmQuery};
 use cw20::Cw20ExecuteMsg;
 
-use structured_note_package::anchor::{AnchorCW20HookMsg, AnchorMarketMsg};
+use structured_note_package::anchor::{AnchorCW20HookMsg, AnchorEpochStateResponse, AnchorMarketMsg, AnchorMarketQueryMsg};
 
+use crate::error::ContractError;
 use crate::state::Config;
 use crate::SubmsgIds;
 
-pub fn deposit_stable(config: Config, deposit_amount: Uint256) -> StdResult<Response> {
+pub fn deposit_stable(config: Config, operation_id: u64, deposit_amount: Uint256) -> Result<Response, ContractError> {
     let deposit_coin = Coin {
         denom: config.stable_denom.clone(),
         amount: deposit_amount.into(),
@@ -17,7 +18,7 @@ pub fn deposit_stable(config: Config, deposit_amount: Uint256) -> StdResult<Resp
             contract_addr: config.anchor_market_contract.to_string(),
             msg: to_binary(&AnchorMarketMsg::DepositStable {})?,
             funds: vec![deposit_coin],
-        }), SubmsgIds::DepositStable.id(),
+        }), SubmsgIds::DepositStable.reply_id(operation_id),
         ))
         .add_attributes(vec![
             ("action", "deposit_stable_to_anchor_market"),
@@ -25,7 +26,7 @@ pub fn deposit_stable(config: Config, deposit_amount: Uint256) -> StdResult<Resp
         ]))
 }
 
-pub fn redeem_stable(config: Config, amount: Uint128) -> StdResult<Response> {
+pub fn redeem_stable(config: Config, operation_id: u64, amount: Uint128) -> Result<Response, ContractError> {
     Ok(Response::new()
         .add_submessage(SubMsg::reply_on_success(
             CosmosMsg::Wasm(WasmMsg::Execute {
@@ -37,10 +38,30 @@ pub fn redeem_stable(config: Config, amount: Uint128) -> StdResult<Response> {
                 })?,
                 funds: vec![],
             }),
-            SubmsgIds::RedeemStable.id(),
+            SubmsgIds::RedeemStable.reply_id(operation_id),
         ))
         .add_attributes(vec![
             ("action", "redeem_stable"),
             ("aterra_amount", &amount.to_string()),
         ]))
-}
\ No newline at end of file
+}
+
+// Exchange rate is stable per one aterra, with interest accrued up to block_height
+pub fn query_aterra_exchange_rate(deps: Deps, config: &Config, block_height: u64) -> Result<Decimal256, ContractError> {
+    let epoch_state: AnchorEpochStateResponse = deps.querier.query(&QueryRequest::Wasm(WasmQuery::Smart {
+        contract_addr: config.anchor_market_contract.to_string(),
+        msg: to_binary(&AnchorMarketQueryMsg::EpochState {
+            block_height: Some(block_height),
+            distributed_interest: None,
+        })?,
+    }))?;
+    Ok(epoch_state.exchange_rate)
+}
+
+pub fn calculate_aterra_amount(stable_amount: Uint128, exchange_rate: Decimal256) -> Uint128 {
+    (Uint256::from(stable_amount) / exchange_rate).into()
+}
+
+pub fn calculate_redeemed_stable(aterra_amount: Uint128, exchange_rate: Decimal256) -> Uint128 {
+    (Uint256::from(aterra_amount) * exchange_rate).into()
+}
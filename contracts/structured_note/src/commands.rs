@@ -1,112 +1,304 @@
 use cosmwasm_bignumber::Uint256;
-use cosmwasm_std::{BalanceResponse, BankMsg, BankQuery, Coin, CosmosMsg, Decimal, DepsMut, Env, Fraction, MessageInfo, QueryRequest, Response, StdError, StdResult, Uint128};
+use cosmwasm_std::{Addr, BankMsg, Coin, CosmosMsg, Decimal, Deps, DepsMut, Env, from_binary, MessageInfo, Response, StdResult, Storage, to_binary, Uint128, WasmMsg};
+use cw20::{Cw20ExecuteMsg, Cw20ReceiveMsg};
 
 use structured_note_package::mirror::MirrorAssetConfigResponse;
+use structured_note_package::structured_note::{Cw20HookMsg, ReceiveAs, UpdateConfigMsg};
 
-use crate::anchor::deposit_stable as anc_deposit_stable;
-use crate::mirror::{get_assets_prices, query_masset_config, query_mirror_mint_config, withdraw_collateral};
-use crate::state::{add_farmer_to_cdp, DepositState, load_config, load_position, load_withdraw_state, may_load_cdp, may_load_position, Position, remove_farmer_from_cdp, remove_position, save_deposit_state, save_is_open, save_is_raw, save_position, save_withdraw_state, update_is_open, WithdrawState};
-use crate::terraswap::query_pair_addr;
-use crate::utils::{decimal_division, decimal_multiplication};
+use crate::anchor::{calculate_aterra_amount, deposit_stable as anc_deposit_stable, query_aterra_exchange_rate};
+use crate::error::ContractError;
+use crate::mirror::{AssetsPrices, check_assets_prices_age, deposit_to_cdp, get_assets_prices, mint_masset, may_query_cdp, open_cdp, query_assets_prices, query_cdp, query_masset_config, query_mirror_mint_config, query_position_state, withdraw_all_collateral, withdraw_collateral};
+use crate::state::{add_farmer_to_cdp, AssetConfig, Balances, Config, DepositState, freeze_asset, is_asset_frozen, load_cdp, load_config, load_is_open, load_is_paused, load_is_raw, load_position, load_total_collateral, load_withdraw_state, may_load_asset_config, may_load_cdp, may_load_position, may_load_protect_state, Position, PositionState, ProtectState, remove_asset_config, remove_cdp, remove_farmer_from_cdp, remove_operation, remove_position, save_asset_config, save_config, save_deposit_state, save_is_open, save_is_paused, save_is_raw, save_position, save_protect_state, save_withdraw_state, start_operation, unfreeze_asset, update_cdp_amounts, WithdrawState};
+use crate::simulation::{estimate_sell_price_impact, plan_deposit_collateral, plan_deposit_mints};
+use crate::terraswap::{check_price_deviation, query_pool_reserves, resolve_pair_addr, swap_stable_to_masset_msg};
+use crate::utils::{decimal_division, decimal_multiplication, divide_by_decimal, query_balance, query_token_balance};
 
+const MIN_LEVERAGE: u8 = 1;
+const MAX_LEVERAGE: u8 = 5;
+const TARGET_LEVERAGE_TOLERANCE_PERCENT: u64 = 1;
+const DEPOSIT_FILL_MARGIN_PERMILLE: u64 = 1;
+
+pub fn receive_cw20(deps: DepsMut, env: Env, info: MessageInfo, cw20_msg: Cw20ReceiveMsg) -> Result<Response, ContractError> {
+    let config = load_config(deps.storage)?;
+    if info.sender != config.aterra_addr {
+        return Err(ContractError::UnsupportedToken { token_addr: info.sender.to_string() });
+    };
+    let farmer_addr = deps.api.addr_validate(&cw20_msg.sender)?;
+    match from_binary(&cw20_msg.msg)? {
+        Cw20HookMsg::Deposit {
+            masset_token,
+            leverage,
+            target_leverage,
+            aim_collateral_ratio,
+            max_spread,
+        } => deposit_aterra(deps, env, farmer_addr, cw20_msg.amount, masset_token, leverage, target_leverage, aim_collateral_ratio, max_spread),
+    }
+}
+
+// Deposit principal before protocol fee
+#[derive(Clone, Copy)]
+pub enum DepositFunds {
+    Stable(Uint128),
+    Aterra(Uint128),
+}
+
+impl DepositFunds {
+    pub fn amount(&self) -> Uint128 {
+        match self {
+            DepositFunds::Stable(amount) | DepositFunds::Aterra(amount) => *amount,
+        }
+    }
+}
+
+// Remaining collateral and loan which deposits can add to masset CDP, None if not capped
+pub struct DepositCapacity {
+    pub collateral: Option<Uint128>,
+    pub loan: Option<Uint128>,
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn deposit(
-    deps: DepsMut,
+    mut deps: DepsMut,
+    env: Env,
     info: MessageInfo,
     masset_token: String,
     leverage: Option<u8>,
+    target_leverage: Option<Decimal>,
+    aim_collateral_ratio: Decimal,
+    max_spread: Option<Decimal>,
+) -> Result<Response, ContractError> {
+    let operation_id = start_operation(deps.storage, env.block.height)?;
+    let config = load_config(deps.storage)?;
+
+    let deposit_amount: Uint256 = info
+        .funds
+        .iter()
+        .find(|c| c.denom == config.stable_denom)
+        .map(|c| Uint256::from(c.amount))
+        .unwrap_or_else(Uint256::zero);
+
+    // Cannot deposit zero amount
+    if deposit_amount.is_zero() {
+        return Err(ContractError::ZeroDeposit {});
+    };
+
+    let (_, accepted_amount) = start_deposit(deps.branch(), &env, operation_id, &info.sender, masset_token, leverage, target_leverage, aim_collateral_ratio, max_spread, DepositFunds::Stable(deposit_amount.into()))?;
+    let stable_denom = config.stable_denom.clone();
+    let response = deposit_stable_with_fee(config, operation_id, Uint256::from(accepted_amount))?;
+    Ok(add_stable_refund(response, stable_denom, &info.sender, Uint128::from(deposit_amount) - accepted_amount))
+}
+
+// Part of deposit which is not filled due to deposit caps is returned to farmer
+fn add_stable_refund(response: Response, stable_denom: String, farmer_addr: &Addr, refund_amount: Uint128) -> Response {
+    if refund_amount.is_zero() {
+        return response;
+    };
+    response
+        .add_message(CosmosMsg::Bank(BankMsg::Send {
+            to_address: farmer_addr.to_string(),
+            amount: vec![
+                Coin {
+                    denom: stable_denom,
+                    amount: refund_amount,
+                }],
+        }))
+        .add_attribute("refund_amount", refund_amount.to_string())
+}
+
+// aterra is already a collateral, so DepositStable step is skipped
+#[allow(clippy::too_many_arguments)]
+pub fn deposit_aterra(
+    mut deps: DepsMut,
+    env: Env,
+    farmer_addr: Addr,
+    amount: Uint128,
+    masset_token: String,
+    leverage: Option<u8>,
+    target_leverage: Option<Decimal>,
+    aim_collateral_ratio: Decimal,
+    max_spread: Option<Decimal>,
+) -> Result<Response, ContractError> {
+    if amount.is_zero() {
+        return Err(ContractError::ZeroDeposit {});
+    };
+    let operation_id = start_operation(deps.storage, env.block.height)?;
+    let config = load_config(deps.storage)?;
+    if calculate_protocol_fee(&config, amount).1.is_zero() {
+        return Err(ContractError::ZeroDepositAfterFee {});
+    };
+
+    let (state, accepted_amount) = start_deposit(deps.branch(), &env, operation_id, &farmer_addr, masset_token, leverage, target_leverage, aim_collateral_ratio, max_spread, DepositFunds::Aterra(amount))?;
+    let (fee_amount, net_deposit_amount) = calculate_protocol_fee(&config, accepted_amount);
+    let refund_amount = amount - accepted_amount;
+    let treasury = config.nexus_treasury.to_string();
+    let aterra_addr = config.aterra_addr.to_string();
+    let response = if load_is_open(deps.storage, operation_id)? {
+        open_cdp(config, operation_id, state, net_deposit_amount)?
+    } else {
+        let cdp = load_cdp(deps.storage, &state.masset_token)?;
+        deposit_to_cdp(config, operation_id, cdp.idx, net_deposit_amount)?
+    };
+    let mut response = response.add_attribute("protocol_fee_amount", fee_amount.to_string());
+    if !refund_amount.is_zero() {
+        response = response
+            .add_message(CosmosMsg::Wasm(WasmMsg::Execute {
+                contract_addr: aterra_addr.clone(),
+                msg: to_binary(&Cw20ExecuteMsg::Transfer {
+                    recipient: farmer_addr.to_string(),
+                    amount: refund_amount,
+                })?,
+                funds: vec![],
+            }))
+            .add_attribute("refund_amount", refund_amount.to_string());
+    };
+    if fee_amount.is_zero() {
+        return Ok(response);
+    };
+    Ok(response
+        .add_message(CosmosMsg::Wasm(WasmMsg::Execute {
+            contract_addr: aterra_addr,
+            msg: to_binary(&Cw20ExecuteMsg::Transfer {
+                recipient: treasury,
+                amount: fee_amount,
+            })?,
+            funds: vec![],
+        })))
+}
+
+// Validates deposit, saves state of the deposit loop and attaches farmer to CDP.
+// Returns accepted amount of funds, which is less than deposited one if deposit is partially filled due to caps
+#[allow(clippy::too_many_arguments)]
+pub fn start_deposit(
+    deps: DepsMut,
+    env: &Env,
+    operation_id: u64,
+    farmer_addr: &Addr,
+    masset_token: String,
+    leverage: Option<u8>,
+    target_leverage: Option<Decimal>,
     aim_collateral_ratio: Decimal,
-) -> StdResult<Response> {
-    save_is_raw(deps.storage, false)?;
-    save_is_open(deps.storage, false)?;
+    max_spread: Option<Decimal>,
+    funds: DepositFunds,
+) -> Result<(DepositState, Uint128), ContractError> {
     let config = load_config(deps.storage)?;
 
     let mirror_mint_config = query_mirror_mint_config(deps.as_ref(), config.mirror_mint_contract.to_string())?;
 
     let masset_token = deps.api.addr_validate(&masset_token)?;
+    check_deposits_enabled(deps.storage, &masset_token)?;
     let masset_config = query_masset_config(deps.as_ref(), &masset_token)?;
+    let asset_config = load_asset_config(deps.storage, &masset_token)?;
 
-    let pair_addr = deps.api.addr_validate(&query_pair_addr(deps.as_ref(), &deps.api.addr_validate(&mirror_mint_config.terraswap_factory)?, &masset_token)?)?;
+    let pair_addr = resolve_pair_addr(deps.as_ref(), &mirror_mint_config, &asset_config)?;
 
-    let (collateral_price, asset_price) = get_assets_prices(deps.as_ref(), &mirror_mint_config, &config, &masset_token)?;
-    let asset_price_in_collateral_asset = decimal_division(collateral_price, asset_price)?;
+    let (collateral_price, asset_price, collateral_multiplier) = get_assets_prices(deps.as_ref(), env, &mirror_mint_config, &config, &masset_token)?;
+    let asset_price_in_collateral_asset = decimal_division(asset_price, collateral_price)?;
 
-    let min_collateral_ratio = decimal_multiplication(&masset_config.min_collateral_ratio, &config.min_over_collateralization);
+    let min_collateral_ratio = calculate_safe_collateral_ratio(&asset_config, &masset_config, collateral_multiplier);
     if aim_collateral_ratio < min_collateral_ratio {
-        return Err(StdError::generic_err("Aim collateral ratio too low"));
+        return Err(ContractError::CollateralRatioTooLow {
+            collateral_ratio: aim_collateral_ratio,
+            min_collateral_ratio,
+        });
     };
 
     validate_masset(&masset_config)?;
+    let max_spread = resolve_max_spread(&config, max_spread)?;
 
-    let deposit_amount: Uint256 = info
-        .funds
-        .iter()
-        .find(|c| c.denom == config.stable_denom)
-        .map(|c| Uint256::from(c.amount))
-        .unwrap_or_else(Uint256::zero);
-
-    // Cannot deposit zero amount
-    if deposit_amount.is_zero() {
-        return Err(StdError::generic_err("Deposit amount is zero"));
+    let position = may_load_position(deps.storage, farmer_addr, &masset_token)?;
+    let target_leverage = target_leverage.or_else(|| position.as_ref().and_then(|p| p.target_leverage));
+    // iterations for target leverage are planned below
+    let leverage = match target_leverage {
+        Some(_) => 0,
+        None => resolve_leverage(farmer_addr, &masset_token, position.as_ref(), leverage, asset_config.max_leverage)?,
     };
 
-    if let Some(p) = may_load_position(deps.storage, &info.sender, &masset_token)? {
-        save_deposit_state(deps.storage, &DepositState {
-            farmer_addr: p.farmer_addr,
-            masset_token: p.masset_token,
-            leverage: p.leverage,
-            cur_iteration_index: 0,
-            asset_price_in_collateral_asset,
-            pair_addr,
-            aim_collateral_ratio,
-        })?;
-    } else {
-        if let Some(leverage) = leverage {
-            if !(1..=5).contains(&leverage) {
-                return Err(StdError::generic_err("Invalid message: leverage iterations amount should be from 1 to 5."));
-            }
-            save_deposit_state(deps.storage, &DepositState {
-                farmer_addr: info.sender.clone(),
-                masset_token: masset_token.clone(),
-                leverage,
-                cur_iteration_index: 0,
-                asset_price_in_collateral_asset,
-                pair_addr,
-                aim_collateral_ratio,
-            })?;
-        } else {
-            return Err(StdError::generic_err(format!(
-                "There isn't position: farmer_addr: {}, masset_token: {}. To create new position provide 'leverage'",
-                &info.sender.to_string(),
-                &masset_token.to_string())));
+    let mut state = DepositState {
+        farmer_addr: farmer_addr.clone(),
+        masset_token: masset_token.clone(),
+        leverage,
+        cur_iteration_index: 0,
+        asset_price_in_collateral_asset,
+        pair_addr,
+        aim_collateral_ratio,
+        masset_price: asset_price,
+        max_spread,
+        target_loan: None,
+        target_leverage,
+    };
+    let position_state = match &position {
+        Some(p) => query_position_state(deps.as_ref(), p)?,
+        None => PositionState::default(),
+    };
+    let deposit_collateral_of = |amount: Uint128| -> Result<Uint128, ContractError> {
+        let (_, net_amount) = calculate_protocol_fee(&config, amount);
+        match funds {
+            DepositFunds::Stable(_) => Ok(divide_by_decimal(net_amount, collateral_price)?),
+            DepositFunds::Aterra(_) => Ok(net_amount),
         }
+    };
+    let plan_target = |state: &mut DepositState, deposit_collateral: Uint128| -> Result<(), ContractError> {
+        if let Some(target_leverage) = target_leverage {
+            let (iterations, target_loan) = plan_target_leverage(state, &position_state, deposit_collateral, collateral_price, target_leverage, asset_config.max_leverage)?;
+            state.leverage = iterations;
+            state.target_loan = Some(target_loan);
+        };
+        Ok(())
+    };
+    let mut accepted_amount = funds.amount();
+    let mut deposit_collateral = deposit_collateral_of(accepted_amount)?;
+    plan_target(&mut state, deposit_collateral)?;
+
+    let capacity = calculate_deposit_capacity(deps.storage, &config, &asset_config)?;
+    let fill_ratio = calculate_deposit_fill_ratio(&state, &position_state, deposit_collateral, collateral_price, &capacity)?;
+    if fill_ratio < Decimal::one() {
+        accepted_amount = accepted_amount * fill_ratio;
+        deposit_collateral = deposit_collateral_of(accepted_amount)?;
+        if deposit_collateral.is_zero() {
+            return Err(ContractError::DepositCapReached { masset_token: masset_token.to_string() });
+        };
+        plan_target(&mut state, deposit_collateral)?;
+    };
+    check_deposit_cap(&state, &position_state, deposit_collateral, collateral_price, &capacity)?;
+    check_price_deviation(deps.as_ref(), &config, &state.pair_addr, &masset_token, asset_price)?;
+    check_deposit_price_impact(deps.as_ref(), &config, &state, &position_state, deposit_collateral, collateral_price)?;
+    save_deposit_state(deps.storage, operation_id, &state)?;
+
+    if position.is_none() {
         if let Some(cdp) = may_load_cdp(deps.storage, &masset_token)? {
+            // joiner of existing CDP keeps leverage of its first deposit, same as position opening CDP,
+            // so its later deposits and rebalances loop the same number of iterations
             save_position(deps.storage, &Position {
-                farmer_addr: info.sender.clone(),
+                farmer_addr: farmer_addr.clone(),
                 masset_token: masset_token.clone(),
                 cdp_idx: cdp.idx,
-                leverage: 0,
-                loan: Default::default(),
-                collateral: Default::default(),
+                leverage: state.leverage,
+                loan_shares: Default::default(),
+                collateral_shares: Default::default(),
                 aim_collateral_ratio,
+                target_leverage,
             })?;
-            add_farmer_to_cdp(deps.storage, cdp.idx, info.sender, masset_token)?;
+            add_farmer_to_cdp(deps.storage, cdp.idx, farmer_addr.clone(), masset_token)?;
         } else {
-            update_is_open(deps.storage, true)?;
+            save_is_open(deps.storage, operation_id, true)?;
         }
     }
-    anc_deposit_stable(config, deposit_amount)
+    Ok((state, accepted_amount))
 }
 
 pub fn raw_deposit(
     deps: DepsMut,
+    env: Env,
     info: MessageInfo,
     masset_token: String,
-) -> StdResult<Response> {
-    save_is_raw(deps.storage, true)?;
-    save_is_open(deps.storage, false)?;
+) -> Result<Response, ContractError> {
+    let operation_id = start_operation(deps.storage, env.block.height)?;
+    save_is_raw(deps.storage, operation_id, true)?;
     let config = load_config(deps.storage)?;
 
     let masset_token = deps.api.addr_validate(&masset_token)?;
+    check_deposits_enabled(deps.storage, &masset_token)?;
+    let asset_config = load_asset_config(deps.storage, &masset_token)?;
     let masset_config = query_masset_config(deps.as_ref(), &masset_token)?;
 
     validate_masset(&masset_config)?;
@@ -119,11 +311,25 @@ pub fn raw_deposit(
         .unwrap_or_else(Uint256::zero);
 
     if deposit_amount.is_zero() {
-        return Err(StdError::generic_err("Deposit amount is zero"));
+        return Err(ContractError::ZeroDeposit {});
     };
 
     if let Some(p) = may_load_position(deps.storage, &info.sender, &masset_token)? {
-        save_deposit_state(deps.storage, &DepositState {
+        // raw deposit adds collateral only, so only collateral caps limit it
+        let exchange_rate = query_aterra_exchange_rate(deps.as_ref(), &config, env.block.height)?;
+        let deposit_collateral_of = |amount: Uint128| calculate_aterra_amount(calculate_protocol_fee(&config, amount).1, exchange_rate);
+        let capacity = calculate_deposit_capacity(deps.storage, &config, &asset_config)?;
+        let mut accepted_amount = Uint128::from(deposit_amount);
+        let fill_ratio = apply_fill_margin(capacity_ratio(capacity.collateral, deposit_collateral_of(accepted_amount)));
+        if fill_ratio < Decimal::one() {
+            accepted_amount = accepted_amount * fill_ratio;
+            if deposit_collateral_of(accepted_amount).is_zero() {
+                return Err(ContractError::DepositCapReached { masset_token: masset_token.to_string() });
+            };
+        };
+        check_capacity("collateral", capacity.collateral, deposit_collateral_of(accepted_amount))?;
+
+        save_deposit_state(deps.storage, operation_id, &DepositState {
             farmer_addr: p.farmer_addr.clone(),
             masset_token: p.masset_token,
             leverage: p.leverage,
@@ -131,110 +337,308 @@ pub fn raw_deposit(
             asset_price_in_collateral_asset: Decimal::default(),    //not used on raw withdraw
             pair_addr: p.farmer_addr,   //not used on raw withdraw
             aim_collateral_ratio: Decimal::default(),   // not used on raw withdraw
+            masset_price: Decimal::default(),   // not used on raw withdraw
+            max_spread: Decimal::default(),   // not used on raw withdraw
+            target_loan: None,
+            target_leverage: None,
         })?;
-        anc_deposit_stable(config, deposit_amount)
+        let stable_denom = config.stable_denom.clone();
+        let response = deposit_stable_with_fee(config, operation_id, Uint256::from(accepted_amount))?;
+        Ok(add_stable_refund(response, stable_denom, &info.sender, Uint128::from(deposit_amount) - accepted_amount))
     } else {
-        return Err(StdError::generic_err(format!(
-            "There isn't position: farmer_addr: {}, masset_token: {}. To create new position provide 'leverage'",
-            &info.sender.to_string(),
-            &masset_token.to_string())));
+        Err(ContractError::LeverageRequired {
+            farmer_addr: info.sender.to_string(),
+            masset_token: masset_token.to_string(),
+        })
+    }
+}
+
+// Existing position keeps its leverage, new one needs valid leverage
+pub fn resolve_leverage(farmer_addr: &Addr, masset_token: &Addr, position: Option<&Position>, leverage: Option<u8>, max_leverage: u8) -> Result<u8, ContractError> {
+    match (position, leverage) {
+        (Some(p), _) => Ok(p.leverage),
+        (None, Some(leverage)) => {
+            if !(MIN_LEVERAGE..=max_leverage).contains(&leverage) {
+                return Err(ContractError::LeverageOutOfRange {
+                    leverage,
+                    min: MIN_LEVERAGE,
+                    max: max_leverage,
+                });
+            };
+            Ok(leverage)
+        }
+        (None, None) => Err(ContractError::LeverageRequired {
+            farmer_addr: farmer_addr.to_string(),
+            masset_token: masset_token.to_string(),
+        }),
     }
 }
 
-pub fn validate_masset(masset_config: &MirrorAssetConfigResponse) -> StdResult<Response> {
+// Exposure is collateral value to position net value. Loop mints up to the loan of target exposure,
+// so iterations are the number of mints needed to reach it
+pub fn plan_target_leverage(state: &DepositState, position_state: &PositionState, deposit_collateral: Uint128, collateral_price: Decimal, target_leverage: Decimal, max_leverage: u8) -> Result<(u8, Uint128), ContractError> {
+    if target_leverage <= Decimal::one() {
+        return Err(ContractError::InvalidTargetLeverage { target_leverage });
+    };
+    let price = state.asset_price_in_collateral_asset;
+    let net_value = (position_state.collateral + deposit_collateral).saturating_sub(position_state.loan * price);
+    let target_loan = divide_by_decimal(net_value * (target_leverage - Decimal::one()), price)?;
+
+    let planning_state = DepositState {
+        leverage: max_leverage,
+        target_loan: Some(target_loan),
+        ..state.clone()
+    };
+    let mints = plan_deposit_mints(&planning_state, position_state.collateral, position_state.loan, deposit_collateral, collateral_price)?;
+    let final_loan = mints.iter().fold(position_state.loan, |loan, mint| loan + *mint);
+    if final_loan < target_loan * (Decimal::one() - Decimal::percent(TARGET_LEVERAGE_TOLERANCE_PERCENT)) {
+        return Err(ContractError::TargetLeverageUnreachable {
+            target_leverage,
+            max_leverage: Decimal::one() + Decimal::from_ratio(final_loan * price, net_value),
+        });
+    };
+    Ok((mints.len() as u8, target_loan))
+}
+
+// Swap spread of the loop moves position away from planned leverage, so deposit is reverted
+// when leverage after the last deposit to CDP is out of target leverage tolerance
+pub fn check_target_leverage(state: &DepositState, position_state: &PositionState) -> Result<(), ContractError> {
+    let target_leverage = match state.target_leverage {
+        Some(target_leverage) => target_leverage,
+        None => return Ok(()),
+    };
+    let net_value = position_state.collateral.saturating_sub(position_state.loan * state.asset_price_in_collateral_asset);
+    let leverage = if net_value.is_zero() {
+        Decimal::MAX
+    } else {
+        Decimal::from_ratio(position_state.collateral, net_value)
+    };
+    let tolerance = decimal_multiplication(&target_leverage, &Decimal::percent(TARGET_LEVERAGE_TOLERANCE_PERCENT));
+    if leverage + tolerance < target_leverage || leverage > target_leverage + tolerance {
+        return Err(ContractError::TargetLeverageMissed { target_leverage, leverage });
+    };
+    Ok(())
+}
+
+pub fn calculate_protocol_fee(config: &Config, deposit_amount: Uint128) -> (Uint128, Uint128) {
+    let fee_amount = deposit_amount * config.protocol_fee;
+    (fee_amount, deposit_amount - fee_amount)
+}
+
+// Protocol fee is charged on deposit principal before it goes to Anchor
+pub fn deposit_stable_with_fee(config: Config, operation_id: u64, deposit_amount: Uint256) -> Result<Response, ContractError> {
+    let (fee_amount, net_deposit_amount) = calculate_protocol_fee(&config, deposit_amount.into());
+    if net_deposit_amount.is_zero() {
+        return Err(ContractError::ZeroDepositAfterFee {});
+    };
+
+    let treasury = config.nexus_treasury.to_string();
+    let stable_denom = config.stable_denom.clone();
+    let response = anc_deposit_stable(config, operation_id, Uint256::from(net_deposit_amount))?
+        .add_attribute("protocol_fee_amount", fee_amount.to_string());
+    if fee_amount.is_zero() {
+        return Ok(response);
+    };
+    Ok(response
+        .add_message(CosmosMsg::Bank(BankMsg::Send {
+            to_address: treasury,
+            amount: vec![
+                Coin {
+                    denom: stable_denom,
+                    amount: fee_amount,
+                }],
+        })))
+}
+
+pub fn validate_masset(masset_config: &MirrorAssetConfigResponse) -> Result<Response, ContractError> {
     if masset_config.end_price.is_some() {
-        return Err(StdError::generic_err("Invalid mirror asset: delisted  or migrated".to_string()));
+        return Err(ContractError::InvalidMasset { reason: "delisted or migrated".to_string() });
     };
     if masset_config.ipo_params.is_some() {
-        return Err(StdError::generic_err("Invalid mirror asset: pre ipo state".to_string()));
+        return Err(ContractError::InvalidMasset { reason: "pre ipo state".to_string() });
     };
     Ok(Default::default())
 }
 
-pub fn exit(position: Position) -> StdResult<Response> {
+pub fn exit(position: Position, position_state: PositionState) -> Result<Response, ContractError> {
     Ok(Response::new()
         .add_attributes(vec![
             ("action", "deposit_stable"),
-            ("farmet_addr", &position.farmer_addr.to_string()),
-            ("masset_token", &position.masset_token.to_string()),
-            ("collateral", &position.collateral.to_string()),
-            ("loan", &position.loan.to_string()),
+            ("farmer_addr", position.farmer_addr.as_str()),
+            ("masset_token", position.masset_token.as_str()),
+            ("collateral", &position_state.collateral.to_string()),
+            ("loan", &position_state.loan.to_string()),
         ]))
 }
 
-pub fn withdraw(deps: DepsMut, info: MessageInfo, masset_token: String, aim_collateral: Uint128, aim_collateral_ratio: Decimal) -> StdResult<Response> {
+#[allow(clippy::too_many_arguments)]
+pub fn withdraw(deps: DepsMut, env: Env, info: MessageInfo, masset_token: String, aim_collateral: Uint128, aim_collateral_ratio: Decimal, max_spread: Option<Decimal>, receive_as: Option<ReceiveAs>) -> Result<Response, ContractError> {
+    let operation_id = start_operation(deps.storage, env.block.height)?;
     let masset_token = deps.api.addr_validate(&masset_token)?;
 
-    if let Some(position) = may_load_position(deps.storage, &info.sender, &masset_token)? {
-        if position.collateral < aim_collateral {
-            return Err(StdError::generic_err("Invalid msg: aim_collateral is greater then current!"));
-        };
+    let config = load_config(deps.storage)?;
+    let mirror_mint_config = query_mirror_mint_config(deps.as_ref(), config.mirror_mint_contract.to_string())?;
+    let prices = query_assets_prices(deps.as_ref(), &mirror_mint_config, &config, &masset_token)?;
+    check_assets_prices_age(&env, &config, &masset_token, &prices)?;
+    let (position, position_state, mut state) = prepare_withdraw(deps.as_ref(), &info.sender, &masset_token, aim_collateral, aim_collateral_ratio, max_spread, &prices)?;
+    state.receive_as = receive_as.unwrap_or_default();
+    state.balances_before = query_contract_balances(deps.as_ref(), &env, &config, &masset_token)?;
+    check_price_deviation(deps.as_ref(), &config, &state.pair_addr, &masset_token, state.masset_price)?;
+    save_withdraw_state(deps.storage, operation_id, &state)?;
+    let masset_price_in_collateral_asset = decimal_division(state.masset_price, state.collateral_price)?;
+    let amount_to_withdraw = calculate_withdraw_amount(position_state.collateral, position_state.loan, aim_collateral, masset_price_in_collateral_asset, state.safe_collateral_ratio);
+    withdraw_collateral(config, operation_id, position.cdp_idx, amount_to_withdraw)
+}
 
-        let config = load_config(deps.storage)?;
-        let masset_config = query_masset_config(deps.as_ref(), &masset_token)?;
-        let safe_collateral_ratio = decimal_multiplication(&masset_config.min_collateral_ratio, &config.min_over_collateralization);
-        if aim_collateral_ratio < safe_collateral_ratio {
-            return Err(StdError::generic_err(format!("aim_collateral_ratio lower than safe_collateral_ratio: {}", &safe_collateral_ratio)));
-        };
+// Validates withdraw aim against current position and builds state of the withdraw loop
+pub fn prepare_withdraw(deps: Deps, farmer_addr: &Addr, masset_token: &Addr, aim_collateral: Uint128, aim_collateral_ratio: Decimal, max_spread: Option<Decimal>, prices: &AssetsPrices) -> Result<(Position, PositionState, WithdrawState), ContractError> {
+    let position = match may_load_position(deps.storage, farmer_addr, masset_token)? {
+        Some(position) => position,
+        None => return Err(ContractError::position_not_found(farmer_addr, masset_token)),
+    };
+    let position_state = query_position_state(deps, &position)?;
+    if position_state.collateral < aim_collateral {
+        return Err(ContractError::AimCollateralTooHigh {
+            aim_collateral,
+            collateral: position_state.collateral,
+        });
+    };
 
-        let mirror_mint_config = query_mirror_mint_config(deps.as_ref(), config.mirror_mint_contract.to_string())?;
-        let (collateral_price, masset_price) = get_assets_prices(deps.as_ref(), &mirror_mint_config, &config, &masset_token)?;
-        let masset_price_in_collateral_asset = decimal_division(collateral_price, masset_price)?;
-        let loan_in_collateral_asset = position.loan * masset_price_in_collateral_asset;
-        let current_collateral_ratio = Decimal::from_ratio(position.collateral, loan_in_collateral_asset);
-        if aim_collateral_ratio > current_collateral_ratio {
-            return Err(StdError::generic_err(format!("aim_collateral_ratio greater than current_collateral_ratio: {}", &current_collateral_ratio)));
-        };
+    let config = load_config(deps.storage)?;
+    let max_spread = resolve_max_spread(&config, max_spread)?;
+    let masset_config = query_masset_config(deps, masset_token)?;
+    let asset_config = load_asset_config(deps.storage, masset_token)?;
+    let mirror_mint_config = query_mirror_mint_config(deps, config.mirror_mint_contract.to_string())?;
+    let (collateral_price, masset_price) = (prices.collateral_price, prices.masset_price);
+    let safe_collateral_ratio = calculate_safe_collateral_ratio(&asset_config, &masset_config, prices.collateral_multiplier);
+    if aim_collateral_ratio < safe_collateral_ratio {
+        return Err(ContractError::CollateralRatioTooLow {
+            collateral_ratio: aim_collateral_ratio,
+            min_collateral_ratio: safe_collateral_ratio,
+        });
+    };
+
+    let masset_price_in_collateral_asset = decimal_division(masset_price, collateral_price)?;
+    let current_collateral_ratio = calculate_collateral_ratio(position_state.collateral, position_state.loan, masset_price_in_collateral_asset);
+    if aim_collateral_ratio > current_collateral_ratio {
+        return Err(ContractError::AimCollateralRatioTooHigh {
+            aim_collateral_ratio,
+            current_collateral_ratio,
+        });
+    };
 
-        let aim_loan_in_collateral_asset = Uint128::from(aim_collateral.u128() * aim_collateral_ratio.denominator() / aim_collateral_ratio.numerator());
-        let aim_loan = Uint128::from(aim_loan_in_collateral_asset.u128() * masset_price_in_collateral_asset.denominator() / masset_price_in_collateral_asset.numerator());
+    let aim_loan = calculate_aim_loan(aim_collateral, aim_collateral_ratio, masset_price_in_collateral_asset)?;
 
-        let pair_addr = deps.api.addr_validate(&query_pair_addr(deps.as_ref(), &deps.api.addr_validate(&mirror_mint_config.terraswap_factory)?, &masset_token)?)?;
+    let pair_addr = resolve_pair_addr(deps, &mirror_mint_config, &asset_config)?;
 
-        save_withdraw_state(deps.storage, &WithdrawState {
-            farmer_addr: position.farmer_addr,
-            masset_token: position.masset_token,
-            aim_collateral,
-            aim_loan,
-            pair_addr,
-            collateral_price,
-            masset_price,
-            safe_collateral_ratio,
-        })?;
-        let amount_to_withdraw = calculate_withdraw_amount(position.collateral, position.loan, aim_loan, masset_price_in_collateral_asset, safe_collateral_ratio);
-        withdraw_collateral(config, position.cdp_idx, amount_to_withdraw)
-    } else {
-        Err(StdError::generic_err(format!(
-            "There isn't position: farmer_addr: {}, masset_token: {}.",
-            &info.sender.to_string(),
-            &masset_token.to_string())))
-    }
+    let state = WithdrawState {
+        farmer_addr: position.farmer_addr.clone(),
+        masset_token: position.masset_token.clone(),
+        aim_collateral,
+        aim_loan,
+        pair_addr,
+        collateral_price,
+        masset_price,
+        safe_collateral_ratio,
+        max_spread,
+        receive_as: ReceiveAs::Stable,
+        balances_before: Balances::default(),
+    };
+    Ok((position, position_state, state))
+}
+
+pub fn close_position(deps: DepsMut, env: Env, info: MessageInfo, masset_token: String) -> Result<Response, ContractError> {
+    let masset_token = deps.api.addr_validate(&masset_token)?;
+    let position = match may_load_position(deps.storage, &info.sender, &masset_token)? {
+        Some(position) => position,
+        None => {
+            return Err(ContractError::position_not_found(&info.sender, &masset_token));
+        }
+    };
+    let position_state = query_position_state(deps.as_ref(), &position)?;
+    let attributes = vec![
+        ("action", "close_position"),
+        ("farmer_addr", info.sender.as_str()),
+        ("masset_token", masset_token.as_str()),
+    ];
+    if position_state.collateral.is_zero() && position_state.loan.is_zero() {
+        remove_farmer(deps, &position)?;
+        return Ok(Response::new().add_attributes(attributes));
+    };
+
+    let config = load_config(deps.storage)?;
+    let mirror_mint_config = query_mirror_mint_config(deps.as_ref(), config.mirror_mint_contract.to_string())?;
+    let masset_config = query_masset_config(deps.as_ref(), &masset_token)?;
+    let asset_config = load_asset_config(deps.storage, &masset_token)?;
+    let pair_addr = resolve_pair_addr(deps.as_ref(), &mirror_mint_config, &asset_config)?;
+    let (collateral_price, masset_price, collateral_multiplier) = get_assets_prices(deps.as_ref(), &env, &mirror_mint_config, &config, &masset_token)?;
+    let masset_price_in_collateral_asset = decimal_division(masset_price, collateral_price)?;
+
+    // exit is limited by Mirror min collateral ratio only, so position between it and safe ratio can still be closed
+    let safe_collateral_ratio = calculate_mirror_min_collateral_ratio(&masset_config, collateral_multiplier);
+    let balances_before = query_contract_balances(deps.as_ref(), &env, &config, &masset_token)?;
+    let operation_id = start_operation(deps.storage, env.block.height)?;
+    save_withdraw_state(deps.storage, operation_id, &WithdrawState {
+        farmer_addr: position.farmer_addr,
+        masset_token: position.masset_token,
+        aim_collateral: Uint128::zero(),
+        aim_loan: Uint128::zero(),
+        pair_addr,
+        collateral_price,
+        masset_price,
+        safe_collateral_ratio,
+        max_spread: config.default_max_spread,
+        receive_as: ReceiveAs::Stable,
+        balances_before,
+    })?;
+    if position_state.loan.is_zero() {
+        return Ok(withdraw_all_collateral(config, operation_id, position.cdp_idx, position_state.collateral)?.add_attributes(attributes));
+    };
+    let amount_to_withdraw = calculate_withdraw_amount(position_state.collateral, position_state.loan, Uint128::zero(), masset_price_in_collateral_asset, safe_collateral_ratio);
+    if amount_to_withdraw.is_zero() {
+        return Err(ContractError::NoSafeWithdraw { safe_collateral_ratio });
+    };
+    Ok(withdraw_collateral(config, operation_id, position.cdp_idx, amount_to_withdraw)?.add_attributes(attributes))
+}
+
+// Aim of zero collateral and zero loan means that position is being closed
+pub fn is_close_state(state: &WithdrawState) -> bool {
+    state.aim_collateral.is_zero() && state.aim_loan.is_zero()
 }
 
-pub fn raw_withdraw(deps: DepsMut, info: MessageInfo, masset_token: String, amount: Uint128) -> StdResult<Response> {
+pub fn raw_withdraw(deps: DepsMut, env: Env, info: MessageInfo, masset_token: String, amount: Uint128, receive_as: Option<ReceiveAs>) -> Result<Response, ContractError> {
+    let operation_id = start_operation(deps.storage, env.block.height)?;
+    save_is_raw(deps.storage, operation_id, true)?;
     let masset_token = deps.api.addr_validate(&masset_token)?;
 
     if let Some(position) = may_load_position(deps.storage, &info.sender, &masset_token)? {
-        if position.collateral < amount {
-            return Err(StdError::generic_err("Not enough asset in collateral"));
+        let position_state = query_position_state(deps.as_ref(), &position)?;
+        if position_state.collateral < amount {
+            return Err(ContractError::InsufficientCollateral {
+                amount,
+                collateral: position_state.collateral,
+            });
         };
         let config = load_config(deps.storage)?;
         let mirror_mint_config = query_mirror_mint_config(deps.as_ref(), config.mirror_mint_contract.to_string())?;
 
-        let (collateral_price, masset_price) = get_assets_prices(deps.as_ref(), &mirror_mint_config, &config, &masset_token)?;
-        let masset_price_in_collateral_asset = decimal_division(collateral_price, masset_price)?;
+        let (collateral_price, masset_price, collateral_multiplier) = get_assets_prices(deps.as_ref(), &env, &mirror_mint_config, &config, &masset_token)?;
+        let masset_price_in_collateral_asset = decimal_division(masset_price, collateral_price)?;
 
         let masset_config = query_masset_config(deps.as_ref(), &masset_token)?;
-        let safe_collateral_ratio = decimal_multiplication(&masset_config.min_collateral_ratio, &config.min_over_collateralization);
-        let loan_in_collateral_asset = position.loan * masset_price_in_collateral_asset;
-        let min_safe_collateral = Uint128::from(loan_in_collateral_asset.u128() * safe_collateral_ratio.denominator() / safe_collateral_ratio.numerator());
-        if position.collateral - amount < min_safe_collateral {
-            return Err(StdError::generic_err("Amount to withdraw too big for raw withdraw"));
+        let asset_config = load_asset_config(deps.storage, &masset_token)?;
+        let safe_collateral_ratio = calculate_safe_collateral_ratio(&asset_config, &masset_config, collateral_multiplier);
+        let min_safe_collateral = calculate_min_safe_collateral(position_state.loan, masset_price_in_collateral_asset, safe_collateral_ratio);
+        if position_state.collateral - amount < min_safe_collateral {
+            return Err(ContractError::UnsafeWithdraw {
+                max_amount: position_state.collateral.saturating_sub(min_safe_collateral),
+            });
         };
 
-        let pair_addr = deps.api.addr_validate(&query_pair_addr(deps.as_ref(), &deps.api.addr_validate(&mirror_mint_config.terraswap_factory)?, &masset_token)?)?;
+        let pair_addr = resolve_pair_addr(deps.as_ref(), &mirror_mint_config, &asset_config)?;
 
-        save_withdraw_state(deps.storage, &WithdrawState {
+        let balances_before = query_contract_balances(deps.as_ref(), &env, &config, &masset_token)?;
+        save_withdraw_state(deps.storage, operation_id, &WithdrawState {
             farmer_addr: position.farmer_addr,
             masset_token: position.masset_token,
             aim_collateral: Uint128::default(), // not used in raw withdraw
@@ -243,54 +647,797 @@ pub fn raw_withdraw(deps: DepsMut, info: MessageInfo, masset_token: String, amou
             collateral_price,
             masset_price,
             safe_collateral_ratio,
+            // used only to swap returned stable to masset
+            max_spread: config.default_max_spread,
+            receive_as: receive_as.unwrap_or_default(),
+            balances_before,
         })?;
-        withdraw_collateral(config, position.cdp_idx, amount)
+        withdraw_collateral(config, operation_id, position.cdp_idx, amount)
     } else {
-        Err(StdError::generic_err(format!(
-            "There isn't position: farmer_addr: {}, masset_token: {}.",
-            &info.sender.to_string(),
-            &masset_token.to_string())))
+        Err(ContractError::position_not_found(&info.sender, &masset_token))
     }
 }
 
-pub fn is_aim_state(position: &Position, state: &WithdrawState) -> bool {
-    position.collateral == state.aim_collateral && position.loan == state.aim_loan
-}
+pub fn rebalance(deps: DepsMut, env: Env, farmer_addr: String, masset_token: String) -> Result<Response, ContractError> {
+    let operation_id = start_operation(deps.storage, env.block.height)?;
+    let farmer_addr = deps.api.addr_validate(&farmer_addr)?;
+    let masset_token = deps.api.addr_validate(&masset_token)?;
+    let position = match may_load_position(deps.storage, &farmer_addr, &masset_token)? {
+        Some(position) => position,
+        None => {
+            return Err(ContractError::position_not_found(&farmer_addr, &masset_token));
+        }
+    };
+    let position_state = query_position_state(deps.as_ref(), &position)?;
+    if position_state.collateral.is_zero() {
+        return Err(ContractError::NothingToRebalance { reason: "position collateral is zero".to_string() });
+    };
 
-pub fn calculate_withdraw_amount(collateral: Uint128, loan: Uint128, aim_collateral: Uint128, masset_price_in_collateral_asset: Decimal, safe_collateral_ratio: Decimal) -> Uint128 {
-    let loan_in_collateral_asset = loan * masset_price_in_collateral_asset;
-    let min_safe_collateral = Uint128::from(loan_in_collateral_asset.u128() * safe_collateral_ratio.denominator() / safe_collateral_ratio.numerator());
-    let max_safe_withdraw = collateral - min_safe_collateral;
-    if aim_collateral < min_safe_collateral {
-        collateral - aim_collateral
+    let config = load_config(deps.storage)?;
+    let mirror_mint_config = query_mirror_mint_config(deps.as_ref(), config.mirror_mint_contract.to_string())?;
+    let masset_config = query_masset_config(deps.as_ref(), &masset_token)?;
+    let asset_config = load_asset_config(deps.storage, &masset_token)?;
+    validate_masset(&masset_config)?;
+    let pair_addr = resolve_pair_addr(deps.as_ref(), &mirror_mint_config, &asset_config)?;
+
+    let (collateral_price, masset_price, collateral_multiplier) = get_assets_prices(deps.as_ref(), &env, &mirror_mint_config, &config, &masset_token)?;
+    let masset_price_in_collateral_asset = decimal_division(masset_price, collateral_price)?;
+    let aim_collateral_ratio = position.aim_collateral_ratio;
+    let cur_collateral_ratio = if position_state.loan.is_zero() {
+        None
     } else {
-        max_safe_withdraw
-    }
+        Some(calculate_collateral_ratio(position_state.collateral, position_state.loan, masset_price_in_collateral_asset))
+    };
+
+    let attributes = vec![
+        ("action", "rebalance".to_string()),
+        ("farmer_addr", farmer_addr.to_string()),
+        ("masset_token", masset_token.to_string()),
+        ("aim_collateral_ratio", aim_collateral_ratio.to_string()),
+        ("cur_collateral_ratio", cur_collateral_ratio.map(|r| r.to_string()).unwrap_or_default()),
+    ];
+
+    match cur_collateral_ratio {
+        // collateral ratio is too low: withdraw collateral, buy and burn masset until aim ratio is reached
+        Some(ratio) if ratio + config.rebalance_band < aim_collateral_ratio => {
+            let collateral_to_repay = calculate_delever_collateral(position_state.collateral, position_state.loan, masset_price_in_collateral_asset, aim_collateral_ratio)?;
+            let aim_collateral = position_state.collateral - collateral_to_repay;
+            let aim_loan = position_state.loan.saturating_sub(divide_by_decimal(collateral_to_repay, masset_price_in_collateral_asset)?);
+
+            let safe_collateral_ratio = calculate_safe_collateral_ratio(&asset_config, &masset_config, collateral_multiplier);
+            let amount_to_withdraw = calculate_withdraw_amount(position_state.collateral, position_state.loan, aim_collateral, masset_price_in_collateral_asset, safe_collateral_ratio);
+            if amount_to_withdraw.is_zero() {
+                return Err(ContractError::NoSafeWithdraw { safe_collateral_ratio });
+            };
+            let balances_before = query_contract_balances(deps.as_ref(), &env, &config, &masset_token)?;
+            save_withdraw_state(deps.storage, operation_id, &WithdrawState {
+                farmer_addr: position.farmer_addr,
+                masset_token: position.masset_token,
+                aim_collateral,
+                aim_loan,
+                pair_addr,
+                collateral_price,
+                masset_price,
+                safe_collateral_ratio,
+                max_spread: config.default_max_spread,
+                receive_as: ReceiveAs::Stable,
+                balances_before,
+            })?;
+            return Ok(withdraw_collateral(config, operation_id, position.cdp_idx, amount_to_withdraw)?.add_attributes(attributes));
+        }
+        Some(ratio) if ratio <= aim_collateral_ratio + config.rebalance_band => {
+            return Err(ContractError::WithinRebalanceBand {
+                collateral_ratio: ratio,
+                aim_collateral_ratio,
+            });
+        }
+        // collateral ratio is too high: mint more masset and run deposit loop for position leverage iterations
+        // or up to target leverage of the position,
+        // it increases exposure like a deposit, so it's disabled while paused or frozen
+        _ => check_deposits_enabled(deps.storage, &masset_token)?,
+    };
+
+    let mut state = DepositState {
+        farmer_addr: position.farmer_addr.clone(),
+        masset_token: position.masset_token.clone(),
+        leverage: position.leverage,
+        // there is no new deposit, so loop starts from minting
+        cur_iteration_index: 1,
+        asset_price_in_collateral_asset: masset_price_in_collateral_asset,
+        pair_addr,
+        aim_collateral_ratio,
+        masset_price,
+        max_spread: config.default_max_spread,
+        target_loan: None,
+        target_leverage: position.target_leverage,
+    };
+    if let Some(target_leverage) = position.target_leverage {
+        let (iterations, target_loan) = plan_target_leverage(&state, &position_state, Uint128::zero(), collateral_price, target_leverage, asset_config.max_leverage)?;
+        state.leverage = iterations;
+        state.target_loan = Some(target_loan);
+    };
+    // rebalance adds collateral and loan to CDP the same as deposit loop, so it's limited by deposit caps
+    // and mints only the share of planned loan which fits into remaining capacity
+    let capacity = calculate_deposit_capacity(deps.storage, &config, &asset_config)?;
+    let fill_ratio = calculate_deposit_fill_ratio(&state, &position_state, Uint128::zero(), collateral_price, &capacity)?;
+    if fill_ratio < Decimal::one() {
+        let (_, planned_loan) = plan_deposit_amounts(&state, &position_state, Uint128::zero(), collateral_price)?;
+        let capped_loan = position_state.loan + planned_loan * fill_ratio;
+        if capped_loan <= position_state.loan {
+            return Err(ContractError::DepositCapReached { masset_token: masset_token.to_string() });
+        };
+        state.target_loan = Some(state.target_loan.map_or(capped_loan, |target_loan| target_loan.min(capped_loan)));
+        // partially filled rebalance doesn't reach target leverage
+        state.target_leverage = None;
+    };
+    check_deposit_cap(&state, &position_state, Uint128::zero(), collateral_price, &capacity)?;
+    check_deposit_price_impact(deps.as_ref(), &config, &state, &position_state, Uint128::zero(), collateral_price)?;
+    let aim_loan = calculate_deposit_aim_loan(&state, position_state.collateral)?;
+    let mint_amount = aim_loan.saturating_sub(position_state.loan);
+    if mint_amount.is_zero() {
+        return Err(ContractError::NothingToRebalance { reason: "nothing to mint".to_string() });
+    };
+    save_deposit_state(deps.storage, operation_id, &state)?;
+    Ok(mint_masset(config, operation_id, position.cdp_idx, masset_token.to_string(), mint_amount)?.add_attributes(attributes))
 }
 
-pub fn return_stable(deps: DepsMut, env: Env) -> StdResult<Response> {
-    let state = load_withdraw_state(deps.storage)?;
-    let position = load_position(deps.storage, &state.farmer_addr, &state.masset_token)?;
+// Shared CDP is delevered as a whole, so every farmer pays for it in proportion to their shares
+pub fn protect_cdp(deps: DepsMut, env: Env, info: MessageInfo, masset_token: String) -> Result<Response, ContractError> {
+    let operation_id = start_operation(deps.storage, env.block.height)?;
+    let masset_token = deps.api.addr_validate(&masset_token)?;
+    let cdp = match may_load_cdp(deps.storage, &masset_token)? {
+        Some(cdp) => cdp,
+        None => return Err(ContractError::CdpNotFound { masset_token: masset_token.to_string() }),
+    };
+    let cdp_state = query_cdp(deps.as_ref(), cdp.idx)?;
+    if cdp_state.loan_amount.is_zero() {
+        return Err(ContractError::NoLoanToRepay {});
+    };
+
     let config = load_config(deps.storage)?;
-    if position.collateral == Uint128::zero() {
-        remove_position(deps.storage, &position.farmer_addr, &position.masset_token);
-        remove_farmer_from_cdp(deps.storage, &position.farmer_addr, &position.masset_token)?;
-    };
-    let balance: BalanceResponse = deps.querier.query(&QueryRequest::Bank(BankQuery::Balance {
-        address: env.contract.address.to_string(),
-        denom: config.stable_denom.clone(),
-    }))?;
-    Ok(Response::new()
-        .add_message(CosmosMsg::Bank(BankMsg::Send {
-            to_address: position.farmer_addr.to_string(),
+    let mirror_mint_config = query_mirror_mint_config(deps.as_ref(), config.mirror_mint_contract.to_string())?;
+    let masset_config = query_masset_config(deps.as_ref(), &masset_token)?;
+    let asset_config = load_asset_config(deps.storage, &masset_token)?;
+    let pair_addr = resolve_pair_addr(deps.as_ref(), &mirror_mint_config, &asset_config)?;
+
+    let (collateral_price, masset_price, collateral_multiplier) = get_assets_prices(deps.as_ref(), &env, &mirror_mint_config, &config, &masset_token)?;
+    let masset_price_in_collateral_asset = decimal_division(masset_price, collateral_price)?;
+    let mirror_min_collateral_ratio = calculate_mirror_min_collateral_ratio(&masset_config, collateral_multiplier);
+    let safe_collateral_ratio = decimal_multiplication(&mirror_min_collateral_ratio, &asset_config.min_over_collateralization);
+    let cur_collateral_ratio = calculate_collateral_ratio(cdp_state.collateral_amount, cdp_state.loan_amount, masset_price_in_collateral_asset);
+    if cur_collateral_ratio >= safe_collateral_ratio {
+        return Err(ContractError::CdpNotInDanger {
+            collateral_ratio: cur_collateral_ratio,
+            safe_collateral_ratio,
+        });
+    };
+
+    // delever with rebalance_band margin to not trigger protection again on small price moves
+    let aim_collateral_ratio = safe_collateral_ratio + config.rebalance_band;
+    let collateral_to_repay = calculate_delever_collateral(cdp_state.collateral_amount, cdp_state.loan_amount, masset_price_in_collateral_asset, aim_collateral_ratio)?;
+    let aim_loan = cdp_state.loan_amount.saturating_sub(divide_by_decimal(collateral_to_repay, masset_price_in_collateral_asset)?);
+    let bounty_collateral = collateral_to_repay * config.protect_bounty;
+    let aim_collateral = cdp_state.collateral_amount.saturating_sub(collateral_to_repay + bounty_collateral);
+
+    // CDP is already below safe ratio, so withdrawals are limited by Mirror min collateral ratio only
+    let amount_to_withdraw = calculate_withdraw_amount(cdp_state.collateral_amount, cdp_state.loan_amount, aim_collateral, masset_price_in_collateral_asset, mirror_min_collateral_ratio);
+    if amount_to_withdraw.is_zero() {
+        return Err(ContractError::NoSafeWithdraw { safe_collateral_ratio: mirror_min_collateral_ratio });
+    };
+
+    let balances_before = query_contract_balances(deps.as_ref(), &env, &config, &masset_token)?;
+    save_withdraw_state(deps.storage, operation_id, &WithdrawState {
+        // position of caller is not touched by protection, farmer_addr is kept only for withdraw loop state
+        farmer_addr: info.sender.clone(),
+        masset_token: masset_token.clone(),
+        aim_collateral,
+        aim_loan,
+        pair_addr,
+        collateral_price,
+        masset_price,
+        safe_collateral_ratio: mirror_min_collateral_ratio,
+        max_spread: config.default_max_spread,
+        receive_as: ReceiveAs::Stable,
+        balances_before,
+    })?;
+    save_protect_state(deps.storage, operation_id, &ProtectState {
+        caller: info.sender.clone(),
+        masset_token: masset_token.clone(),
+        bounty: bounty_collateral * collateral_price,
+    })?;
+    Ok(withdraw_collateral(config, operation_id, cdp.idx, amount_to_withdraw)?
+        .add_attributes(vec![
+            ("action", "protect_cdp"),
+            ("caller", info.sender.as_str()),
+            ("masset_token", masset_token.as_str()),
+            ("cur_collateral_ratio", &cur_collateral_ratio.to_string()),
+            ("aim_collateral_ratio", &aim_collateral_ratio.to_string()),
+        ]))
+}
+
+pub fn pay_protect_bounty(deps: DepsMut, env: Env, operation_id: u64) -> Result<Response, ContractError> {
+    let protect_state = match may_load_protect_state(deps.storage, operation_id)? {
+        Some(protect_state) => protect_state,
+        None => return Err(ContractError::UnexpectedOperation { operation_id, expected: "CDP protection".to_string() }),
+    };
+    let config = load_config(deps.storage)?;
+    let stable_balance = query_operation_stable(deps.as_ref(), &env, &config, &load_withdraw_state(deps.storage, operation_id)?)?;
+    let bounty_amount = stable_balance.min(protect_state.bounty);
+    // stable left after buying masset belongs to CDP farmers, so it's deposited back as collateral,
+    // DepositStable and DepositToCDP replies finish protection
+    let leftover_amount = stable_balance - bounty_amount;
+    let leftover_aterra_amount = if leftover_amount.is_zero() {
+        Uint128::zero()
+    } else {
+        calculate_aterra_amount(leftover_amount, query_aterra_exchange_rate(deps.as_ref(), &config, env.block.height)?)
+    };
+
+    let mut response = if leftover_aterra_amount.is_zero() {
+        finish_protection(deps, operation_id, &protect_state.masset_token)?;
+        let mut response = Response::new();
+        // dust which can't be deposited to Anchor goes to treasury
+        if !leftover_amount.is_zero() {
+            response = response.add_message(CosmosMsg::Bank(BankMsg::Send {
+                to_address: config.nexus_treasury.to_string(),
+                amount: vec![
+                    Coin {
+                        denom: config.stable_denom.clone(),
+                        amount: leftover_amount,
+                    }],
+            }));
+        };
+        response
+    } else {
+        anc_deposit_stable(config.clone(), operation_id, Uint256::from(leftover_amount))?
+    };
+    if !bounty_amount.is_zero() {
+        response = response.add_message(CosmosMsg::Bank(BankMsg::Send {
+            to_address: protect_state.caller.to_string(),
             amount: vec![
                 Coin {
                     denom: config.stable_denom,
-                    amount: balance.amount.amount,
+                    amount: bounty_amount,
                 }],
-        }))
+        }));
+    };
+    Ok(response
+        .add_attributes(vec![
+            ("action", "pay_protect_bounty"),
+            ("caller", protect_state.caller.as_str()),
+            ("masset_token", protect_state.masset_token.as_str()),
+            ("bounty_amount", &bounty_amount.to_string()),
+            ("leftover_amount", &leftover_amount.to_string()),
+        ]))
+}
+
+// Protection changes CDP amounts without changing shares
+pub fn finish_protection(deps: DepsMut, operation_id: u64, masset_token: &Addr) -> Result<(), ContractError> {
+    remove_operation(deps.storage, operation_id);
+    let cdp_state = query_cdp(deps.as_ref(), load_cdp(deps.storage, masset_token)?.idx)?;
+    update_cdp_amounts(deps.storage, masset_token, &cdp_state)?;
+    Ok(())
+}
+
+// share rounding can leave position a few units below aim amounts
+pub fn is_aim_state(position_state: &PositionState, state: &WithdrawState) -> bool {
+    position_state.collateral <= state.aim_collateral && position_state.loan <= state.aim_loan
+}
+
+//aim_collateral_ratio = collateral_value / aim_loan_value = collateral_amount / (aim_loan_amount * asset_price_in_collateral_asset)
+// aim_loan_amount = collateral_amount/(aim_collateral_ratio * asset_price_in_collateral_asset)
+// Fails on zero masset price instead of minting unbounded loan
+pub fn calculate_aim_loan(collateral: Uint128, aim_collateral_ratio: Decimal, asset_price_in_collateral_asset: Decimal) -> StdResult<Uint128> {
+    let coef = decimal_multiplication(&aim_collateral_ratio, &asset_price_in_collateral_asset);
+    divide_by_decimal(collateral, coef)
+}
+
+pub fn calculate_deposit_price_impact(deps: Deps, state: &DepositState, position_state: &PositionState, deposit_collateral: Uint128, collateral_price: Decimal) -> Result<Decimal, ContractError> {
+    let sell_amounts = plan_deposit_mints(state, position_state.collateral, position_state.loan, deposit_collateral, collateral_price)?;
+
+    let (masset_reserve, stable_reserve) = query_pool_reserves(deps, &state.pair_addr, &state.masset_token)?;
+    Ok(estimate_sell_price_impact(masset_reserve, stable_reserve, &sell_amounts))
+}
+
+// Aim loan of deposit loop iteration, capped by loan of target leverage
+pub fn calculate_deposit_aim_loan(state: &DepositState, collateral: Uint128) -> StdResult<Uint128> {
+    let aim_loan = calculate_aim_loan(collateral, state.aim_collateral_ratio, state.asset_price_in_collateral_asset)?;
+    Ok(match state.target_loan {
+        Some(target_loan) => aim_loan.min(target_loan),
+        None => aim_loan,
+    })
+}
+
+// Position is opened with aim ratio, or with higher one when target loan is less than aim loan
+pub fn calculate_open_collateral_ratio(state: &DepositState, collateral: Uint128) -> StdResult<Decimal> {
+    let aim_loan = calculate_deposit_aim_loan(state, collateral)?;
+    let aim_loan_in_collateral_asset = aim_loan * state.asset_price_in_collateral_asset;
+    if aim_loan_in_collateral_asset.is_zero() {
+        return Ok(state.aim_collateral_ratio);
+    };
+    Ok(state.aim_collateral_ratio.max(Decimal::from_ratio(collateral, aim_loan_in_collateral_asset)))
+}
+
+// Caps limit tracked amounts of masset CDP and total collateral of all CDPs
+pub fn calculate_deposit_capacity(storage: &dyn Storage, config: &Config, asset_config: &AssetConfig) -> Result<DepositCapacity, ContractError> {
+    let (cdp_collateral, cdp_loan) = match may_load_cdp(storage, &asset_config.masset_token)? {
+        Some(cdp) => (cdp.collateral, cdp.loan),
+        None => (Uint128::zero(), Uint128::zero()),
+    };
+    let asset_collateral = asset_config.deposit_cap.map(|cap| cap.saturating_sub(cdp_collateral));
+    let total_collateral = load_total_collateral(storage)?;
+    let global_collateral = config.deposit_cap.map(|cap| cap.saturating_sub(total_collateral));
+    let collateral = match (asset_collateral, global_collateral) {
+        (Some(asset), Some(global)) => Some(asset.min(global)),
+        (asset, global) => asset.or(global),
+    };
+    Ok(DepositCapacity {
+        collateral,
+        loan: asset_config.loan_cap.map(|cap| cap.saturating_sub(cdp_loan)),
+    })
+}
+
+// Collateral and loan added to CDP by all planned iterations of the deposit loop
+pub fn plan_deposit_amounts(state: &DepositState, position_state: &PositionState, deposit_collateral: Uint128, collateral_price: Decimal) -> Result<(Uint128, Uint128), ContractError> {
+    let mints = plan_deposit_mints(state, position_state.collateral, position_state.loan, deposit_collateral, collateral_price)?;
+    let collateral = plan_deposit_collateral(state, deposit_collateral, &mints, collateral_price)?;
+    let loan = mints.iter().fold(Uint128::zero(), |loan, mint| loan + *mint);
+    Ok((collateral, loan))
+}
+
+// Share of deposit which fits into remaining capacity, with margin for rounding of scaled deposit loop
+pub fn calculate_deposit_fill_ratio(state: &DepositState, position_state: &PositionState, deposit_collateral: Uint128, collateral_price: Decimal, capacity: &DepositCapacity) -> Result<Decimal, ContractError> {
+    let (collateral, loan) = plan_deposit_amounts(state, position_state, deposit_collateral, collateral_price)?;
+    Ok(apply_fill_margin(capacity_ratio(capacity.collateral, collateral).min(capacity_ratio(capacity.loan, loan))))
+}
+
+fn apply_fill_margin(fill_ratio: Decimal) -> Decimal {
+    if fill_ratio == Decimal::one() {
+        return fill_ratio;
+    };
+    decimal_multiplication(&fill_ratio, &(Decimal::one() - Decimal::permille(DEPOSIT_FILL_MARGIN_PERMILLE)))
+}
+
+fn capacity_ratio(capacity: Option<Uint128>, amount: Uint128) -> Decimal {
+    match capacity {
+        Some(capacity) if amount > capacity => Decimal::from_ratio(capacity, amount),
+        _ => Decimal::one(),
+    }
+}
+
+pub fn check_deposit_cap(state: &DepositState, position_state: &PositionState, deposit_collateral: Uint128, collateral_price: Decimal, capacity: &DepositCapacity) -> Result<(), ContractError> {
+    let (collateral, loan) = plan_deposit_amounts(state, position_state, deposit_collateral, collateral_price)?;
+    check_capacity("collateral", capacity.collateral, collateral)?;
+    check_capacity("loan", capacity.loan, loan)
+}
+
+fn check_capacity(cap: &str, capacity: Option<Uint128>, amount: Uint128) -> Result<(), ContractError> {
+    match capacity {
+        Some(capacity) if amount > capacity => Err(ContractError::DepositCapExceeded {
+            cap: cap.to_string(),
+            amount,
+            capacity,
+        }),
+        _ => Ok(()),
+    }
+}
+
+pub fn check_deposit_price_impact(deps: Deps, config: &Config, state: &DepositState, position_state: &PositionState, deposit_collateral: Uint128, collateral_price: Decimal) -> Result<(), ContractError> {
+    let price_impact = calculate_deposit_price_impact(deps, state, position_state, deposit_collateral, collateral_price)?;
+    if price_impact > config.max_price_impact {
+        return Err(ContractError::PriceImpactExceeded {
+            price_impact,
+            max_price_impact: config.max_price_impact,
+        });
+    };
+    Ok(())
+}
+
+// Mirror scales min collateral ratio of masset by multiplier of collateral
+pub fn calculate_mirror_min_collateral_ratio(masset_config: &MirrorAssetConfigResponse, collateral_multiplier: Decimal) -> Decimal {
+    decimal_multiplication(&masset_config.min_collateral_ratio, &collateral_multiplier)
+}
+
+pub fn calculate_safe_collateral_ratio(asset_config: &AssetConfig, masset_config: &MirrorAssetConfigResponse, collateral_multiplier: Decimal) -> Decimal {
+    decimal_multiplication(&calculate_mirror_min_collateral_ratio(masset_config, collateral_multiplier), &asset_config.min_over_collateralization)
+}
+
+// Withdraw down to aim_collateral, but not below collateral which keeps safe_collateral_ratio
+pub fn calculate_withdraw_amount(collateral: Uint128, loan: Uint128, aim_collateral: Uint128, masset_price_in_collateral_asset: Decimal, safe_collateral_ratio: Decimal) -> Uint128 {
+    let min_safe_collateral = calculate_min_safe_collateral(loan, masset_price_in_collateral_asset, safe_collateral_ratio);
+    let max_safe_withdraw = collateral.saturating_sub(min_safe_collateral);
+    collateral.saturating_sub(aim_collateral).min(max_safe_withdraw)
+}
+
+// Collateral to spend on loan repayment to reach aim ratio:
+// (C - x) / ((L - x / p) * p) = aim  =>  x = (aim * L * p - C) / (aim - 1)
+pub fn calculate_delever_collateral(collateral: Uint128, loan: Uint128, masset_price_in_collateral_asset: Decimal, aim_collateral_ratio: Decimal) -> Result<Uint128, ContractError> {
+    if aim_collateral_ratio <= Decimal::one() {
+        return Err(ContractError::CollateralRatioTooLow {
+            collateral_ratio: aim_collateral_ratio,
+            min_collateral_ratio: Decimal::one(),
+        });
+    };
+    let aim_loan_in_collateral_asset = loan * masset_price_in_collateral_asset * aim_collateral_ratio;
+    Ok(divide_by_decimal(
+        aim_loan_in_collateral_asset.saturating_sub(collateral),
+        aim_collateral_ratio - Decimal::one(),
+    )?.min(collateral))
+}
+
+pub fn calculate_min_safe_collateral(loan: Uint128, masset_price_in_collateral_asset: Decimal, safe_collateral_ratio: Decimal) -> Uint128 {
+    loan * masset_price_in_collateral_asset * safe_collateral_ratio
+}
+
+pub fn calculate_collateral_ratio(collateral: Uint128, loan: Uint128, masset_price_in_collateral_asset: Decimal) -> Decimal {
+    let loan_in_collateral_asset = loan * masset_price_in_collateral_asset;
+    Decimal::from_ratio(collateral, loan_in_collateral_asset)
+}
+
+// Collateral returned as aterra is redeemed only for stable needed to repay loan down to aim
+pub fn calculate_redeem_amount(deps: Deps, env: &Env, config: &Config, operation_id: u64, state: &WithdrawState, withdrawn_amount: Uint128) -> Result<Uint128, ContractError> {
+    if state.receive_as != ReceiveAs::Aterra {
+        return Ok(withdrawn_amount);
+    };
+    if load_is_raw(deps.storage, operation_id)? {
+        return Ok(Uint128::zero());
+    };
+    let position = load_position(deps.storage, &state.farmer_addr, &state.masset_token)?;
+    let position_state = query_position_state(deps, &position)?;
+    let repay_to_aim_value = position_state.loan.saturating_sub(state.aim_loan) * state.masset_price;
+    let stable_balance = query_operation_stable(deps, env, config, state)?;
+    let needed_stable = repay_to_aim_value.saturating_sub(stable_balance);
+    if needed_stable.is_zero() {
+        return Ok(Uint128::zero());
+    };
+    let exchange_rate = query_aterra_exchange_rate(deps, config, env.block.height)?;
+    // rounded up, so redeemed stable covers repayment
+    Ok((calculate_aterra_amount(needed_stable, exchange_rate) + Uint128::new(1)).min(withdrawn_amount))
+}
+
+// Mirror removes position emptied by the last farmer, so its CDP is removed too and the next deposit opens a new one
+fn remove_farmer(deps: DepsMut, position: &Position) -> Result<(), ContractError> {
+    remove_position(deps.storage, &position.farmer_addr, &position.masset_token);
+    let cdp = remove_farmer_from_cdp(deps.storage, &position.farmer_addr, &position.masset_token)?;
+    if cdp.farmers.is_empty() && may_query_cdp(deps.as_ref(), cdp.idx)?.is_none() {
+        remove_cdp(deps.storage, &position.masset_token);
+    };
+    Ok(())
+}
+
+pub fn return_stable(mut deps: DepsMut, env: Env, operation_id: u64) -> Result<Response, ContractError> {
+    let state = load_withdraw_state(deps.storage, operation_id)?;
+    remove_operation(deps.storage, operation_id);
+    let position = load_position(deps.storage, &state.farmer_addr, &state.masset_token)?;
+    let config = load_config(deps.storage)?;
+    if position.collateral_shares.is_zero() {
+        remove_farmer(deps.branch(), &position)?;
+    };
+    let return_amount = query_operation_stable(deps.as_ref(), &env, &config, &state)?;
+    let mut response = Response::new();
+    // zero coins can't be sent, e.g. when all collateral is kept as aterra
+    if !return_amount.is_zero() {
+        let return_msg = match state.receive_as {
+            // swapped masset goes to farmer directly
+            ReceiveAs::Masset => swap_stable_to_masset_msg(&config.stable_denom, &state, return_amount, position.farmer_addr.to_string())?,
+            _ => CosmosMsg::Bank(BankMsg::Send {
+                to_address: position.farmer_addr.to_string(),
+                amount: vec![
+                    Coin {
+                        denom: config.stable_denom.clone(),
+                        amount: return_amount,
+                    }],
+            }),
+        };
+        response = response.add_message(return_msg);
+    };
+    // collateral which wasn't redeemed
+    let aterra_amount = if state.receive_as == ReceiveAs::Aterra {
+        query_token_balance(&deps.querier, &config.aterra_addr, &env.contract.address)?.saturating_sub(state.balances_before.aterra)
+    } else {
+        Uint128::zero()
+    };
+    if !aterra_amount.is_zero() {
+        response = response.add_message(CosmosMsg::Wasm(WasmMsg::Execute {
+            contract_addr: config.aterra_addr.to_string(),
+            msg: to_binary(&Cw20ExecuteMsg::Transfer {
+                recipient: position.farmer_addr.to_string(),
+                amount: aterra_amount,
+            })?,
+            funds: vec![],
+        }));
+    };
+    // masset bought over the loan on position close
+    let masset_amount = query_token_balance(&deps.querier, &position.masset_token, &env.contract.address)?.saturating_sub(state.balances_before.masset);
+    if !masset_amount.is_zero() {
+        response = response.add_message(CosmosMsg::Wasm(WasmMsg::Execute {
+            contract_addr: position.masset_token.to_string(),
+            msg: to_binary(&Cw20ExecuteMsg::Transfer {
+                recipient: position.farmer_addr.to_string(),
+                amount: masset_amount,
+            })?,
+            funds: vec![],
+        }));
+    };
+    Ok(response
         .add_attributes(vec![
             ("action", "return_stable"),
-            ("return_amount", &balance.amount.amount.to_string()),
+            ("receive_as", &state.receive_as.to_string()),
+            ("return_amount", &return_amount.to_string()),
+            ("return_aterra_amount", &aterra_amount.to_string()),
+            ("return_masset_amount", &masset_amount.to_string()),
         ]))
-}
\ No newline at end of file
+}
+
+pub fn query_contract_balances(deps: Deps, env: &Env, config: &Config, masset_token: &Addr) -> Result<Balances, ContractError> {
+    Ok(Balances {
+        stable: query_balance(&deps.querier, &env.contract.address, &config.stable_denom)?,
+        aterra: query_token_balance(&deps.querier, &config.aterra_addr, &env.contract.address)?,
+        masset: query_token_balance(&deps.querier, masset_token, &env.contract.address)?,
+    })
+}
+
+// Stable received by the operation, stable which was in the contract before it isn't spent or paid out
+pub fn query_operation_stable(deps: Deps, env: &Env, config: &Config, state: &WithdrawState) -> Result<Uint128, ContractError> {
+    Ok(query_balance(&deps.querier, &env.contract.address, &config.stable_denom)?.saturating_sub(state.balances_before.stable))
+}
+
+pub fn pause(deps: DepsMut, info: MessageInfo, is_paused: bool) -> Result<Response, ContractError> {
+    let config = load_config(deps.storage)?;
+    if info.sender != config.governance_contract {
+        return Err(ContractError::Unauthorized {});
+    };
+    save_is_paused(deps.storage, is_paused)?;
+    Ok(Response::new().add_attributes(vec![
+        ("action", if is_paused { "pause" } else { "unpause" }),
+    ]))
+}
+
+pub fn freeze_masset(deps: DepsMut, info: MessageInfo, masset_token: String, is_frozen: bool) -> Result<Response, ContractError> {
+    let config = load_config(deps.storage)?;
+    if info.sender != config.governance_contract {
+        return Err(ContractError::Unauthorized {});
+    };
+    let masset_token = deps.api.addr_validate(&masset_token)?;
+    if is_frozen {
+        freeze_asset(deps.storage, &masset_token)?;
+    } else {
+        unfreeze_asset(deps.storage, &masset_token);
+    };
+    Ok(Response::new().add_attributes(vec![
+        ("action", if is_frozen { "freeze_asset" } else { "unfreeze_asset" }),
+        ("masset_token", masset_token.as_str()),
+    ]))
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn register_asset(deps: DepsMut, info: MessageInfo, masset_token: String, min_over_collateralization: Decimal, max_leverage: u8, deposit_cap: Option<Uint128>, loan_cap: Option<Uint128>, pair_addr: Option<String>) -> Result<Response, ContractError> {
+    let config = load_config(deps.storage)?;
+    if info.sender != config.governance_contract {
+        return Err(ContractError::Unauthorized {});
+    };
+    let masset_token = deps.api.addr_validate(&masset_token)?;
+    validate_masset(&query_masset_config(deps.as_ref(), &masset_token)?)?;
+    validate_min_over_collateralization(min_over_collateralization)?;
+    validate_max_leverage(max_leverage)?;
+    let pair_addr = pair_addr.map(|pair_addr| deps.api.addr_validate(&pair_addr)).transpose()?;
+    if let Some(pair_addr) = &pair_addr {
+        // fails if pair doesn't contain masset
+        query_pool_reserves(deps.as_ref(), pair_addr, &masset_token)?;
+    };
+
+    save_asset_config(deps.storage, &AssetConfig {
+        masset_token: masset_token.clone(),
+        min_over_collateralization,
+        max_leverage,
+        deposit_cap,
+        loan_cap,
+        pair_addr: pair_addr.clone(),
+    })?;
+    Ok(Response::new().add_attributes(vec![
+        ("action", "register_asset".to_string()),
+        ("masset_token", masset_token.to_string()),
+        ("min_over_collateralization", min_over_collateralization.to_string()),
+        ("max_leverage", max_leverage.to_string()),
+        ("deposit_cap", deposit_cap.map(|cap| cap.to_string()).unwrap_or_default()),
+        ("loan_cap", loan_cap.map(|cap| cap.to_string()).unwrap_or_default()),
+        ("pair_addr", pair_addr.map(|pair_addr| pair_addr.to_string()).unwrap_or_default()),
+    ]))
+}
+
+pub fn remove_asset(deps: DepsMut, info: MessageInfo, masset_token: String) -> Result<Response, ContractError> {
+    let config = load_config(deps.storage)?;
+    if info.sender != config.governance_contract {
+        return Err(ContractError::Unauthorized {});
+    };
+    let masset_token = deps.api.addr_validate(&masset_token)?;
+    load_asset_config(deps.storage, &masset_token)?;
+    if let Some(cdp) = may_load_cdp(deps.storage, &masset_token)? {
+        if !cdp.farmers.is_empty() {
+            return Err(ContractError::AssetInUse { masset_token: masset_token.to_string() });
+        };
+        // CDP left without farmers is dropped, deposit after masset is registered again opens a new one
+        remove_cdp(deps.storage, &masset_token);
+    };
+    remove_asset_config(deps.storage, &masset_token);
+    Ok(Response::new().add_attributes(vec![
+        ("action", "remove_asset"),
+        ("masset_token", masset_token.as_str()),
+    ]))
+}
+
+pub fn load_asset_config(storage: &dyn Storage, masset_token: &Addr) -> Result<AssetConfig, ContractError> {
+    may_load_asset_config(storage, masset_token)?.ok_or_else(|| ContractError::asset_not_registered(masset_token))
+}
+
+// New deposits are rejected while contract is paused or masset is frozen
+pub fn check_deposits_enabled(storage: &dyn Storage, masset_token: &Addr) -> Result<(), ContractError> {
+    if load_is_paused(storage)? {
+        return Err(ContractError::Paused {});
+    };
+    if is_asset_frozen(storage, masset_token) {
+        return Err(ContractError::AssetFrozen { masset_token: masset_token.to_string() });
+    };
+    Ok(())
+}
+
+pub fn update_config(deps: DepsMut, info: MessageInfo, msg: UpdateConfigMsg) -> Result<Response, ContractError> {
+    let mut config = load_config(deps.storage)?;
+    if info.sender != config.governance_contract {
+        return Err(ContractError::Unauthorized {});
+    };
+
+    let mut attributes: Vec<(String, String)> = vec![("action".to_string(), "update_config".to_string())];
+    let mut changed = |name: &str, old_value: String, new_value: String| {
+        attributes.push((format!("old_{}", name), old_value));
+        attributes.push((format!("new_{}", name), new_value));
+    };
+
+    if let Some(governance_contract) = msg.governance_contract {
+        let governance_contract = deps.api.addr_validate(&governance_contract)?;
+        changed("governance_contract", config.governance_contract.to_string(), governance_contract.to_string());
+        config.governance_contract = governance_contract;
+    };
+    if let Some(mirror_mint_contract) = msg.mirror_mint_contract {
+        let mirror_mint_contract = deps.api.addr_validate(&mirror_mint_contract)?;
+        changed("mirror_mint_contract", config.mirror_mint_contract.to_string(), mirror_mint_contract.to_string());
+        config.mirror_mint_contract = mirror_mint_contract;
+    };
+    if let Some(anchor_market_contract) = msg.anchor_market_contract {
+        let anchor_market_contract = deps.api.addr_validate(&anchor_market_contract)?;
+        changed("anchor_market_contract", config.anchor_market_contract.to_string(), anchor_market_contract.to_string());
+        config.anchor_market_contract = anchor_market_contract;
+    };
+    if let Some(aterra_addr) = msg.aterra_addr {
+        let aterra_addr = deps.api.addr_validate(&aterra_addr)?;
+        changed("aterra_addr", config.aterra_addr.to_string(), aterra_addr.to_string());
+        config.aterra_addr = aterra_addr;
+    };
+    if let Some(nexus_treasury) = msg.nexus_treasury {
+        let nexus_treasury = deps.api.addr_validate(&nexus_treasury)?;
+        changed("nexus_treasury", config.nexus_treasury.to_string(), nexus_treasury.to_string());
+        config.nexus_treasury = nexus_treasury;
+    };
+    if let Some(protocol_fee) = msg.protocol_fee {
+        validate_protocol_fee(protocol_fee)?;
+        changed("protocol_fee", config.protocol_fee.to_string(), protocol_fee.to_string());
+        config.protocol_fee = protocol_fee;
+    };
+    if let Some(default_max_spread) = msg.default_max_spread {
+        validate_max_spread(default_max_spread)?;
+        changed("default_max_spread", config.default_max_spread.to_string(), default_max_spread.to_string());
+        config.default_max_spread = default_max_spread;
+    };
+    if let Some(max_price_impact) = msg.max_price_impact {
+        validate_max_price_impact(max_price_impact)?;
+        changed("max_price_impact", config.max_price_impact.to_string(), max_price_impact.to_string());
+        config.max_price_impact = max_price_impact;
+    };
+    if let Some(rebalance_band) = msg.rebalance_band {
+        validate_rebalance_band(rebalance_band)?;
+        changed("rebalance_band", config.rebalance_band.to_string(), rebalance_band.to_string());
+        config.rebalance_band = rebalance_band;
+    };
+    if let Some(protect_bounty) = msg.protect_bounty {
+        validate_protect_bounty(protect_bounty)?;
+        changed("protect_bounty", config.protect_bounty.to_string(), protect_bounty.to_string());
+        config.protect_bounty = protect_bounty;
+    };
+    if let Some(max_oracle_age) = msg.max_oracle_age {
+        validate_max_oracle_age(max_oracle_age)?;
+        changed("max_oracle_age", config.max_oracle_age.to_string(), max_oracle_age.to_string());
+        config.max_oracle_age = max_oracle_age;
+    };
+    if let Some(max_price_deviation) = msg.max_price_deviation {
+        validate_max_price_deviation(max_price_deviation)?;
+        changed("max_price_deviation", config.max_price_deviation.to_string(), max_price_deviation.to_string());
+        config.max_price_deviation = max_price_deviation;
+    };
+    let clear_deposit_cap = msg.clear_deposit_cap.unwrap_or(false);
+    if clear_deposit_cap && msg.deposit_cap.is_some() {
+        return Err(ContractError::invalid_config("deposit_cap", "can't be set and cleared at once"));
+    };
+    if let Some(deposit_cap) = msg.deposit_cap {
+        changed("deposit_cap", config.deposit_cap.map(|cap| cap.to_string()).unwrap_or_default(), deposit_cap.to_string());
+        config.deposit_cap = Some(deposit_cap);
+    };
+    if clear_deposit_cap {
+        changed("deposit_cap", config.deposit_cap.map(|cap| cap.to_string()).unwrap_or_default(), String::new());
+        config.deposit_cap = None;
+    };
+
+    save_config(deps.storage, &config)?;
+    Ok(Response::new().add_attributes(attributes))
+}
+
+pub fn validate_protocol_fee(protocol_fee: Decimal) -> Result<(), ContractError> {
+    if protocol_fee >= Decimal::one() {
+        return Err(ContractError::invalid_config("protocol_fee", "should be less than 1"));
+    };
+    Ok(())
+}
+
+pub fn validate_min_over_collateralization(min_over_collateralization: Decimal) -> Result<(), ContractError> {
+    if min_over_collateralization < Decimal::one() {
+        return Err(ContractError::invalid_config("min_over_collateralization", "should be greater or equal to 1"));
+    };
+    Ok(())
+}
+
+pub fn validate_max_leverage(max_leverage: u8) -> Result<(), ContractError> {
+    if !(MIN_LEVERAGE..=MAX_LEVERAGE).contains(&max_leverage) {
+        return Err(ContractError::invalid_config("max_leverage", &format!("should be from {} to {}", MIN_LEVERAGE, MAX_LEVERAGE)));
+    };
+    Ok(())
+}
+
+pub fn validate_max_spread(max_spread: Decimal) -> Result<(), ContractError> {
+    if max_spread.is_zero() || max_spread >= Decimal::one() {
+        return Err(ContractError::invalid_config("max_spread", "should be greater than 0 and less than 1"));
+    };
+    Ok(())
+}
+
+pub fn validate_max_price_impact(max_price_impact: Decimal) -> Result<(), ContractError> {
+    if max_price_impact.is_zero() || max_price_impact >= Decimal::one() {
+        return Err(ContractError::invalid_config("max_price_impact", "should be greater than 0 and less than 1"));
+    };
+    Ok(())
+}
+
+pub fn validate_rebalance_band(rebalance_band: Decimal) -> Result<(), ContractError> {
+    if rebalance_band.is_zero() {
+        return Err(ContractError::invalid_config("rebalance_band", "should be greater than 0"));
+    };
+    Ok(())
+}
+
+pub fn validate_protect_bounty(protect_bounty: Decimal) -> Result<(), ContractError> {
+    if protect_bounty >= Decimal::one() {
+        return Err(ContractError::invalid_config("protect_bounty", "should be less than 1"));
+    };
+    Ok(())
+}
+
+pub fn validate_max_oracle_age(max_oracle_age: u64) -> Result<(), ContractError> {
+    if max_oracle_age == 0 {
+        return Err(ContractError::invalid_config("max_oracle_age", "should be greater than 0"));
+    };
+    Ok(())
+}
+
+pub fn validate_max_price_deviation(max_price_deviation: Decimal) -> Result<(), ContractError> {
+    if max_price_deviation.is_zero() || max_price_deviation >= Decimal::one() {
+        return Err(ContractError::invalid_config("max_price_deviation", "should be greater than 0 and less than 1"));
+    };
+    Ok(())
+}
+
+pub fn resolve_max_spread(config: &Config, max_spread: Option<Decimal>) -> Result<Decimal, ContractError> {
+    match max_spread {
+        Some(max_spread) => {
+            validate_max_spread(max_spread)?;
+            Ok(max_spread)
+        }
+        None => Ok(config.default_max_spread),
+    }
+}
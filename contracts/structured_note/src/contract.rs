@@ -1,17 +1,20 @@
-use std::convert::TryFrom;
-use std::str::FromStr;
+use cosmwasm_bignumber::Uint256;
+use cosmwasm_std::{Binary, ContractResult, Deps, DepsMut, entry_point, Env, MessageInfo, Reply, Response, to_binary, Uint128};
 
-use cosmwasm_std::{Binary, ContractResult, Deps, DepsMut, entry_point, Env, Fraction, MessageInfo, Reply, Response, StdError, StdResult, to_binary, Uint128};
+use structured_note_package::mirror::CDPState;
+use structured_note_package::structured_note::{ExecuteMsg, InstantiateMsg, MigrateMsg, QueryMsg};
 
-use structured_note_package::structured_note::{ExecuteMsg, InstantiateMsg, QueryMsg};
-
-use crate::anchor::redeem_stable;
-use crate::commands::{calculate_withdraw_amount, deposit, exit, is_aim_state, raw_deposit, raw_withdraw, return_stable, withdraw};
-use crate::mirror::{burn_masset, deposit_to_cdp, mint_masset, open_cdp, withdraw_collateral};
-use crate::state::{add_farmer_to_cdp, Config, decrease_position_collateral, decrease_position_loan, increase_iteration_index, increase_position_collateral, increase_position_loan, load_cdp, load_config, load_deposit_state, load_is_open, load_is_raw, load_position, load_withdraw_state, may_load_position, Position, save_config, save_position};
-use crate::SubmsgIds;
-use crate::terraswap::{buy_masset, sell_masset};
-use crate::utils::{decimal_division, decimal_multiplication, get_amount_from_response_asset_as_string_attr, get_amount_from_response_raw_attr, query_balance};
+use crate::anchor::{deposit_stable as anc_deposit_stable, redeem_stable};
+use crate::commands::{calculate_deposit_aim_loan, calculate_redeem_amount, calculate_withdraw_amount, check_target_leverage, close_position, deposit, exit, finish_protection, freeze_masset, is_aim_state, is_close_state, pause, query_operation_stable, pay_protect_bounty, protect_cdp, raw_deposit, raw_withdraw, rebalance, register_asset, remove_asset, return_stable, update_config, validate_max_price_impact, validate_max_oracle_age, validate_max_price_deviation, validate_max_spread, validate_protect_bounty, validate_protocol_fee, validate_rebalance_band, receive_cw20, withdraw};
+use crate::error::ContractError;
+use crate::migration::{CONTRACT_VERSION, load_stored_contract_version, migrate_storage, parse_version, set_current_contract_version};
+use crate::mirror::{burn_masset, deposit_to_cdp, may_query_cdp, mint_masset, open_cdp, query_cdp, query_position_state, withdraw_all_collateral, withdraw_collateral};
+use crate::state::{add_farmer_to_cdp, Config, decrease_position_collateral, decrease_position_loan, increase_iteration_index, increase_position_collateral, increase_position_loan, load_cdp, load_config, load_deposit_state, load_is_open, load_is_raw, load_position, load_withdraw_state, may_load_position, may_load_protect_state, Position, PositionState, remove_operation, save_config, save_is_open, save_position};
+use crate::queries::{query_all_asset_configs, query_all_cdps, query_asset_config, query_all_positions, query_cdp_info, query_deposit_capacity, query_farmer_positions, query_fee_preview, query_position, query_position_health, query_simulate_deposit, query_simulate_withdraw, query_status};
+use crate::{parse_reply_id, SubmsgIds};
+use crate::terraswap::{buy_masset, check_buy_spread, check_price_deviation, check_sell_spread, sell_masset};
+use crate::reply_parser::{may_parse_asset_attr, parse_amount_attr, parse_asset_attr, token_asset_info};
+use crate::utils::decimal_division;
 
 #[entry_point]
 pub fn instantiate(
@@ -19,7 +22,14 @@ pub fn instantiate(
     _env: Env,
     _info: MessageInfo,
     msg: InstantiateMsg,
-) -> StdResult<Response> {
+) -> Result<Response, ContractError> {
+    validate_protocol_fee(msg.protocol_fee)?;
+    validate_max_spread(msg.default_max_spread)?;
+    validate_max_price_impact(msg.max_price_impact)?;
+    validate_rebalance_band(msg.rebalance_band)?;
+    validate_protect_bounty(msg.protect_bounty)?;
+    validate_max_oracle_age(msg.max_oracle_age)?;
+    validate_max_price_deviation(msg.max_price_deviation)?;
     save_config(deps.storage, &Config {
         stable_denom: msg.stable_denom,
         governance_contract: deps.api.addr_validate(&msg.governance_contract)?,
@@ -28,169 +38,344 @@ pub fn instantiate(
         aterra_addr: deps.api.addr_validate(&msg.aterra_addr)?,
         nexus_treasury: deps.api.addr_validate(&msg.nexus_treasury)?,
         protocol_fee: msg.protocol_fee,
-        min_over_collateralization: msg.min_over_collateralization,
+        default_max_spread: msg.default_max_spread,
+        max_price_impact: msg.max_price_impact,
+        rebalance_band: msg.rebalance_band,
+        protect_bounty: msg.protect_bounty,
+        max_oracle_age: msg.max_oracle_age,
+        max_price_deviation: msg.max_price_deviation,
+        deposit_cap: msg.deposit_cap,
     })?;
+    set_current_contract_version(deps.storage)?;
     Ok(Response::default())
 }
 
-//TODO: v.0.2 check liquidity
-//TODO: v.0.2 check slippage
+#[entry_point]
+pub fn migrate(deps: DepsMut, _env: Env, _msg: MigrateMsg) -> Result<Response, ContractError> {
+    let stored_version = load_stored_contract_version(deps.storage)?;
+    let current_version = parse_version(CONTRACT_VERSION)?;
+    if stored_version > current_version {
+        return Err(ContractError::MigrationDowngrade {
+            stored_version: stored_version.to_string(),
+            current_version: current_version.to_string(),
+        });
+    };
+
+    migrate_storage(deps.storage, &stored_version)?;
+    set_current_contract_version(deps.storage)?;
+
+    Ok(Response::new()
+        .add_attributes(vec![
+            ("action", "migrate"),
+            ("from_version", &stored_version.to_string()),
+            ("to_version", &current_version.to_string()),
+        ]))
+}
+
 //TODO: v.0.2 avoid send zero tokens issue: check deposit is enough to -> mint enough aterra to -> borrow enough masset to -> buy enough UST -> etc
 #[entry_point]
-pub fn execute(deps: DepsMut, _env: Env, info: MessageInfo, msg: ExecuteMsg) -> StdResult<Response> {
+pub fn execute(deps: DepsMut, env: Env, info: MessageInfo, msg: ExecuteMsg) -> Result<Response, ContractError> {
     match msg {
         ExecuteMsg::Deposit {
             masset_token,
             leverage,
+            target_leverage,
             aim_collateral_ratio,
+            max_spread,
         } => {
-            deposit(deps, info, masset_token, leverage, aim_collateral_ratio)
+            deposit(deps, env, info, masset_token, leverage, target_leverage, aim_collateral_ratio, max_spread)
         }
         ExecuteMsg::RawDeposit { masset_token } => {
-            raw_deposit(deps, info, masset_token)
+            raw_deposit(deps, env, info, masset_token)
+        }
+        ExecuteMsg::Withdraw { masset_token, aim_collateral, aim_collateral_ratio, max_spread, receive_as } => {
+            withdraw(deps, env, info, masset_token, aim_collateral, aim_collateral_ratio, max_spread, receive_as)
+        }
+        ExecuteMsg::RawWithdraw { masset_token, amount, receive_as } => {
+            raw_withdraw(deps, env, info, masset_token, amount, receive_as)
+        }
+        ExecuteMsg::ClosePosition { masset_token } => {
+            close_position(deps, env, info, masset_token)
+        }
+        ExecuteMsg::Rebalance { farmer_addr, masset_token } => {
+            rebalance(deps, env, farmer_addr, masset_token)
+        }
+        ExecuteMsg::ProtectCdp { masset_token } => {
+            protect_cdp(deps, env, info, masset_token)
+        }
+        ExecuteMsg::UpdateConfig(msg) => {
+            update_config(deps, info, *msg)
         }
-        ExecuteMsg::Withdraw { masset_token, aim_collateral, aim_collateral_ratio } => {
-            withdraw(deps, info, masset_token, aim_collateral, aim_collateral_ratio)
+        ExecuteMsg::RegisterAsset { masset_token, min_over_collateralization, max_leverage, deposit_cap, loan_cap, pair_addr } => {
+            register_asset(deps, info, masset_token, min_over_collateralization, max_leverage, deposit_cap, loan_cap, pair_addr)
         }
-        ExecuteMsg::RawWithdraw { masset_token, amount } => {
-            raw_withdraw(deps, info, masset_token, amount)
+        ExecuteMsg::RemoveAsset { masset_token } => {
+            remove_asset(deps, info, masset_token)
+        }
+        ExecuteMsg::Pause {} => {
+            pause(deps, info, true)
+        }
+        ExecuteMsg::Unpause {} => {
+            pause(deps, info, false)
+        }
+        ExecuteMsg::FreezeAsset { masset_token } => {
+            freeze_masset(deps, info, masset_token, true)
+        }
+        ExecuteMsg::UnfreezeAsset { masset_token } => {
+            freeze_masset(deps, info, masset_token, false)
+        }
+        ExecuteMsg::Receive(msg) => {
+            receive_cw20(deps, env, info, msg)
         }
     }
 }
 
 #[cfg_attr(not(feature = "library"), entry_point)]
-pub fn reply(deps: DepsMut, env: Env, msg: Reply) -> StdResult<Response> {
+pub fn reply(deps: DepsMut, env: Env, msg: Reply) -> Result<Response, ContractError> {
     let events = match msg.result {
         ContractResult::Ok(result) => result.events,
-        ContractResult::Err(_) => return Err(StdError::generic_err("Fail to parse reply response")),
+        ContractResult::Err(error) => return Err(ContractError::SubmessageFailed { error }),
     };
 
-    let submessage_enum = SubmsgIds::try_from(msg.id)?;
+    let (operation_id, submessage_enum) = parse_reply_id(msg.id)?;
+    let config = load_config(deps.storage)?;
+    let aterra = token_asset_info(&config.aterra_addr);
     match submessage_enum {
         SubmsgIds::DepositStable => {
-            let received_aterra_amount = Uint128::from_str(&get_amount_from_response_raw_attr(events, "mint_amount".to_string())?)?;
-            let config = load_config(deps.storage)?;
-            let state = load_deposit_state(deps.storage)?;
-            if load_is_open(deps.storage)? {
-                open_cdp(config, state, received_aterra_amount)
+            let received_aterra_amount = parse_amount_attr(&events, &config.anchor_market_contract, "mint_amount")?;
+            // stable left after CDP protection goes back to CDP
+            if let Some(protect_state) = may_load_protect_state(deps.storage, operation_id)? {
+                let cdp = load_cdp(deps.storage, &protect_state.masset_token)?;
+                return deposit_to_cdp(config, operation_id, cdp.idx, received_aterra_amount);
+            };
+            let state = load_deposit_state(deps.storage, operation_id)?;
+            if load_is_open(deps.storage, operation_id)? {
+                open_cdp(config, operation_id, state, received_aterra_amount)
             } else {
                 let cdp = load_cdp(deps.storage, &state.masset_token)?;
-                deposit_to_cdp(config, cdp.idx, received_aterra_amount)
+                deposit_to_cdp(config, operation_id, cdp.idx, received_aterra_amount)
             }
         }
         SubmsgIds::OpenCDP => {
-            let state = increase_iteration_index(deps.storage)?;
-            let cdp_idx = Uint128::from_str(&get_amount_from_response_raw_attr(events.clone(), "position_idx".to_string())?)?;
-            let minted_amount = Uint128::from_str(&get_amount_from_response_asset_as_string_attr(events.clone(), "mint_amount".to_string())?)?;
-            let collateral_amount = Uint128::from_str(&get_amount_from_response_asset_as_string_attr(events, "collateral_amount".to_string())?)?;
+            save_is_open(deps.storage, operation_id, false)?;
+            let state = increase_iteration_index(deps.storage, operation_id)?;
+            let cdp_idx = parse_amount_attr(&events, &config.mirror_mint_contract, "position_idx")?;
+            let minted_amount = parse_asset_attr(&events, &config.mirror_mint_contract, "mint_amount", &token_asset_info(&state.masset_token))?;
+            let collateral_amount = parse_asset_attr(&events, &config.mirror_mint_contract, "collateral_amount", &aterra)?;
             save_position(deps.storage, &Position {
                 farmer_addr: state.farmer_addr.clone(),
                 masset_token: state.masset_token.clone(),
                 cdp_idx,
                 leverage: state.leverage,
-                loan: minted_amount,
-                collateral: collateral_amount,
+                loan_shares: Uint128::zero(),
+                collateral_shares: Uint128::zero(),
                 aim_collateral_ratio: state.aim_collateral_ratio,
+                target_leverage: state.target_leverage,
             })?;
             add_farmer_to_cdp(deps.storage, cdp_idx, state.farmer_addr.clone(), state.masset_token.clone())?;
-            sell_masset(env, &state, minted_amount)
+            let cdp_state = CDPState {
+                collateral_amount,
+                loan_amount: minted_amount,
+            };
+            increase_position_collateral(deps.storage, &state.farmer_addr, &state.masset_token, collateral_amount, &cdp_state)?;
+            increase_position_loan(deps.storage, &state.farmer_addr, &state.masset_token, minted_amount, &cdp_state)?;
+            check_price_deviation(deps.as_ref(), &config, &state.pair_addr, &state.masset_token, state.masset_price)?;
+            check_sell_spread(deps.as_ref(), &state, minted_amount)?;
+            sell_masset(env, operation_id, &state, minted_amount)
         }
         SubmsgIds::DepositToCDP => {
-            let config = load_config(deps.storage)?;
-            let state = increase_iteration_index(deps.storage)?;
-            let deposit_amount = Uint128::from_str(&get_amount_from_response_asset_as_string_attr(events, "deposit_amount".to_string())?)?;
-            let position = increase_position_collateral(deps.storage, &state.farmer_addr, &state.masset_token, deposit_amount)?;
-            if load_is_raw(deps.storage)? || state.cur_iteration_index > state.leverage {
-                return exit(position);
+            let deposit_amount = parse_asset_attr(&events, &config.mirror_mint_contract, "deposit_amount", &aterra)?;
+            if let Some(protect_state) = may_load_protect_state(deps.storage, operation_id)? {
+                finish_protection(deps, operation_id, &protect_state.masset_token)?;
+                return Ok(Response::new()
+                    .add_attributes(vec![
+                        ("action", "return_protection_leftover"),
+                        ("masset_token", protect_state.masset_token.as_str()),
+                        ("collateral_amount", &deposit_amount.to_string()),
+                    ]));
             };
-            //aim_collateral_ratio = collateral_value / aim_loan_value = collateral_amount / (aim_loan_amount * asset_price_in_collateral_asset)
-            // aim_loan_amount = collateral_amount/(aim_collateral_ratio * asset_price_in_collateral_asset)
-            let coef = decimal_multiplication(&state.aim_collateral_ratio, &state.asset_price_in_collateral_asset);
-            let aim_loan_amount = Uint128::from(position.collateral.u128() * coef.denominator() / coef.numerator());
-
-            if aim_loan_amount <= position.loan {
+            let state = increase_iteration_index(deps.storage, operation_id)?;
+            let cdp_state = query_cdp(deps.as_ref(), load_cdp(deps.storage, &state.masset_token)?.idx)?;
+            let (position, position_state) = increase_position_collateral(deps.storage, &state.farmer_addr, &state.masset_token, deposit_amount, &cdp_state)?;
+            if load_is_raw(deps.storage, operation_id)? {
+                remove_operation(deps.storage, operation_id);
+                return exit(position, position_state);
+            };
+            if state.cur_iteration_index > state.leverage {
+                check_target_leverage(&state, &position_state)?;
+                remove_operation(deps.storage, operation_id);
+                return exit(position, position_state);
+            };
+            let aim_loan_amount = calculate_deposit_aim_loan(&state, position_state.collateral)?;
+            // target loan is reached before planned iterations due to swap spread
+            if state.target_loan.is_some() && aim_loan_amount <= position_state.loan {
+                check_target_leverage(&state, &position_state)?;
+                remove_operation(deps.storage, operation_id);
+                return exit(position, position_state);
+            };
+            if aim_loan_amount <= position_state.loan {
                 // impossible case because to decrease loan_amount contract needs to burn some masset_tokens which are not considered to be in the contract atm
-                return Err(StdError::generic_err("Aim loan amount is less or equals to actual loan amount. Deposit doesn't handle burning borrowed asset tokens."));
+                return Err(ContractError::AimLoanNotAboveLoan {});
             };
-            let mint_amount = aim_loan_amount - position.loan;
-            mint_masset(config, position.cdp_idx, state.masset_token.to_string(), mint_amount)
+            let mint_amount = aim_loan_amount - position_state.loan;
+            mint_masset(config, operation_id, position.cdp_idx, state.masset_token.to_string(), mint_amount)
         }
         SubmsgIds::SellMAsset => {
-            let received_aterra_amount = Uint128::from_str(&get_amount_from_response_raw_attr(events, "mint_amount".to_string())?)?;
-            let config = load_config(deps.storage)?;
-            let state = load_deposit_state(deps.storage)?;
-            let position = load_position(deps.storage, &state.farmer_addr, &state.masset_token)?;
-            deposit_to_cdp(config, position.cdp_idx, received_aterra_amount)
+            // UST from the swap goes to Anchor first, DepositStable reply deposits received aUST to CDP
+            let state = load_deposit_state(deps.storage, operation_id)?;
+            let received_stable_amount = parse_amount_attr(&events, &state.pair_addr, "return_amount")?;
+            anc_deposit_stable(config, operation_id, Uint256::from(received_stable_amount))
         }
         SubmsgIds::MintMAsset => {
-            let state = load_deposit_state(deps.storage)?;
-            let minted_amount = Uint128::from_str(&get_amount_from_response_asset_as_string_attr(events, "mint_amount".to_string())?)?;
-            increase_position_loan(deps.storage, &state.farmer_addr, &state.masset_token, minted_amount)?;
-            sell_masset(env, &state, minted_amount)
+            let state = load_deposit_state(deps.storage, operation_id)?;
+            let minted_amount = parse_asset_attr(&events, &config.mirror_mint_contract, "mint_amount", &token_asset_info(&state.masset_token))?;
+            let cdp_state = query_cdp(deps.as_ref(), load_cdp(deps.storage, &state.masset_token)?.idx)?;
+            increase_position_loan(deps.storage, &state.farmer_addr, &state.masset_token, minted_amount, &cdp_state)?;
+            check_price_deviation(deps.as_ref(), &config, &state.pair_addr, &state.masset_token, state.masset_price)?;
+            check_sell_spread(deps.as_ref(), &state, minted_amount)?;
+            sell_masset(env, operation_id, &state, minted_amount)
         }
         SubmsgIds::Exit => {
-            let state = load_deposit_state(deps.storage)?;
-            exit(load_position(deps.storage, &state.farmer_addr, &state.masset_token)?)
+            // all position collateral is withdrawn, RedeemStable reply returns stable and removes position
+            let received_aterra_amount = parse_asset_attr(&events, &config.mirror_mint_contract, "withdraw_amount", &aterra)?;
+            let state = load_withdraw_state(deps.storage, operation_id)?;
+            // Mirror removes CDP emptied by the last farmer
+            let cdp_state = may_query_cdp(deps.as_ref(), load_cdp(deps.storage, &state.masset_token)?.idx)?.unwrap_or_default();
+            decrease_position_collateral(deps.storage, &state.farmer_addr, &state.masset_token, received_aterra_amount, &cdp_state)?;
+            redeem_stable(config, operation_id, received_aterra_amount)
         }
         SubmsgIds::WithdrawCollateral => {
-            let received_aterra_amount = Uint128::from_str(&get_amount_from_response_asset_as_string_attr(events, "withdraw_amount".to_string())?)?;
-            let state = load_withdraw_state(deps.storage)?;
-            decrease_position_collateral(deps.storage, &state.farmer_addr, &state.masset_token, received_aterra_amount)?;
-            redeem_stable(load_config(deps.storage)?, received_aterra_amount)
-        }
-        SubmsgIds::RedeemStable => {
-            let state = load_withdraw_state(deps.storage)?;
-            let config = load_config(deps.storage)?;
-            if load_is_raw(deps.storage)? {
-                return return_stable(deps, env);
+            let received_aterra_amount = parse_asset_attr(&events, &config.mirror_mint_contract, "withdraw_amount", &aterra)?;
+            let state = load_withdraw_state(deps.storage, operation_id)?;
+            // CDP protection withdraws collateral of all farmers, so shares stay untouched
+            if may_load_protect_state(deps.storage, operation_id)?.is_none() {
+                let cdp_state = may_query_cdp(deps.as_ref(), load_cdp(deps.storage, &state.masset_token)?.idx)?.unwrap_or_default();
+                decrease_position_collateral(deps.storage, &state.farmer_addr, &state.masset_token, received_aterra_amount, &cdp_state)?;
             };
-            if let Some(position) = may_load_position(deps.storage, &state.farmer_addr, &state.masset_token)? {
-                if is_aim_state(&position, &state) {
-                    return return_stable(deps, env);
-                };
-
-                let repay_to_aim_value = (position.loan - state.aim_loan) * state.masset_price;
-                let stable_balance = query_balance(&deps.querier, &env.contract.address, &config.stable_denom)?;
-
-                let offer_amount = stable_balance.min(repay_to_aim_value);
-                buy_masset(config, state, env.contract.address.to_string(), offer_amount)
-            } else {
-                Err(StdError::generic_err(format!(
-                    "There isn't position: farmer_addr: {}, masset_token: {}.",
-                    &state.farmer_addr.to_string(),
-                    &state.masset_token.to_string())))
-            }
+            let redeem_amount = calculate_redeem_amount(deps.as_ref(), &env, &config, operation_id, &state, received_aterra_amount)?;
+            if redeem_amount.is_zero() {
+                return continue_withdraw(deps, env, config, operation_id);
+            };
+            redeem_stable(config, operation_id, redeem_amount)
         }
+        SubmsgIds::RedeemStable => continue_withdraw(deps, env, config, operation_id),
         SubmsgIds::BuyMAsset => {
-            let state = load_withdraw_state(deps.storage)?;
-            let position = load_position(deps.storage, &state.farmer_addr, &state.masset_token)?;
-            let return_amount = Uint128::from_str(&get_amount_from_response_raw_attr(events, "return_amount".to_string())?)?;
-            burn_masset(load_config(deps.storage)?, load_withdraw_state(deps.storage)?, position.cdp_idx, return_amount)
+            let state = load_withdraw_state(deps.storage, operation_id)?;
+            let cdp = load_cdp(deps.storage, &state.masset_token)?;
+            let return_amount = parse_amount_attr(&events, &state.pair_addr, "return_amount")?;
+            // can't burn more than loan, rest of bought masset is returned with stable
+            let loan = if may_load_protect_state(deps.storage, operation_id)?.is_some() {
+                query_cdp(deps.as_ref(), cdp.idx)?.loan_amount
+            } else {
+                query_position_state(deps.as_ref(), &load_position(deps.storage, &state.farmer_addr, &state.masset_token)?)?.loan
+            };
+            burn_masset(config, operation_id, state, cdp.idx, return_amount.min(loan))
         }
         SubmsgIds::BurnMAsset => {
-            let state = load_withdraw_state(deps.storage)?;
-            let burn_amount = Uint128::from_str(&get_amount_from_response_asset_as_string_attr(events, "burn_amount".to_string())?)?;
-            let position = decrease_position_loan(deps.storage, &state.farmer_addr, &state.masset_token, burn_amount)?;
-            let state = load_withdraw_state(deps.storage)?;
-            let config = load_config(deps.storage)?;
-            if is_aim_state(&position, &state) {
-                return return_stable(deps, env);
+            let state = load_withdraw_state(deps.storage, operation_id)?;
+            let burn_amount = parse_asset_attr(&events, &config.mirror_mint_contract, "burn_amount", &token_asset_info(&state.masset_token))?;
+            if may_load_protect_state(deps.storage, operation_id)?.is_some() {
+                let cdp = load_cdp(deps.storage, &state.masset_token)?;
+                let cdp_state = query_cdp(deps.as_ref(), cdp.idx)?;
+                let cdp_position_state = PositionState {
+                    collateral: cdp_state.collateral_amount,
+                    loan: cdp_state.loan_amount,
+                };
+                if is_aim_state(&cdp_position_state, &state) {
+                    return pay_protect_bounty(deps, env, operation_id);
+                };
+                let masset_price_in_collateral_asset = decimal_division(state.masset_price, state.collateral_price)?;
+                let amount_to_withdraw = calculate_withdraw_amount(cdp_position_state.collateral, cdp_position_state.loan, state.aim_collateral, masset_price_in_collateral_asset, state.safe_collateral_ratio);
+                if amount_to_withdraw.is_zero() {
+                    return pay_protect_bounty(deps, env, operation_id);
+                };
+                return withdraw_collateral(config, operation_id, cdp.idx, amount_to_withdraw);
+            };
+            let cdp_state = query_cdp(deps.as_ref(), load_cdp(deps.storage, &state.masset_token)?.idx)?;
+            let (position, mut position_state) = decrease_position_loan(deps.storage, &state.farmer_addr, &state.masset_token, burn_amount, &cdp_state)?;
+            // Mirror takes protocol fee on burn from position collateral, it's charged only from the burning farmer
+            if let Some(protocol_fee) = may_parse_asset_attr(&events, &config.mirror_mint_contract, "protocol_fee", &aterra)? {
+                position_state = decrease_position_collateral(deps.storage, &state.farmer_addr, &state.masset_token, protocol_fee, &cdp_state)?.1;
             };
-            let masset_price_in_collateral_asset = decimal_division(state.collateral_price, state.masset_price)?;
-            let amount_to_withdraw = calculate_withdraw_amount(position.collateral, position.loan, state.aim_collateral, masset_price_in_collateral_asset, state.safe_collateral_ratio);
-            withdraw_collateral(config, position.cdp_idx, amount_to_withdraw)
+            if is_aim_state(&position_state, &state) {
+                return return_stable(deps, env, operation_id);
+            };
+            if is_close_state(&state) && position_state.loan.is_zero() {
+                return withdraw_all_collateral(config, operation_id, position.cdp_idx, position_state.collateral);
+            };
+            let masset_price_in_collateral_asset = decimal_division(state.masset_price, state.collateral_price)?;
+            let amount_to_withdraw = calculate_withdraw_amount(position_state.collateral, position_state.loan, state.aim_collateral, masset_price_in_collateral_asset, state.safe_collateral_ratio);
+            withdraw_collateral(config, operation_id, position.cdp_idx, amount_to_withdraw)
         }
     }
 }
 
+// Stable of withdrawn collateral is in the contract: buy masset to repay loan or return assets to farmer
+fn continue_withdraw(deps: DepsMut, env: Env, config: Config, operation_id: u64) -> Result<Response, ContractError> {
+    let state = load_withdraw_state(deps.storage, operation_id)?;
+    if load_is_raw(deps.storage, operation_id)? {
+        return return_stable(deps, env, operation_id);
+    };
+    if let Some(protect_state) = may_load_protect_state(deps.storage, operation_id)? {
+        let cdp_state = query_cdp(deps.as_ref(), load_cdp(deps.storage, &state.masset_token)?.idx)?;
+        let cdp_position_state = PositionState {
+            collateral: cdp_state.collateral_amount,
+            loan: cdp_state.loan_amount,
+        };
+        let repay_to_aim_value = cdp_position_state.loan.saturating_sub(state.aim_loan) * state.masset_price;
+        let stable_balance = query_operation_stable(deps.as_ref(), &env, &config, &state)?;
+        // bounty is kept aside from stable to buy masset
+        let offer_amount = stable_balance.saturating_sub(protect_state.bounty).min(repay_to_aim_value);
+        if is_aim_state(&cdp_position_state, &state) || offer_amount.is_zero() {
+            return pay_protect_bounty(deps, env, operation_id);
+        };
+        check_price_deviation(deps.as_ref(), &config, &state.pair_addr, &state.masset_token, state.masset_price)?;
+        check_buy_spread(deps.as_ref(), &config.stable_denom, &state, offer_amount)?;
+        return buy_masset(config, operation_id, state, env.contract.address.to_string(), offer_amount);
+    };
+    if let Some(position) = may_load_position(deps.storage, &state.farmer_addr, &state.masset_token)? {
+        let position_state = query_position_state(deps.as_ref(), &position)?;
+        if is_aim_state(&position_state, &state) {
+            return return_stable(deps, env, operation_id);
+        };
+
+        let mut repay_to_aim_value = position_state.loan.saturating_sub(state.aim_loan) * state.masset_price;
+        // on close buy with max_spread margin to repay whole loan, unburned masset is returned to farmer
+        if is_close_state(&state) {
+            repay_to_aim_value += repay_to_aim_value * state.max_spread;
+        };
+        let stable_balance = query_operation_stable(deps.as_ref(), &env, &config, &state)?;
+
+        let offer_amount = stable_balance.min(repay_to_aim_value);
+        check_price_deviation(deps.as_ref(), &config, &state.pair_addr, &state.masset_token, state.masset_price)?;
+        check_buy_spread(deps.as_ref(), &config.stable_denom, &state, offer_amount)?;
+        buy_masset(config, operation_id, state, env.contract.address.to_string(), offer_amount)
+    } else {
+        Err(ContractError::position_not_found(&state.farmer_addr, &state.masset_token))
+    }
+}
+
 #[entry_point]
-pub fn query(deps: Deps, env: Env, msg: QueryMsg) -> StdResult<Binary> {
-    match msg {
+pub fn query(deps: Deps, env: Env, msg: QueryMsg) -> Result<Binary, ContractError> {
+    let response = match msg {
         QueryMsg::Config {} => to_binary(&load_config(deps.storage)?),
-        QueryMsg::Position { masset_token } => {
-            let masset_token = deps.api.addr_validate(&masset_token)?;
-            let position = load_position(deps.storage, &env.contract.address, &masset_token)?;
-            Ok(to_binary(&position)?)
+        QueryMsg::Status {} => to_binary(&query_status(deps)?),
+        QueryMsg::AssetConfig { masset_token } => to_binary(&query_asset_config(deps, masset_token)?),
+        QueryMsg::DepositCapacity { masset_token } => to_binary(&query_deposit_capacity(deps, masset_token)?),
+        QueryMsg::AllAssetConfigs { start_after, limit } => to_binary(&query_all_asset_configs(deps, start_after, limit)?),
+        QueryMsg::Position { farmer_addr, masset_token } => to_binary(&query_position(deps, farmer_addr, masset_token)?),
+        QueryMsg::FarmerPositions { farmer_addr, start_after, limit } => to_binary(&query_farmer_positions(deps, farmer_addr, start_after, limit)?),
+        QueryMsg::AllPositions { start_after, limit } => to_binary(&query_all_positions(deps, start_after, limit)?),
+        QueryMsg::FeePreview { deposit_amount } => to_binary(&query_fee_preview(deps, deposit_amount)?),
+        QueryMsg::Cdp { masset_token } => to_binary(&query_cdp_info(deps, masset_token)?),
+        QueryMsg::AllCdps { start_after, limit } => to_binary(&query_all_cdps(deps, start_after, limit)?),
+        QueryMsg::PositionHealth { farmer_addr, masset_token } => to_binary(&query_position_health(deps, env, farmer_addr, masset_token)?),
+        QueryMsg::SimulateDeposit { farmer_addr, masset_token, deposit_amount, leverage, target_leverage, aim_collateral_ratio } => {
+            to_binary(&query_simulate_deposit(deps, env, farmer_addr, masset_token, deposit_amount, leverage, target_leverage, aim_collateral_ratio)?)
         }
-    }
+        QueryMsg::SimulateWithdraw { farmer_addr, masset_token, aim_collateral, aim_collateral_ratio } => {
+            to_binary(&query_simulate_withdraw(deps, env, farmer_addr, masset_token, aim_collateral, aim_collateral_ratio)?)
+        }
+    }?;
+    Ok(response)
 }
@@ -0,0 +1,153 @@
+use cosmwasm_std::{Addr, Decimal, StdError, Uint128};
+use thiserror::Error;
+
+use crate::reply_parser::ReplyParseError;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum ContractError {
+    #[error("{0}")]
+    Std(#[from] StdError),
+
+    #[error("{0}")]
+    ReplyParse(#[from] ReplyParseError),
+
+    #[error("Unauthorized: only governance contract can execute this message")]
+    Unauthorized {},
+
+    #[error("Unsupported token {token_addr}: only aterra can be received")]
+    UnsupportedToken { token_addr: String },
+
+    #[error("Contract is paused: deposits are disabled, withdraws are available")]
+    Paused {},
+
+    #[error("Mirror asset {masset_token} is frozen: deposits are disabled, withdraws are available")]
+    AssetFrozen { masset_token: String },
+
+    #[error("Mirror asset {masset_token} isn't registered")]
+    AssetNotRegistered { masset_token: String },
+
+    #[error("Mirror asset {masset_token} can't be removed while it has positions")]
+    AssetInUse { masset_token: String },
+
+    #[error("Deposit cap exceeded: {cap} added by deposit {amount} is greater than remaining capacity {capacity}")]
+    DepositCapExceeded { cap: String, amount: Uint128, capacity: Uint128 },
+
+    #[error("Deposit cap of {masset_token} is reached")]
+    DepositCapReached { masset_token: String },
+
+    #[error("Invalid config: {field} {reason}")]
+    InvalidConfig { field: String, reason: String },
+
+    #[error("Can't migrate from version {stored_version} to older version {current_version}")]
+    MigrationDowngrade { stored_version: String, current_version: String },
+
+    #[error("There isn't position: farmer_addr: {farmer_addr}, masset_token: {masset_token}.")]
+    PositionNotFound { farmer_addr: String, masset_token: String },
+
+    #[error("There isn't cdp: masset_token: {masset_token}.")]
+    CdpNotFound { masset_token: String },
+
+    #[error("Invalid message: leverage iterations amount should be from {min} to {max}, got {leverage}")]
+    LeverageOutOfRange { leverage: u8, min: u8, max: u8 },
+
+    #[error("There isn't position: farmer_addr: {farmer_addr}, masset_token: {masset_token}. To create new position provide 'leverage'")]
+    LeverageRequired { farmer_addr: String, masset_token: String },
+
+    #[error("Target leverage {target_leverage} should be greater than 1")]
+    InvalidTargetLeverage { target_leverage: Decimal },
+
+    #[error("Target leverage {target_leverage} is unreachable: max leverage is {max_leverage}. Decrease target leverage or aim collateral ratio")]
+    TargetLeverageUnreachable { target_leverage: Decimal, max_leverage: Decimal },
+
+    #[error("Target leverage {target_leverage} is missed: position leverage after deposit is {leverage}. Decrease max spread or deposit amount")]
+    TargetLeverageMissed { target_leverage: Decimal, leverage: Decimal },
+
+    #[error("Invalid mirror asset: {reason}")]
+    InvalidMasset { reason: String },
+
+    #[error("Collateral ratio {collateral_ratio} is lower than {min_collateral_ratio}")]
+    CollateralRatioTooLow { collateral_ratio: Decimal, min_collateral_ratio: Decimal },
+
+    #[error("Aim collateral ratio {aim_collateral_ratio} is greater than current collateral ratio {current_collateral_ratio}")]
+    AimCollateralRatioTooHigh { aim_collateral_ratio: Decimal, current_collateral_ratio: Decimal },
+
+    #[error("Aim collateral {aim_collateral} is greater than current collateral {collateral}")]
+    AimCollateralTooHigh { aim_collateral: Uint128, collateral: Uint128 },
+
+    #[error("Deposit amount is zero")]
+    ZeroDeposit {},
+
+    #[error("Deposit amount is zero after protocol fee")]
+    ZeroDepositAfterFee {},
+
+    #[error("Not enough collateral: requested {amount}, position has {collateral}")]
+    InsufficientCollateral { amount: Uint128, collateral: Uint128 },
+
+    #[error("Amount to withdraw is too big: max safe withdraw amount is {max_amount}")]
+    UnsafeWithdraw { max_amount: Uint128 },
+
+    #[error("No collateral can be withdrawn safely above collateral ratio {safe_collateral_ratio}")]
+    NoSafeWithdraw { safe_collateral_ratio: Decimal },
+
+    #[error("Not enough liquidity: price impact of leveraged deposit {price_impact} exceeds max_price_impact {max_price_impact}. Decrease deposit amount or leverage")]
+    PriceImpactExceeded { price_impact: Decimal, max_price_impact: Decimal },
+
+    #[error("Swap spread {spread} exceeds max_spread {max_spread}")]
+    SlippageExceeded { spread: Decimal, max_spread: Decimal },
+
+    #[error("Aim loan amount is less or equals to actual loan amount. Deposit doesn't handle burning borrowed asset tokens.")]
+    AimLoanNotAboveLoan {},
+
+    #[error("Position collateral ratio {collateral_ratio} is within rebalance band of aim collateral ratio {aim_collateral_ratio}")]
+    WithinRebalanceBand { collateral_ratio: Decimal, aim_collateral_ratio: Decimal },
+
+    #[error("Nothing to rebalance: {reason}")]
+    NothingToRebalance { reason: String },
+
+    #[error("CDP isn't in danger zone: collateral ratio {collateral_ratio} is not lower than safe collateral ratio {safe_collateral_ratio}")]
+    CdpNotInDanger { collateral_ratio: Decimal, safe_collateral_ratio: Decimal },
+
+    #[error("CDP has no loan to repay")]
+    NoLoanToRepay {},
+
+    #[error("Operation {operation_id} isn't {expected}")]
+    UnexpectedOperation { operation_id: u64, expected: String },
+
+    #[error("Submessage failed: {error}")]
+    SubmessageFailed { error: String },
+
+    #[error("Pair {pair_addr} doesn't contain masset_token {masset_token}")]
+    PairAssetNotFound { pair_addr: String, masset_token: String },
+
+    #[error("Oracle price of {asset} is stale: last updated at {last_updated}, max oracle age is {max_oracle_age} seconds")]
+    OraclePriceStale { asset: String, last_updated: u64, max_oracle_age: u64 },
+
+    #[error("Collateral {asset} is revoked by Mirror collateral oracle")]
+    CollateralRevoked { asset: String },
+
+    #[error("Terraswap pool price {pool_price} deviates from oracle price {oracle_price} by {deviation}, max price deviation is {max_price_deviation}")]
+    PriceDeviationExceeded { pool_price: Decimal, oracle_price: Decimal, deviation: Decimal, max_price_deviation: Decimal },
+
+    #[error("Mirror {query} query failed")]
+    MirrorQueryFailed { query: String },
+}
+
+impl ContractError {
+    pub fn position_not_found(farmer_addr: &Addr, masset_token: &Addr) -> Self {
+        ContractError::PositionNotFound {
+            farmer_addr: farmer_addr.to_string(),
+            masset_token: masset_token.to_string(),
+        }
+    }
+
+    pub fn asset_not_registered(masset_token: &Addr) -> Self {
+        ContractError::AssetNotRegistered { masset_token: masset_token.to_string() }
+    }
+
+    pub fn invalid_config(field: &str, reason: &str) -> Self {
+        ContractError::InvalidConfig {
+            field: field.to_string(),
+            reason: reason.to_string(),
+        }
+    }
+}
@@ -3,12 +3,20 @@ use std::convert::TryFrom;
 use cosmwasm_std::StdError;
 
 pub mod state;
-mod anchor;
-mod mirror;
-mod terraswap;
-mod contract;
-mod commands;
-mod utils;
+pub mod anchor;
+pub mod mirror;
+pub mod terraswap;
+pub mod contract;
+pub mod error;
+pub mod commands;
+pub mod migration;
+pub mod queries;
+pub mod reply_parser;
+pub mod simulation;
+pub mod utils;
+
+#[cfg(test)]
+mod testing;
 
 pub enum SubmsgIds {
     //Deposit
@@ -17,8 +25,8 @@ pub enum SubmsgIds {
     DepositToCDP,
     MintMAsset,
     SellMAsset,
-    Exit,
     //Withdraw
+    Exit,
     WithdrawCollateral,
     RedeemStable,
     BuyMAsset,
@@ -63,6 +71,18 @@ impl SubmsgIds {
             SubmsgIds::BurnMAsset => 9,
         }
     }
+
+    // Reply id keeps operation id in upper bits and step of the operation in lower 8 bits
+    pub const fn reply_id(&self, operation_id: u64) -> u64 {
+        operation_id << OPERATION_ID_SHIFT | self.id()
+    }
+}
+
+const OPERATION_ID_SHIFT: u64 = 8;
+
+pub fn parse_reply_id(reply_id: u64) -> Result<(u64, SubmsgIds), StdError> {
+    let submsg_id = SubmsgIds::try_from(reply_id & ((1 << OPERATION_ID_SHIFT) - 1))?;
+    Ok((reply_id >> OPERATION_ID_SHIFT, submsg_id))
 }
 
 #[inline]
@@ -0,0 +1,194 @@
+use cosmwasm_std::{Addr, Decimal, Order, StdError, StdResult, Storage, Uint128};
+use cw2::{CONTRACT, set_contract_version};
+use cw_storage_plus::{Item, Map};
+use schemars::JsonSchema;
+use semver::Version;
+use serde::{Deserialize, Serialize};
+
+use crate::state::{AssetConfig, CDP, Config, Position, save_asset_config, save_cdp, save_config, save_position, save_total_collateral};
+
+pub const CONTRACT_NAME: &str = "crates.io:structured-note";
+pub const CONTRACT_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+// v1.0.0 was deployed without cw2, so a missing contract version means v1.0.0 storage layout
+const UNVERSIONED_CONTRACT_VERSION: Version = Version::new(1, 0, 0);
+
+pub fn set_current_contract_version(storage: &mut dyn Storage) -> StdResult<()> {
+    set_contract_version(storage, CONTRACT_NAME, CONTRACT_VERSION)
+}
+
+pub fn load_stored_contract_version(storage: &dyn Storage) -> StdResult<Version> {
+    match CONTRACT.may_load(storage)? {
+        Some(stored) => {
+            if stored.contract != CONTRACT_NAME {
+                return Err(StdError::generic_err(format!(
+                    "Can't migrate from contract: {}, expected: {}",
+                    stored.contract,
+                    CONTRACT_NAME)));
+            };
+            parse_version(&stored.version)
+        }
+        None => Ok(UNVERSIONED_CONTRACT_VERSION),
+    }
+}
+
+pub fn parse_version(version: &str) -> StdResult<Version> {
+    Version::parse(version).map_err(|e| StdError::generic_err(format!("Invalid contract version '{}': {}", version, e)))
+}
+
+// Every storage migration runs if stored version is lower than version which introduced it.
+// Add new steps to the end of the list, they are applied in order.
+pub fn migrate_storage(storage: &mut dyn Storage, from_version: &Version) -> StdResult<()> {
+    if *from_version < Version::new(1, 1, 0) {
+        v1_0_0::migrate_asset_configs(storage)?;
+        v1_0_0::migrate_config(storage)?;
+        v1_0_0::migrate_cdps(storage)?;
+        v1_0_0::migrate_positions(storage)?;
+        v1_0_0::remove_singleton_states(storage);
+    };
+    Ok(())
+}
+
+mod v1_0_0 {
+    use super::*;
+
+    const DEFAULT_MAX_SPREAD_PERCENT: u64 = 1;
+    const DEFAULT_MAX_PRICE_IMPACT_PERCENT: u64 = 5;
+    const DEFAULT_REBALANCE_BAND_PERCENT: u64 = 10;
+    const DEFAULT_PROTECT_BOUNTY_PERCENT: u64 = 1;
+    const DEFAULT_MAX_ORACLE_AGE_SECONDS: u64 = 60;
+    const DEFAULT_MAX_PRICE_DEVIATION_PERCENT: u64 = 10;
+    const DEFAULT_MAX_LEVERAGE: u8 = 5;
+
+    static KEY_CONFIG: Item<ConfigV100> = Item::new("config");
+    static KEY_CDPS: Map<&Addr, CDPV100> = Map::new("cdps");
+    static KEY_POSITIONS: Map<(&Addr, &Addr), PositionV100> = Map::new("positions");
+
+    #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+    pub struct ConfigV100 {
+        pub stable_denom: String,
+        pub governance_contract: Addr,
+        pub mirror_mint_contract: Addr,
+        pub anchor_market_contract: Addr,
+        pub aterra_addr: Addr,
+        pub nexus_treasury: Addr,
+        pub protocol_fee: Decimal,
+        pub min_over_collateralization: Decimal,
+    }
+
+    #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+    pub struct CDPV100 {
+        pub idx: Uint128,
+        pub masset_token: Addr,
+        pub farmers: Vec<Addr>,
+    }
+
+    #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+    pub struct PositionV100 {
+        pub farmer_addr: Addr,
+        pub masset_token: Addr,
+        pub cdp_idx: Uint128,
+        pub leverage: u8,
+        pub loan: Uint128,
+        pub collateral: Uint128,
+        pub aim_collateral_ratio: Decimal,
+    }
+
+    // v1.0.0 accepted any masset, so massets of existing CDPs are registered with global
+    // min_over_collateralization. Must run before config migration.
+    pub fn migrate_asset_configs(storage: &mut dyn Storage) -> StdResult<()> {
+        let config = KEY_CONFIG.load(storage)?;
+        let old_cdps = KEY_CDPS
+            .range(storage, None, None, Order::Ascending)
+            .map(|cdp| Ok(cdp?.1))
+            .collect::<StdResult<Vec<CDPV100>>>()?;
+        for old in old_cdps {
+            save_asset_config(storage, &AssetConfig {
+                masset_token: old.masset_token,
+                min_over_collateralization: config.min_over_collateralization,
+                max_leverage: DEFAULT_MAX_LEVERAGE,
+                deposit_cap: None,
+                loan_cap: None,
+                pair_addr: None,
+            })?;
+        }
+        Ok(())
+    }
+
+    pub fn migrate_config(storage: &mut dyn Storage) -> StdResult<()> {
+        let old = KEY_CONFIG.load(storage)?;
+        save_config(storage, &Config {
+            stable_denom: old.stable_denom,
+            governance_contract: old.governance_contract,
+            mirror_mint_contract: old.mirror_mint_contract,
+            anchor_market_contract: old.anchor_market_contract,
+            aterra_addr: old.aterra_addr,
+            nexus_treasury: old.nexus_treasury,
+            protocol_fee: old.protocol_fee,
+            default_max_spread: Decimal::percent(DEFAULT_MAX_SPREAD_PERCENT),
+            max_price_impact: Decimal::percent(DEFAULT_MAX_PRICE_IMPACT_PERCENT),
+            rebalance_band: Decimal::percent(DEFAULT_REBALANCE_BAND_PERCENT),
+            protect_bounty: Decimal::percent(DEFAULT_PROTECT_BOUNTY_PERCENT),
+            max_oracle_age: DEFAULT_MAX_ORACLE_AGE_SECONDS,
+            max_price_deviation: Decimal::percent(DEFAULT_MAX_PRICE_DEVIATION_PERCENT),
+            deposit_cap: None,
+        })
+    }
+
+    // v1.0.0 positions stored absolute amounts, they become shares 1:1,
+    // so CDP total shares are sums of its positions amounts. Must run before positions migration.
+    pub fn migrate_cdps(storage: &mut dyn Storage) -> StdResult<()> {
+        let old_cdps = KEY_CDPS
+            .range(storage, None, None, Order::Ascending)
+            .map(|cdp| Ok(cdp?.1))
+            .collect::<StdResult<Vec<CDPV100>>>()?;
+        let old_positions = KEY_POSITIONS
+            .range(storage, None, None, Order::Ascending)
+            .map(|position| Ok(position?.1))
+            .collect::<StdResult<Vec<PositionV100>>>()?;
+        let mut total_collateral = Uint128::zero();
+        for old in old_cdps {
+            let cdp_positions = old_positions.iter().filter(|p| p.masset_token == old.masset_token);
+            let (collateral_shares, loan_shares) = cdp_positions.fold((Uint128::zero(), Uint128::zero()), |(c, l), p| (c + p.collateral, l + p.loan));
+            save_cdp(storage, &CDP {
+                idx: old.idx,
+                masset_token: old.masset_token,
+                farmers: old.farmers,
+                collateral_shares,
+                loan_shares,
+                // v1.0.0 positions amounts are the last known amounts of Mirror position
+                collateral: collateral_shares,
+                loan: loan_shares,
+            })?;
+            total_collateral += collateral_shares;
+        }
+        save_total_collateral(storage, total_collateral)
+    }
+
+    // Operation states are stored per operation now, singletons are leftovers of finished operations
+    pub fn remove_singleton_states(storage: &mut dyn Storage) {
+        for key in [&b"deposit_state"[..], b"withdraw_state", b"is_open", b"is_raw"] {
+            storage.remove(key);
+        }
+    }
+
+    pub fn migrate_positions(storage: &mut dyn Storage) -> StdResult<()> {
+        let old_positions = KEY_POSITIONS
+            .range(storage, None, None, Order::Ascending)
+            .map(|position| Ok(position?.1))
+            .collect::<StdResult<Vec<PositionV100>>>()?;
+        for old in old_positions {
+            save_position(storage, &Position {
+                farmer_addr: old.farmer_addr,
+                masset_token: old.masset_token,
+                cdp_idx: old.cdp_idx,
+                leverage: old.leverage,
+                loan_shares: old.loan,
+                collateral_shares: old.collateral,
+                aim_collateral_ratio: old.aim_collateral_ratio,
+                target_leverage: None,
+            })?;
+        }
+        Ok(())
+    }
+}
@@ -1,14 +1,16 @@
-use cosmwasm_std::{Addr, Binary, CosmosMsg, Decimal, Deps, QueryRequest, Response, StdError, StdResult, SubMsg, to_binary, Uint128, WasmMsg, WasmQuery};
+use cosmwasm_std::{Addr, Binary, CosmosMsg, Decimal, Deps, Env, from_slice, QueryRequest, Response, StdResult, SubMsg, to_binary, Uint128, WasmMsg, WasmQuery};
 use cosmwasm_storage::to_length_prefixed;
 use cw20::Cw20ExecuteMsg;
 use terraswap::asset::{Asset, AssetInfo};
 
 use structured_note_package::mirror::{CDPState, MirrorAssetConfigResponse, MirrorCDPResponse, MirrorCollateralOracleQueryMsg, MirrorCollateralPriceResponse, MirrorMintConfigResponse, MirrorMintCW20HookMsg, MirrorMintExecuteMsg, MirrorOracleQueryMsg, MirrorPriceResponse};
 
+use crate::commands::calculate_open_collateral_ratio;
+use crate::error::ContractError;
 use crate::{concat, SubmsgIds};
-use crate::state::{Config, DepositState, load_config, WithdrawState};
+use crate::state::{calculate_position_state, Config, DepositState, load_cdp, load_config, Position, PositionState, WithdrawState};
 
-pub fn query_mirror_mint_config(deps: Deps, mirror_mint_contract: String) -> StdResult<MirrorMintConfigResponse> {
+pub fn query_mirror_mint_config(deps: Deps, mirror_mint_contract: String) -> Result<MirrorMintConfigResponse, ContractError> {
     let mirror_mint_config: MirrorMintConfigResponse =
         deps.querier.query(&QueryRequest::Wasm(WasmQuery::Raw {
             contract_addr: mirror_mint_contract,
@@ -17,7 +19,7 @@ pub fn query_mirror_mint_config(deps: Deps, mirror_mint_contract: String) -> Std
     Ok(mirror_mint_config)
 }
 
-pub fn query_masset_config(deps: Deps, masset_token: &Addr) -> StdResult<MirrorAssetConfigResponse> {
+pub fn query_masset_config(deps: Deps, masset_token: &Addr) -> Result<MirrorAssetConfigResponse, ContractError> {
     let config = load_config(deps.storage)?;
 
     let masset_config: StdResult<MirrorAssetConfigResponse> =
@@ -37,32 +39,44 @@ pub fn query_masset_config(deps: Deps, masset_token: &Addr) -> StdResult<MirrorA
             end_price: a.end_price,
             ipo_params: a.ipo_params,
         }),
-        Err(_) => Err(StdError::generic_err("Mirror asset config query failed".to_string()))
+        Err(_) => Err(ContractError::MirrorQueryFailed { query: "asset config".to_string() })
     }
 }
 
-pub fn query_cdp(deps: Deps, cdp_idx: Uint128) -> StdResult<CDPState> {
+pub fn query_cdp(deps: Deps, cdp_idx: Uint128) -> Result<CDPState, ContractError> {
+    may_query_cdp(deps, cdp_idx)?.ok_or_else(|| ContractError::MirrorQueryFailed { query: "position".to_string() })
+}
+
+// Mirror removes position once both its collateral and asset are zero, None is returned for it
+pub fn may_query_cdp(deps: Deps, cdp_idx: Uint128) -> Result<Option<CDPState>, ContractError> {
     let config = load_config(deps.storage)?;
 
-    let cdp: StdResult<MirrorCDPResponse> =
-        deps.querier.query(&QueryRequest::Wasm(WasmQuery::Raw {
-            contract_addr: config.mirror_mint_contract.to_string(),
-            key: Binary::from(concat(
-                &to_length_prefixed(b"position"),
-                cdp_idx.to_string().as_bytes(),
-            )),
-        }));
+    let cdp = deps.querier.query_wasm_raw(
+        config.mirror_mint_contract.to_string(),
+        concat(&to_length_prefixed(b"position"), cdp_idx.to_string().as_bytes()),
+    );
 
     match cdp {
-        Ok(cdp) => Ok(CDPState {
-            collateral_amount: cdp.collateral.amount,
-            loan_amount: cdp.asset.amount,
-        }),
-        Err(_) => Err(StdError::generic_err("Mirror position query failed".to_string()))
+        Ok(Some(data)) => {
+            let cdp: MirrorCDPResponse = from_slice(&data).map_err(|_| ContractError::MirrorQueryFailed { query: "position".to_string() })?;
+            Ok(Some(CDPState {
+                collateral_amount: cdp.collateral.amount,
+                loan_amount: cdp.asset.amount,
+            }))
+        }
+        Ok(None) => Ok(None),
+        Err(_) => Err(ContractError::MirrorQueryFailed { query: "position".to_string() })
     }
 }
 
-pub fn query_collateral_price(deps: Deps, collateral_oracle_addr: &Addr, aterra_addr: &Addr) -> StdResult<Decimal> {
+// Position of CDP removed by Mirror after full exit is empty
+pub fn query_position_state(deps: Deps, position: &Position) -> Result<PositionState, ContractError> {
+    let cdp = load_cdp(deps.storage, &position.masset_token)?;
+    let cdp_state = may_query_cdp(deps, cdp.idx)?.unwrap_or_default();
+    Ok(calculate_position_state(position, &cdp, &cdp_state))
+}
+
+pub fn query_collateral_price(deps: Deps, collateral_oracle_addr: &Addr, aterra_addr: &Addr) -> Result<MirrorCollateralPriceResponse, ContractError> {
     let res: MirrorCollateralPriceResponse = deps.querier.query(&QueryRequest::Wasm(WasmQuery::Smart {
         contract_addr: collateral_oracle_addr.to_string(),
         msg: to_binary(&MirrorCollateralOracleQueryMsg::CollateralPrice {
@@ -70,31 +84,82 @@ pub fn query_collateral_price(deps: Deps, collateral_oracle_addr: &Addr, aterra_
             block_height: None,
         })?,
     }))?;
-    Ok(res.rate)
+    Ok(res)
 }
 
-pub fn query_asset_price(deps: Deps, oracle_addr: &Addr, asset_addr: &Addr, base_asset: String) -> StdResult<Decimal> {
+// Mirror oracle rate is base asset price in quote asset, so asset is base and stable denom is quote
+pub fn query_asset_price(deps: Deps, oracle_addr: &Addr, asset_addr: &Addr, quote_asset: String) -> Result<MirrorPriceResponse, ContractError> {
     let res: MirrorPriceResponse = deps.querier.query(&QueryRequest::Wasm(WasmQuery::Smart {
         contract_addr: oracle_addr.to_string(),
         msg: to_binary(&MirrorOracleQueryMsg::Price {
-            base_asset,
-            quote_asset: asset_addr.to_string(),
+            base_asset: asset_addr.to_string(),
+            quote_asset,
         })?,
     }))?;
-    Ok(res.rate)
+    Ok(res)
 }
 
-pub fn get_assets_prices(deps: Deps, mirror_mint_config: &MirrorMintConfigResponse, config: &Config, masset_token: &Addr) -> StdResult<(Decimal, Decimal)> {
+pub struct AssetsPrices {
+    pub collateral_price: Decimal,
+    pub masset_price: Decimal,
+    pub collateral_multiplier: Decimal,
+    pub collateral_last_updated: u64,
+    pub masset_last_updated: u64,
+}
+
+impl AssetsPrices {
+    // seconds since the older of both prices was updated
+    pub fn age(&self, env: &Env) -> u64 {
+        env.block.time.seconds().saturating_sub(self.collateral_last_updated.min(self.masset_last_updated))
+    }
+}
+
+// Prices of not revoked collateral and masset regardless of their age, read-only queries report the age instead of failing
+pub fn query_assets_prices(deps: Deps, mirror_mint_config: &MirrorMintConfigResponse, config: &Config, masset_token: &Addr) -> Result<AssetsPrices, ContractError> {
     let collateral_oracle = deps.api.addr_validate(&mirror_mint_config.collateral_oracle)?;
-    let collateral_price = query_collateral_price(deps, &collateral_oracle, &config.aterra_addr)?;
+    let collateral = query_collateral_price(deps, &collateral_oracle, &config.aterra_addr)?;
+    if collateral.is_revoked {
+        return Err(ContractError::CollateralRevoked { asset: config.aterra_addr.to_string() });
+    };
 
     let oracle_addr = deps.api.addr_validate(&mirror_mint_config.oracle)?;
-    let masset_price = query_asset_price(deps, &oracle_addr, masset_token, config.stable_denom.clone())?;
+    let masset = query_asset_price(deps, &oracle_addr, masset_token, config.stable_denom.clone())?;
+
+    Ok(AssetsPrices {
+        collateral_price: collateral.rate,
+        masset_price: masset.rate,
+        collateral_multiplier: collateral.multiplier,
+        collateral_last_updated: collateral.last_updated,
+        // quote price of base denom is never updated, Mirror oracle returns u64::MAX for it
+        masset_last_updated: masset.last_updated_base.min(masset.last_updated_quote),
+    })
+}
+
+pub fn check_assets_prices_age(env: &Env, config: &Config, masset_token: &Addr, prices: &AssetsPrices) -> Result<(), ContractError> {
+    check_oracle_age(env, config, &config.aterra_addr, prices.collateral_last_updated)?;
+    check_oracle_age(env, config, masset_token, prices.masset_last_updated)
+}
 
-    Ok((collateral_price, masset_price))
+// Returns (collateral_price, masset_price, collateral_multiplier) of fresh prices of not revoked collateral
+pub fn get_assets_prices(deps: Deps, env: &Env, mirror_mint_config: &MirrorMintConfigResponse, config: &Config, masset_token: &Addr) -> Result<(Decimal, Decimal, Decimal), ContractError> {
+    let prices = query_assets_prices(deps, mirror_mint_config, config, masset_token)?;
+    check_assets_prices_age(env, config, masset_token, &prices)?;
+    Ok((prices.collateral_price, prices.masset_price, prices.collateral_multiplier))
 }
 
-pub fn open_cdp(config: Config, state: DepositState, received_aterra_amount: Uint128) -> StdResult<Response> {
+fn check_oracle_age(env: &Env, config: &Config, asset: &Addr, last_updated: u64) -> Result<(), ContractError> {
+    if env.block.time.seconds().saturating_sub(last_updated) > config.max_oracle_age {
+        return Err(ContractError::OraclePriceStale {
+            asset: asset.to_string(),
+            last_updated,
+            max_oracle_age: config.max_oracle_age,
+        });
+    };
+    Ok(())
+}
+
+pub fn open_cdp(config: Config, operation_id: u64, state: DepositState, received_aterra_amount: Uint128) -> Result<Response, ContractError> {
+    let collateral_ratio = calculate_open_collateral_ratio(&state, received_aterra_amount)?;
     Ok(Response::new()
         .add_submessage(SubMsg::reply_on_success(
             CosmosMsg::Wasm(WasmMsg::Execute {
@@ -106,23 +171,24 @@ pub fn open_cdp(config: Config, state: DepositState, received_aterra_amount: Uin
                         asset_info: AssetInfo::Token {
                             contract_addr: state.masset_token.to_string()
                         },
-                        collateral_ratio: state.aim_collateral_ratio,
+                        collateral_ratio,
                         short_params: None,
                     })?,
                 })?,
                 funds: vec![],
             }),
-            SubmsgIds::OpenCDP.id(),
+            SubmsgIds::OpenCDP.reply_id(operation_id),
         ))
         .add_attributes(vec![
             ("action", "open_cdp"),
             ("collateral_amount", &received_aterra_amount.to_string()),
-            ("masset_token", &state.masset_token.to_string()),
+            ("masset_token", state.masset_token.as_str()),
             ("aim_collateral_ratio", &state.aim_collateral_ratio.to_string()),
+            ("collateral_ratio", &collateral_ratio.to_string()),
         ]))
 }
 
-pub fn deposit_to_cdp(config: Config, cdp_idx: Uint128, received_aterra_amount: Uint128) -> StdResult<Response> {
+pub fn deposit_to_cdp(config: Config, operation_id: u64, cdp_idx: Uint128, received_aterra_amount: Uint128) -> Result<Response, ContractError> {
     Ok(Response::new()
         .add_submessage(SubMsg::reply_on_success(
             CosmosMsg::Wasm(WasmMsg::Execute {
@@ -136,7 +202,7 @@ pub fn deposit_to_cdp(config: Config, cdp_idx: Uint128, received_aterra_amount:
                 })?,
                 funds: vec![],
             }),
-            SubmsgIds::DepositToCDP.id(),
+            SubmsgIds::DepositToCDP.reply_id(operation_id),
         ))
         .add_attributes(vec![
             ("action", "deposit_to_cdp"),
@@ -144,7 +210,7 @@ pub fn deposit_to_cdp(config: Config, cdp_idx: Uint128, received_aterra_amount:
         ]))
 }
 
-pub fn mint_masset(config: Config, cdp_idx: Uint128, masset_token: String, amount_to_mint: Uint128) -> StdResult<Response> {
+pub fn mint_masset(config: Config, operation_id: u64, cdp_idx: Uint128, masset_token: String, amount_to_mint: Uint128) -> Result<Response, ContractError> {
     Ok(Response::new()
         .add_submessage(SubMsg::reply_on_success(CosmosMsg::Wasm(WasmMsg::Execute {
             contract_addr: config.mirror_mint_contract.to_string(),
@@ -157,7 +223,7 @@ pub fn mint_masset(config: Config, cdp_idx: Uint128, masset_token: String, amoun
                 short_params: None,
             })?,
             funds: vec![],
-        }), SubmsgIds::MintMAsset.id(),
+        }), SubmsgIds::MintMAsset.reply_id(operation_id),
         ))
         .add_attributes(vec![
             ("action", "mint_masset"),
@@ -166,7 +232,16 @@ pub fn mint_masset(config: Config, cdp_idx: Uint128, masset_token: String, amoun
         ]))
 }
 
-pub fn withdraw_collateral(config: Config, cdp_idx: Uint128, amount_to_withdraw: Uint128) -> StdResult<Response> {
+pub fn withdraw_collateral(config: Config, operation_id: u64, cdp_idx: Uint128, amount_to_withdraw: Uint128) -> Result<Response, ContractError> {
+    withdraw_collateral_with_reply(config, cdp_idx, amount_to_withdraw, SubmsgIds::WithdrawCollateral.reply_id(operation_id))
+}
+
+// Last withdraw of position which has no loan anymore
+pub fn withdraw_all_collateral(config: Config, operation_id: u64, cdp_idx: Uint128, position_collateral: Uint128) -> Result<Response, ContractError> {
+    withdraw_collateral_with_reply(config, cdp_idx, position_collateral, SubmsgIds::Exit.reply_id(operation_id))
+}
+
+fn withdraw_collateral_with_reply(config: Config, cdp_idx: Uint128, amount_to_withdraw: Uint128, reply_id: u64) -> Result<Response, ContractError> {
     Ok(Response::new()
         .add_submessage(SubMsg::reply_on_success(CosmosMsg::Wasm(WasmMsg::Execute {
             contract_addr: config.mirror_mint_contract.to_string(),
@@ -178,7 +253,7 @@ pub fn withdraw_collateral(config: Config, cdp_idx: Uint128, amount_to_withdraw:
                 }),
             })?,
             funds: vec![],
-        }), SubmsgIds::WithdrawCollateral.id(),
+        }), reply_id,
         )).add_attributes(vec![
         ("action", "withdraw_collateral"),
         ("cdp_idx", &cdp_idx.to_string()),
@@ -186,7 +261,7 @@ pub fn withdraw_collateral(config: Config, cdp_idx: Uint128, amount_to_withdraw:
     ]))
 }
 
-pub fn burn_masset(config: Config, state: WithdrawState, cdp_idx: Uint128, return_amount: Uint128) -> StdResult<Response> {
+pub fn burn_masset(config: Config, operation_id: u64, state: WithdrawState, cdp_idx: Uint128, return_amount: Uint128) -> Result<Response, ContractError> {
     Ok(Response::new()
         .add_submessage(SubMsg::reply_on_success(
             CosmosMsg::Wasm(WasmMsg::Execute {
@@ -200,7 +275,7 @@ pub fn burn_masset(config: Config, state: WithdrawState, cdp_idx: Uint128, retur
                 })?,
                 funds: vec![],
             }),
-            SubmsgIds::BurnMAsset.id(),
+            SubmsgIds::BurnMAsset.reply_id(operation_id),
         ))
         .add_attributes(vec![
             ("action", "burn_masset"),
@@ -0,0 +1,486 @@
+use cosmwasm_std::{Decimal, Deps, Env, Uint128};
+
+use structured_note_package::structured_note::{AssetConfigResponse, AssetConfigsResponse, CdpResponse, CdpsResponse, DepositCapacityResponse, DepositIterationResponse, FeePreviewResponse, PositionHealthResponse, PositionResponse, PositionsResponse, SimulateDepositResponse, SimulateWithdrawResponse, StatusResponse, WithdrawRoundResponse};
+
+use crate::anchor::{calculate_aterra_amount, calculate_redeemed_stable, query_aterra_exchange_rate};
+use crate::commands::{calculate_collateral_ratio, calculate_deposit_capacity, calculate_deposit_aim_loan, calculate_deposit_fill_ratio, calculate_deposit_price_impact, calculate_mirror_min_collateral_ratio, calculate_protocol_fee, calculate_safe_collateral_ratio, calculate_withdraw_amount, check_deposit_cap, is_aim_state, load_asset_config, plan_target_leverage, prepare_withdraw, resolve_leverage, validate_masset};
+use crate::error::ContractError;
+use crate::mirror::{query_assets_prices, query_cdp, query_masset_config, query_mirror_mint_config, query_position_state};
+use crate::state::{AssetConfig, calculate_position_state, CDP, DepositState, load_all_asset_configs, load_all_cdps, load_all_positions, load_cdp, load_config, load_total_collateral, may_load_cdp, load_frozen_assets, load_is_paused, load_position, load_positions_by_farmer_addr, may_load_position, Position, PositionState};
+use crate::terraswap::{resolve_pair_addr, simulate_buy_masset, simulate_sell_masset};
+use crate::utils::{decimal_division, decimal_multiplication, divide_by_decimal};
+
+// Withdraw loop converges geometrically, simulation gives up on rounds above the limit
+const MAX_SIMULATED_WITHDRAW_ROUNDS: usize = 50;
+
+pub fn query_status(deps: Deps) -> Result<StatusResponse, ContractError> {
+    Ok(StatusResponse {
+        is_paused: load_is_paused(deps.storage)?,
+        frozen_assets: load_frozen_assets(deps.storage)?
+            .into_iter()
+            .map(|masset_token| masset_token.to_string())
+            .collect(),
+    })
+}
+
+pub fn query_asset_config(deps: Deps, masset_token: String) -> Result<AssetConfigResponse, ContractError> {
+    let masset_token = deps.api.addr_validate(&masset_token)?;
+    Ok(asset_config_to_response(load_asset_config(deps.storage, &masset_token)?))
+}
+
+pub fn query_all_asset_configs(deps: Deps, start_after: Option<String>, limit: Option<u32>) -> Result<AssetConfigsResponse, ContractError> {
+    let start_after = start_after.map(|masset_token| deps.api.addr_validate(&masset_token)).transpose()?;
+    let asset_configs = load_all_asset_configs(deps.storage, start_after.as_ref(), limit)?;
+    Ok(AssetConfigsResponse {
+        asset_configs: asset_configs.into_iter().map(asset_config_to_response).collect(),
+    })
+}
+
+pub fn query_deposit_capacity(deps: Deps, masset_token: String) -> Result<DepositCapacityResponse, ContractError> {
+    let config = load_config(deps.storage)?;
+    let masset_token = deps.api.addr_validate(&masset_token)?;
+    let asset_config = load_asset_config(deps.storage, &masset_token)?;
+    let (cdp_collateral, cdp_loan) = match may_load_cdp(deps.storage, &masset_token)? {
+        Some(cdp) => (cdp.collateral, cdp.loan),
+        None => (Uint128::zero(), Uint128::zero()),
+    };
+    let capacity = calculate_deposit_capacity(deps.storage, &config, &asset_config)?;
+    Ok(DepositCapacityResponse {
+        masset_token: masset_token.to_string(),
+        cdp_collateral,
+        cdp_loan,
+        total_collateral: load_total_collateral(deps.storage)?,
+        asset_deposit_cap: asset_config.deposit_cap,
+        asset_loan_cap: asset_config.loan_cap,
+        global_deposit_cap: config.deposit_cap,
+        collateral_capacity: capacity.collateral,
+        loan_capacity: capacity.loan,
+    })
+}
+
+fn asset_config_to_response(asset_config: AssetConfig) -> AssetConfigResponse {
+    AssetConfigResponse {
+        masset_token: asset_config.masset_token.to_string(),
+        min_over_collateralization: asset_config.min_over_collateralization,
+        max_leverage: asset_config.max_leverage,
+        deposit_cap: asset_config.deposit_cap,
+        loan_cap: asset_config.loan_cap,
+        pair_addr: asset_config.pair_addr.map(|pair_addr| pair_addr.to_string()),
+    }
+}
+
+pub fn query_position(deps: Deps, farmer_addr: String, masset_token: String) -> Result<PositionResponse, ContractError> {
+    let farmer_addr = deps.api.addr_validate(&farmer_addr)?;
+    let masset_token = deps.api.addr_validate(&masset_token)?;
+    let position = load_position(deps.storage, &farmer_addr, &masset_token)?;
+    position_to_response(deps, position)
+}
+
+pub fn query_farmer_positions(deps: Deps, farmer_addr: String, start_after: Option<String>, limit: Option<u32>) -> Result<PositionsResponse, ContractError> {
+    let farmer_addr = deps.api.addr_validate(&farmer_addr)?;
+    let start_after = start_after.map(|masset_token| deps.api.addr_validate(&masset_token)).transpose()?;
+    let positions = load_positions_by_farmer_addr(deps.storage, &farmer_addr, start_after.as_ref(), limit)?;
+    Ok(PositionsResponse {
+        positions: positions
+            .into_iter()
+            .map(|position| position_to_response(deps, position))
+            .collect::<Result<Vec<PositionResponse>, ContractError>>()?,
+    })
+}
+
+pub fn query_all_positions(deps: Deps, start_after: Option<(String, String)>, limit: Option<u32>) -> Result<PositionsResponse, ContractError> {
+    let start_after = match start_after {
+        Some((farmer_addr, masset_token)) => Some((deps.api.addr_validate(&farmer_addr)?, deps.api.addr_validate(&masset_token)?)),
+        None => None,
+    };
+    let positions = load_all_positions(deps.storage, start_after.as_ref().map(|(f, m)| (f, m)), limit)?;
+    Ok(PositionsResponse {
+        positions: positions
+            .into_iter()
+            .map(|position| position_to_response(deps, position))
+            .collect::<Result<Vec<PositionResponse>, ContractError>>()?,
+    })
+}
+
+// Collateral is valued with Anchor exchange rate, loan with Mirror oracle price
+pub fn query_position_health(deps: Deps, env: Env, farmer_addr: String, masset_token: String) -> Result<PositionHealthResponse, ContractError> {
+    let config = load_config(deps.storage)?;
+    let farmer_addr = deps.api.addr_validate(&farmer_addr)?;
+    let masset_token = deps.api.addr_validate(&masset_token)?;
+    let position = load_position(deps.storage, &farmer_addr, &masset_token)?;
+    let position_state = query_position_state(deps, &position)?;
+
+    let mirror_mint_config = query_mirror_mint_config(deps, config.mirror_mint_contract.to_string())?;
+    let masset_config = query_masset_config(deps, &masset_token)?;
+    let asset_config = load_asset_config(deps.storage, &masset_token)?;
+    let prices = query_assets_prices(deps, &mirror_mint_config, &config, &masset_token)?;
+    let (collateral_price, masset_price, collateral_multiplier) = (prices.collateral_price, prices.masset_price, prices.collateral_multiplier);
+    let masset_price_in_collateral_asset = decimal_division(masset_price, collateral_price)?;
+    let exchange_rate = query_aterra_exchange_rate(deps, &config, env.block.height)?;
+
+    let mirror_min_collateral_ratio = calculate_mirror_min_collateral_ratio(&masset_config, collateral_multiplier);
+    let (collateral_ratio, liquidation_price_move, liquidation_price) = if position_state.loan.is_zero() {
+        (None, None, None)
+    } else {
+        let collateral_ratio = calculate_collateral_ratio(position_state.collateral, position_state.loan, masset_price_in_collateral_asset);
+        let liquidation_ratio = decimal_division(collateral_ratio, mirror_min_collateral_ratio)?;
+        let liquidation_price_move = if liquidation_ratio > Decimal::one() { liquidation_ratio - Decimal::one() } else { Decimal::zero() };
+        (Some(collateral_ratio), Some(liquidation_price_move), Some(decimal_multiplication(&masset_price, &liquidation_ratio)))
+    };
+
+    let collateral_value = calculate_redeemed_stable(position_state.collateral, exchange_rate);
+    let loan_value = position_state.loan * masset_price;
+    Ok(PositionHealthResponse {
+        collateral: position_state.collateral,
+        loan: position_state.loan,
+        collateral_ratio,
+        mirror_min_collateral_ratio,
+        min_over_collateralization: asset_config.min_over_collateralization,
+        safe_collateral_ratio: decimal_multiplication(&mirror_min_collateral_ratio, &asset_config.min_over_collateralization),
+        liquidation_price_move,
+        liquidation_price,
+        collateral_value,
+        loan_value,
+        net_asset_value: collateral_value.saturating_sub(loan_value),
+        price_age: prices.age(&env),
+    })
+}
+
+pub fn query_fee_preview(deps: Deps, deposit_amount: Uint128) -> Result<FeePreviewResponse, ContractError> {
+    let config = load_config(deps.storage)?;
+    let (fee_amount, net_deposit_amount) = calculate_protocol_fee(&config, deposit_amount);
+    Ok(FeePreviewResponse {
+        protocol_fee: config.protocol_fee,
+        fee_amount,
+        net_deposit_amount,
+    })
+}
+
+pub fn query_cdp_info(deps: Deps, masset_token: String) -> Result<CdpResponse, ContractError> {
+    let masset_token = deps.api.addr_validate(&masset_token)?;
+    let cdp = load_cdp(deps.storage, &masset_token)?;
+    cdp_to_response(deps, cdp)
+}
+
+pub fn query_all_cdps(deps: Deps, start_after: Option<String>, limit: Option<u32>) -> Result<CdpsResponse, ContractError> {
+    let start_after = start_after.map(|masset_token| deps.api.addr_validate(&masset_token)).transpose()?;
+    let cdps = load_all_cdps(deps.storage, start_after.as_ref(), limit)?;
+    Ok(CdpsResponse {
+        cdps: cdps
+            .into_iter()
+            .map(|cdp| cdp_to_response(deps, cdp))
+            .collect::<Result<Vec<CdpResponse>, ContractError>>()?,
+    })
+}
+
+fn cdp_to_response(deps: Deps, cdp: CDP) -> Result<CdpResponse, ContractError> {
+    let mirror_cdp = query_cdp(deps, cdp.idx)?;
+
+    let mut farmers_collateral = Uint128::zero();
+    let mut farmers_loan = Uint128::zero();
+    let mut farmers_collateral_shares = Uint128::zero();
+    let mut farmers_loan_shares = Uint128::zero();
+    for farmer_addr in cdp.farmers.iter() {
+        if let Some(position) = may_load_position(deps.storage, farmer_addr, &cdp.masset_token)? {
+            let position_state = calculate_position_state(&position, &cdp, &mirror_cdp);
+            farmers_collateral += position_state.collateral;
+            farmers_loan += position_state.loan;
+            farmers_collateral_shares += position.collateral_shares;
+            farmers_loan_shares += position.loan_shares;
+        };
+    }
+
+    Ok(CdpResponse {
+        idx: cdp.idx,
+        masset_token: cdp.masset_token.to_string(),
+        farmers: cdp.farmers.iter().map(|f| f.to_string()).collect(),
+        mirror_collateral: mirror_cdp.collateral_amount,
+        mirror_loan: mirror_cdp.loan_amount,
+        farmers_collateral,
+        farmers_loan,
+        collateral_shares: cdp.collateral_shares,
+        loan_shares: cdp.loan_shares,
+        farmers_collateral_shares,
+        farmers_loan_shares,
+    })
+}
+
+fn position_to_response(deps: Deps, position: Position) -> Result<PositionResponse, ContractError> {
+    let position_state = query_position_state(deps, &position)?;
+    Ok(PositionResponse {
+        farmer_addr: position.farmer_addr.to_string(),
+        masset_token: position.masset_token.to_string(),
+        cdp_idx: position.cdp_idx,
+        leverage: position.leverage,
+        loan: position_state.loan,
+        collateral: position_state.collateral,
+        loan_shares: position.loan_shares,
+        collateral_shares: position.collateral_shares,
+        aim_collateral_ratio: position.aim_collateral_ratio,
+        target_leverage: position.target_leverage,
+    })
+}
+
+// Sells of all iterations hit the same pool one by one, so return of every sell is
+// the difference between simulated returns of cumulative sold amounts
+#[allow(clippy::too_many_arguments)]
+pub fn query_simulate_deposit(
+    deps: Deps,
+    env: Env,
+    farmer_addr: String,
+    masset_token: String,
+    deposit_amount: Uint128,
+    leverage: Option<u8>,
+    target_leverage: Option<Decimal>,
+    aim_collateral_ratio: Decimal,
+) -> Result<SimulateDepositResponse, ContractError> {
+    let config = load_config(deps.storage)?;
+    let farmer_addr = deps.api.addr_validate(&farmer_addr)?;
+    let masset_token = deps.api.addr_validate(&masset_token)?;
+
+    let mirror_mint_config = query_mirror_mint_config(deps, config.mirror_mint_contract.to_string())?;
+    let masset_config = query_masset_config(deps, &masset_token)?;
+    let asset_config = load_asset_config(deps.storage, &masset_token)?;
+    validate_masset(&masset_config)?;
+
+    let prices = query_assets_prices(deps, &mirror_mint_config, &config, &masset_token)?;
+    let (collateral_price, masset_price, collateral_multiplier) = (prices.collateral_price, prices.masset_price, prices.collateral_multiplier);
+    let min_collateral_ratio = calculate_safe_collateral_ratio(&asset_config, &masset_config, collateral_multiplier);
+    if aim_collateral_ratio < min_collateral_ratio {
+        return Err(ContractError::CollateralRatioTooLow {
+            collateral_ratio: aim_collateral_ratio,
+            min_collateral_ratio,
+        });
+    };
+    if deposit_amount.is_zero() {
+        return Err(ContractError::ZeroDeposit {});
+    };
+
+    let position = may_load_position(deps.storage, &farmer_addr, &masset_token)?;
+    let target_leverage = target_leverage.or_else(|| position.as_ref().and_then(|p| p.target_leverage));
+    // iterations for target leverage are planned below
+    let leverage = match target_leverage {
+        Some(_) => 0,
+        None => resolve_leverage(&farmer_addr, &masset_token, position.as_ref(), leverage, asset_config.max_leverage)?,
+    };
+    let position_state = match &position {
+        Some(p) => query_position_state(deps, p)?,
+        None => PositionState::default(),
+    };
+
+    if calculate_protocol_fee(&config, deposit_amount).1.is_zero() {
+        return Err(ContractError::ZeroDepositAfterFee {});
+    };
+
+    let pair_addr = resolve_pair_addr(deps, &mirror_mint_config, &asset_config)?;
+    let masset_price_in_collateral_asset = decimal_division(masset_price, collateral_price)?;
+    let exchange_rate = query_aterra_exchange_rate(deps, &config, env.block.height)?;
+
+    let mut state = DepositState {
+        farmer_addr,
+        masset_token,
+        leverage,
+        cur_iteration_index: 0,
+        asset_price_in_collateral_asset: masset_price_in_collateral_asset,
+        pair_addr,
+        aim_collateral_ratio,
+        masset_price,
+        max_spread: config.default_max_spread,
+        target_loan: None,
+        target_leverage,
+    };
+    // Deposit plans with collateral oracle price, simulation replays with Anchor exchange rate
+    let planned_deposit_collateral_of = |amount: Uint128| -> Result<Uint128, ContractError> {
+        let (_, net_amount) = calculate_protocol_fee(&config, amount);
+        Ok(divide_by_decimal(net_amount, collateral_price)?)
+    };
+    let plan_target = |state: &mut DepositState, deposit_collateral: Uint128| -> Result<(), ContractError> {
+        if let Some(target_leverage) = target_leverage {
+            let (iterations, target_loan) = plan_target_leverage(state, &position_state, deposit_collateral, collateral_price, target_leverage, asset_config.max_leverage)?;
+            state.leverage = iterations;
+            state.target_loan = Some(target_loan);
+        };
+        Ok(())
+    };
+    let mut accepted_amount = deposit_amount;
+    let mut planned_deposit_collateral = planned_deposit_collateral_of(accepted_amount)?;
+    plan_target(&mut state, planned_deposit_collateral)?;
+
+    // the same partial fill as Deposit, not accepted amount is refunded
+    let capacity = calculate_deposit_capacity(deps.storage, &config, &asset_config)?;
+    let fill_ratio = calculate_deposit_fill_ratio(&state, &position_state, planned_deposit_collateral, collateral_price, &capacity)?;
+    if fill_ratio < Decimal::one() {
+        accepted_amount = accepted_amount * fill_ratio;
+        planned_deposit_collateral = planned_deposit_collateral_of(accepted_amount)?;
+        if planned_deposit_collateral.is_zero() {
+            return Err(ContractError::DepositCapReached { masset_token: state.masset_token.to_string() });
+        };
+        plan_target(&mut state, planned_deposit_collateral)?;
+    };
+    check_deposit_cap(&state, &position_state, planned_deposit_collateral, collateral_price, &capacity)?;
+    let (fee_amount, net_deposit_amount) = calculate_protocol_fee(&config, accepted_amount);
+
+    let deposit_collateral = calculate_aterra_amount(net_deposit_amount, exchange_rate);
+    let mut collateral = position_state.collateral + deposit_collateral;
+    let mut loan = position_state.loan;
+    let mut total_sold = Uint128::zero();
+    let mut total_return = Uint128::zero();
+    let mut spread_amount = Uint128::zero();
+    let mut commission_amount = Uint128::zero();
+    let mut iterations = vec![];
+    for _ in 0..state.leverage {
+        let aim_loan = calculate_deposit_aim_loan(&state, collateral)?;
+        if aim_loan <= loan {
+            break;
+        };
+        let mint_amount = aim_loan - loan;
+        loan = aim_loan;
+
+        total_sold += mint_amount;
+        let simulation = simulate_sell_masset(deps, &state.pair_addr, &state.masset_token, total_sold)?;
+        let stable_received = simulation.return_amount.saturating_sub(total_return);
+        total_return = simulation.return_amount;
+        spread_amount = simulation.spread_amount;
+        commission_amount = simulation.commission_amount;
+
+        let collateral_received = calculate_aterra_amount(stable_received, exchange_rate);
+        collateral += collateral_received;
+        iterations.push(DepositIterationResponse {
+            mint_amount,
+            stable_received,
+            collateral_received,
+            collateral,
+            loan,
+        });
+    }
+
+    let (collateral_ratio, effective_leverage) = if loan.is_zero() {
+        (None, Decimal::one())
+    } else {
+        let loan_in_collateral_asset = loan * masset_price_in_collateral_asset;
+        (
+            Some(calculate_collateral_ratio(collateral, loan, masset_price_in_collateral_asset)),
+            Decimal::from_ratio(collateral, collateral.saturating_sub(loan_in_collateral_asset).max(Uint128::new(1))),
+        )
+    };
+
+    let price_impact = calculate_deposit_price_impact(deps, &state, &position_state, planned_deposit_collateral, collateral_price)?;
+
+    Ok(SimulateDepositResponse {
+        leverage: state.leverage,
+        accepted_amount,
+        refund_amount: deposit_amount - accepted_amount,
+        fee_amount,
+        net_deposit_amount,
+        deposit_collateral,
+        iterations,
+        collateral,
+        loan,
+        collateral_ratio,
+        effective_leverage,
+        spread_amount,
+        commission_amount,
+        price_impact,
+        price_age: prices.age(&env),
+    })
+}
+
+// Mirrors WithdrawCollateral -> RedeemStable -> BuyMAsset -> BurnMAsset replies, buys are simulated
+// on cumulative offer amounts the same way as sells of SimulateDeposit
+pub fn query_simulate_withdraw(
+    deps: Deps,
+    env: Env,
+    farmer_addr: String,
+    masset_token: String,
+    aim_collateral: Uint128,
+    aim_collateral_ratio: Decimal,
+) -> Result<SimulateWithdrawResponse, ContractError> {
+    let config = load_config(deps.storage)?;
+    let farmer_addr = deps.api.addr_validate(&farmer_addr)?;
+    let masset_token = deps.api.addr_validate(&masset_token)?;
+
+    let mirror_mint_config = query_mirror_mint_config(deps, config.mirror_mint_contract.to_string())?;
+    let prices = query_assets_prices(deps, &mirror_mint_config, &config, &masset_token)?;
+    let (_, mut position_state, state) = prepare_withdraw(deps, &farmer_addr, &masset_token, aim_collateral, aim_collateral_ratio, None, &prices)?;
+    let masset_price_in_collateral_asset = decimal_division(state.masset_price, state.collateral_price)?;
+    let exchange_rate = query_aterra_exchange_rate(deps, &config, env.block.height)?;
+
+    let mut stable_balance = Uint128::zero();
+    let mut masset_balance = Uint128::zero();
+    let mut total_offered = Uint128::zero();
+    let mut total_bought = Uint128::zero();
+    let mut spread_amount = Uint128::zero();
+    let mut commission_amount = Uint128::zero();
+    let mut rounds = vec![];
+    let mut reaches_aim = false;
+    while rounds.len() < MAX_SIMULATED_WITHDRAW_ROUNDS {
+        let withdraw_amount = calculate_withdraw_amount(position_state.collateral, position_state.loan, state.aim_collateral, masset_price_in_collateral_asset, state.safe_collateral_ratio);
+        if withdraw_amount.is_zero() {
+            break;
+        };
+        position_state.collateral -= withdraw_amount;
+        let stable_redeemed = calculate_redeemed_stable(withdraw_amount, exchange_rate);
+        stable_balance += stable_redeemed;
+
+        let mut round = WithdrawRoundResponse {
+            withdraw_amount,
+            stable_redeemed,
+            offer_amount: Uint128::zero(),
+            masset_bought: Uint128::zero(),
+            burn_amount: Uint128::zero(),
+            protocol_fee: Uint128::zero(),
+            collateral: position_state.collateral,
+            loan: position_state.loan,
+        };
+        if is_aim_state(&position_state, &state) {
+            rounds.push(round);
+            reaches_aim = true;
+            break;
+        };
+
+        let repay_to_aim_value = position_state.loan.saturating_sub(state.aim_loan) * state.masset_price;
+        let offer_amount = stable_balance.min(repay_to_aim_value);
+        if offer_amount.is_zero() {
+            rounds.push(round);
+            break;
+        };
+        stable_balance -= offer_amount;
+        total_offered += offer_amount;
+        let simulation = simulate_buy_masset(deps, &state.pair_addr, &config.stable_denom, total_offered)?;
+        let masset_bought = simulation.return_amount.saturating_sub(total_bought);
+        total_bought = simulation.return_amount;
+        spread_amount = simulation.spread_amount;
+        commission_amount = simulation.commission_amount;
+
+        let burn_amount = masset_bought.min(position_state.loan);
+        masset_balance += masset_bought - burn_amount;
+        let protocol_fee = (burn_amount * masset_price_in_collateral_asset * mirror_mint_config.protocol_fee_rate).min(position_state.collateral);
+        position_state.loan -= burn_amount;
+        position_state.collateral -= protocol_fee;
+
+        round.offer_amount = offer_amount;
+        round.masset_bought = masset_bought;
+        round.burn_amount = burn_amount;
+        round.protocol_fee = protocol_fee;
+        round.collateral = position_state.collateral;
+        round.loan = position_state.loan;
+        rounds.push(round);
+        if is_aim_state(&position_state, &state) {
+            reaches_aim = true;
+            break;
+        };
+    }
+
+    Ok(SimulateWithdrawResponse {
+        aim_loan: state.aim_loan,
+        rounds,
+        collateral: position_state.collateral,
+        loan: position_state.loan,
+        reaches_aim,
+        spread_amount,
+        commission_amount,
+        return_amount: stable_balance,
+        return_masset_amount: masset_balance,
+        price_age: prices.age(&env),
+    })
+}
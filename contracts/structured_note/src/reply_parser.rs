@@ -0,0 +1,124 @@
+use std::str::FromStr;
+
+use cosmwasm_std::{Addr, Event, StdError, Uint128};
+use terraswap::asset::{Asset, AssetInfo};
+use thiserror::Error;
+
+// wasmd emits `wasm` event per contract, Terra core 0.16 emits one `from_contract` event for all
+// contracts called by the message
+const WASM_EVENT_TYPES: [&str; 2] = ["wasm", "from_contract"];
+// wasmd emits `_contract_address`, Terra core emits `contract_address`
+const CONTRACT_ADDRESS_KEYS: [&str; 2] = ["_contract_address", "contract_address"];
+const TOKEN_ADDR_PREFIX: &str = "terra1";
+
+#[derive(Error, Debug, PartialEq)]
+pub enum ReplyParseError {
+    #[error("Attr '{key}' not found in wasm events of contract {contract_addr}")]
+    AttrNotFound { contract_addr: String, key: String },
+
+    #[error("Fail to parse amount of attr '{key}'. Attr value: '{value}'")]
+    InvalidAmount { key: String, value: String },
+
+    #[error("Fail to parse asset of attr '{key}'. Attr value: '{value}'")]
+    InvalidAsset { key: String, value: String },
+
+    #[error("Unexpected asset of attr '{key}': expected {expected}, got {actual}")]
+    UnexpectedAsset { key: String, expected: String, actual: String },
+}
+
+impl From<ReplyParseError> for StdError {
+    fn from(err: ReplyParseError) -> Self {
+        StdError::generic_err(err.to_string())
+    }
+}
+
+// Attributes of wasm events emitted by contract_addr only, so the same key of another contract
+// called in the same submessage (e.g. cw20 token on Send) is never picked up.
+// Each contract address key starts section of attributes of that contract in the event
+pub fn may_find_wasm_attr(events: &[Event], contract_addr: &Addr, key: &str) -> Option<String> {
+    events
+        .iter()
+        .filter(|event| WASM_EVENT_TYPES.contains(&event.ty.as_str()))
+        .flat_map(|event| {
+            let mut is_contract_section = false;
+            event.attributes.iter().filter(move |attr| {
+                if CONTRACT_ADDRESS_KEYS.contains(&attr.key.as_str()) {
+                    is_contract_section = attr.value == contract_addr.as_str();
+                    return false;
+                };
+                is_contract_section
+            })
+        })
+        .find(|attr| attr.key == key)
+        .map(|attr| attr.value.clone())
+}
+
+pub fn find_wasm_attr(events: &[Event], contract_addr: &Addr, key: &str) -> Result<String, ReplyParseError> {
+    may_find_wasm_attr(events, contract_addr, key).ok_or_else(|| ReplyParseError::AttrNotFound {
+        contract_addr: contract_addr.to_string(),
+        key: key.to_string(),
+    })
+}
+
+pub fn parse_amount_attr(events: &[Event], contract_addr: &Addr, key: &str) -> Result<Uint128, ReplyParseError> {
+    let value = find_wasm_attr(events, contract_addr, key)?;
+    parse_amount(key, &value)
+}
+
+// Returns amount of asset attr, checking that it's expected asset
+pub fn parse_asset_attr(events: &[Event], contract_addr: &Addr, key: &str, expected: &AssetInfo) -> Result<Uint128, ReplyParseError> {
+    let value = find_wasm_attr(events, contract_addr, key)?;
+    asset_amount(key, &value, expected)
+}
+
+pub fn may_parse_asset_attr(events: &[Event], contract_addr: &Addr, key: &str, expected: &AssetInfo) -> Result<Option<Uint128>, ReplyParseError> {
+    may_find_wasm_attr(events, contract_addr, key)
+        .map(|value| asset_amount(key, &value, expected))
+        .transpose()
+}
+
+pub fn token_asset_info(token_addr: &Addr) -> AssetInfo {
+    AssetInfo::Token { contract_addr: token_addr.to_string() }
+}
+
+fn asset_amount(key: &str, value: &str, expected: &AssetInfo) -> Result<Uint128, ReplyParseError> {
+    let asset = parse_asset(key, value)?;
+    if !asset.info.equal(expected) {
+        return Err(ReplyParseError::UnexpectedAsset {
+            key: key.to_string(),
+            expected: expected.to_string(),
+            actual: asset.info.to_string(),
+        });
+    };
+    Ok(asset.amount)
+}
+
+fn parse_amount(key: &str, value: &str) -> Result<Uint128, ReplyParseError> {
+    Uint128::from_str(value).map_err(|_| ReplyParseError::InvalidAmount {
+        key: key.to_string(),
+        value: value.to_string(),
+    })
+}
+
+// Asset as string is amount followed by token address or native denom without spaces:
+// 123terra1..., 123uusd, 123ibc/27394FB092D2ECCD56123C74F36E4C1F926001CEADA9CA97EA622B25F41E5EB2
+pub fn parse_asset(key: &str, value: &str) -> Result<Asset, ReplyParseError> {
+    let invalid_asset = || ReplyParseError::InvalidAsset {
+        key: key.to_string(),
+        value: value.to_string(),
+    };
+    let split_idx = value.find(|c: char| !c.is_ascii_digit()).ok_or_else(invalid_asset)?;
+    let (amount, asset) = value.split_at(split_idx);
+    if amount.is_empty() {
+        return Err(invalid_asset());
+    };
+    let info = if asset.starts_with(TOKEN_ADDR_PREFIX) {
+        AssetInfo::Token { contract_addr: asset.to_string() }
+    } else {
+        AssetInfo::NativeToken { denom: asset.to_string() }
+    };
+    Ok(Asset {
+        info,
+        amount: parse_amount(key, amount)?,
+    })
+}
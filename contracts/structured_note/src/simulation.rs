@@ -0,0 +1,59 @@
+use cosmwasm_std::{Decimal, StdResult, Uint128};
+
+use crate::commands::calculate_deposit_aim_loan;
+use crate::state::DepositState;
+use crate::utils::divide_by_decimal;
+
+// Replays DepositToCDP -> MintMAsset -> SellMAsset -> DepositStable loop without price impact
+// and returns amounts of masset to be minted and sold on every iteration
+pub fn plan_deposit_mints(state: &DepositState, collateral: Uint128, loan: Uint128, deposit_collateral: Uint128, collateral_price: Decimal) -> StdResult<Vec<Uint128>> {
+    let mut collateral = collateral + deposit_collateral;
+    let mut loan = loan;
+    let mut mints = vec![];
+    for _ in 0..state.leverage {
+        let aim_loan = calculate_deposit_aim_loan(state, collateral)?;
+        if aim_loan <= loan {
+            break;
+        };
+        let mint_amount = aim_loan - loan;
+        loan = aim_loan;
+        let received_stable = mint_amount * state.masset_price;
+        collateral += divide_by_decimal(received_stable, collateral_price)?;
+        mints.push(mint_amount);
+    }
+    Ok(mints)
+}
+
+// Collateral added by deposit loop: deposit itself and stable of planned sells valued at oracle prices
+pub fn plan_deposit_collateral(state: &DepositState, deposit_collateral: Uint128, mints: &[Uint128], collateral_price: Decimal) -> StdResult<Uint128> {
+    let mut collateral = deposit_collateral;
+    for mint_amount in mints {
+        collateral += divide_by_decimal(*mint_amount * state.masset_price, collateral_price)?;
+    }
+    Ok(collateral)
+}
+
+// Constant product estimation of price impact of selling all amounts one by one,
+// compared with spot price before the first swap
+pub fn estimate_sell_price_impact(masset_reserve: Uint128, stable_reserve: Uint128, sell_amounts: &[Uint128]) -> Decimal {
+    if masset_reserve.is_zero() || stable_reserve.is_zero() {
+        return Decimal::one();
+    };
+    let mut masset_reserve = masset_reserve;
+    let mut stable_reserve = stable_reserve;
+    let mut total_sold = Uint128::zero();
+    let mut total_return = Uint128::zero();
+    for sell_amount in sell_amounts {
+        let return_amount = stable_reserve.multiply_ratio(*sell_amount, masset_reserve + *sell_amount);
+        masset_reserve += *sell_amount;
+        stable_reserve -= return_amount;
+        total_sold += *sell_amount;
+        total_return += return_amount;
+    }
+
+    let expected_return = total_sold.multiply_ratio(stable_reserve + total_return, masset_reserve - total_sold);
+    if expected_return.is_zero() {
+        return Decimal::zero();
+    };
+    Decimal::one() - Decimal::from_ratio(total_return, expected_return)
+}
@@ -1,17 +1,30 @@
-use cosmwasm_std::{Addr, Decimal, Order, StdError, StdResult, Storage, Uint128};
-use cw_storage_plus::{Item, Map};
+use cosmwasm_std::{Addr, Decimal, Order, StdError, StdResult, Storage, Uint128, Uint256};
+use cw_storage_plus::{Bound, Item, Map, PrimaryKey, U64Key};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
+use structured_note_package::mirror::CDPState;
+use structured_note_package::structured_note::ReceiveAs;
+
 static KEY_CONFIG: Item<Config> = Item::new("config");
-static KEY_DEPOSIT_STATE: Item<DepositState> = Item::new("deposit_state");
-static KEY_WITHDRAW_STATE: Item<WithdrawState> = Item::new("withdraw_state");
+static KEY_LAST_OPERATION_ID: Item<u64> = Item::new("last_operation_id");
+static KEY_IS_PAUSED: Item<bool> = Item::new("is_paused");
+// Sum of tracked collateral of all CDPs
+static KEY_TOTAL_COLLATERAL: Item<Uint128> = Item::new("total_collateral");
+// Map<masset_token, masset_token> of massets which don't accept deposits
+static KEY_FROZEN_ASSETS: Map<&Addr, Addr> = Map::new("frozen_assets");
+// Map<operation.id, OperationState>, operation is removed when its reply chain is finished
+static KEY_OPERATIONS: Map<U64Key, OperationState> = Map::new("operations");
+// Map<asset_config.masset_token, AssetConfig> of massets allowed by governance
+static KEY_ASSET_CONFIGS: Map<&Addr, AssetConfig> = Map::new("asset_configs");
 // Map<cdp.masset_token, CDP>
 static KEY_CDPS: Map<&Addr, CDP> = Map::new("cdps");
 // Map<(position.farmer_addr, position.masset_token), Position>
 static KEY_POSITIONS: Map<(&Addr, &Addr), Position> = Map::new("positions");
-static KEY_IS_OPEN: Item<bool> = Item::new("is_open");
-static KEY_IS_RAW: Item<bool> = Item::new("is_raw");
+
+// Pagination settings for range queries
+const DEFAULT_LIMIT: u32 = 10;
+const MAX_LIMIT: u32 = 30;
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct Config {
@@ -22,7 +35,30 @@ pub struct Config {
     pub aterra_addr: Addr,
     pub nexus_treasury: Addr,
     pub protocol_fee: Decimal,
+    pub default_max_spread: Decimal,
+    pub max_price_impact: Decimal,
+    pub rebalance_band: Decimal,
+    pub protect_bounty: Decimal,
+    // Max age of Mirror oracle prices in seconds
+    pub max_oracle_age: u64,
+    // Max deviation of Terraswap pool price from oracle price to sell or buy masset
+    pub max_price_deviation: Decimal,
+    // Max total collateral of all CDPs, not capped if None
+    pub deposit_cap: Option<Uint128>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct AssetConfig {
+    pub masset_token: Addr,
+    // CDP is kept above Mirror min collateral ratio multiplied by it
     pub min_over_collateralization: Decimal,
+    pub max_leverage: u8,
+    // Max collateral of masset CDP, not capped if None
+    pub deposit_cap: Option<Uint128>,
+    // Max loan of masset CDP, not capped if None
+    pub loan_cap: Option<Uint128>,
+    // Pair to swap masset in, Terraswap factory pair if None
+    pub pair_addr: Option<Addr>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
@@ -30,6 +66,26 @@ pub struct CDP {
     pub idx: Uint128,
     pub masset_token: Addr,
     pub farmers: Vec<Addr>,
+    // total shares of all farmers in Mirror position collateral and loan
+    pub collateral_shares: Uint128,
+    pub loan_shares: Uint128,
+    // Mirror position amounts as of the last change of shares or CDP protection
+    #[serde(default)]
+    pub collateral: Uint128,
+    #[serde(default)]
+    pub loan: Uint128,
+}
+
+// Every deposit, withdraw or CDP protection is an operation, its id is carried by reply ids of its submessages
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct OperationState {
+    pub id: u64,
+    pub start_height: u64,
+    pub is_open: bool,
+    pub is_raw: bool,
+    pub deposit_state: Option<DepositState>,
+    pub withdraw_state: Option<WithdrawState>,
+    pub protect_state: Option<ProtectState>,
 }
 
 //Store data for recursive deposit and withdraw
@@ -42,6 +98,12 @@ pub struct DepositState {
     pub asset_price_in_collateral_asset: Decimal,
     pub pair_addr: Addr,
     pub aim_collateral_ratio: Decimal,
+    pub masset_price: Decimal,
+    pub max_spread: Decimal,
+    // loan which brings position to target leverage or fills deposit caps, minting stops on it
+    pub target_loan: Option<Uint128>,
+    #[serde(default)]
+    pub target_leverage: Option<Decimal>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
@@ -54,6 +116,26 @@ pub struct WithdrawState {
     pub collateral_price: Decimal,
     pub masset_price: Decimal,
     pub safe_collateral_ratio: Decimal,
+    pub max_spread: Decimal,
+    #[serde(default)]
+    pub receive_as: ReceiveAs,
+    #[serde(default)]
+    pub balances_before: Balances,
+}
+
+// Contract balances at operation start, operation pays out only amounts received above them
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, JsonSchema)]
+pub struct Balances {
+    pub stable: Uint128,
+    pub aterra: Uint128,
+    pub masset: Uint128,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct ProtectState {
+    pub caller: Addr,
+    pub masset_token: Addr,
+    pub bounty: Uint128,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
@@ -61,10 +143,21 @@ pub struct Position {
     pub farmer_addr: Addr,
     pub masset_token: Addr,
     pub cdp_idx: Uint128,
+    // iterations of deposit loop, for position with target leverage it's planned again on every deposit
     pub leverage: u8,
-    pub loan: Uint128,
-    pub collateral: Uint128,
+    pub loan_shares: Uint128,
+    pub collateral_shares: Uint128,
     pub aim_collateral_ratio: Decimal,
+    // exposure set by the first deposit, later deposits and rebalances mint up to it
+    #[serde(default)]
+    pub target_leverage: Option<Decimal>,
+}
+
+// Farmer's pro-rata claim on Mirror position collateral and loan
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, JsonSchema)]
+pub struct PositionState {
+    pub collateral: Uint128,
+    pub loan: Uint128,
 }
 
 pub fn load_config(storage: &dyn Storage) -> StdResult<Config> {
@@ -75,6 +168,55 @@ pub fn save_config(storage: &mut dyn Storage, config: &Config) -> StdResult<()>
     KEY_CONFIG.save(storage, config)
 }
 
+pub fn load_is_paused(storage: &dyn Storage) -> StdResult<bool> {
+    Ok(KEY_IS_PAUSED.may_load(storage)?.unwrap_or_default())
+}
+
+pub fn save_is_paused(storage: &mut dyn Storage, is_paused: bool) -> StdResult<()> {
+    KEY_IS_PAUSED.save(storage, &is_paused)
+}
+
+pub fn is_asset_frozen(storage: &dyn Storage, masset_token: &Addr) -> bool {
+    KEY_FROZEN_ASSETS.has(storage, masset_token)
+}
+
+pub fn freeze_asset(storage: &mut dyn Storage, masset_token: &Addr) -> StdResult<()> {
+    KEY_FROZEN_ASSETS.save(storage, masset_token, masset_token)
+}
+
+pub fn unfreeze_asset(storage: &mut dyn Storage, masset_token: &Addr) {
+    KEY_FROZEN_ASSETS.remove(storage, masset_token)
+}
+
+pub fn load_frozen_assets(storage: &dyn Storage) -> StdResult<Vec<Addr>> {
+    KEY_FROZEN_ASSETS
+        .range(storage, None, None, Order::Ascending)
+        .map(|asset| Ok(asset?.1))
+        .collect()
+}
+
+pub fn may_load_asset_config(storage: &dyn Storage, masset_token: &Addr) -> StdResult<Option<AssetConfig>> {
+    KEY_ASSET_CONFIGS.may_load(storage, masset_token)
+}
+
+pub fn load_all_asset_configs(storage: &dyn Storage, start_after: Option<&Addr>, limit: Option<u32>) -> StdResult<Vec<AssetConfig>> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let start = start_after.map(|masset_token| Bound::exclusive(masset_token.as_bytes()));
+    KEY_ASSET_CONFIGS
+        .range(storage, start, None, Order::Ascending)
+        .take(limit)
+        .map(|asset_config| Ok(asset_config?.1))
+        .collect()
+}
+
+pub fn save_asset_config(storage: &mut dyn Storage, asset_config: &AssetConfig) -> StdResult<()> {
+    KEY_ASSET_CONFIGS.save(storage, &asset_config.masset_token, asset_config)
+}
+
+pub fn remove_asset_config(storage: &mut dyn Storage, masset_token: &Addr) {
+    KEY_ASSET_CONFIGS.remove(storage, masset_token)
+}
+
 pub fn may_load_cdp(storage: &dyn Storage, masset_token: &Addr) -> StdResult<Option<CDP>> {
     KEY_CDPS.may_load(storage, masset_token)
 }
@@ -83,15 +225,22 @@ pub fn load_cdp(storage: &dyn Storage, masset_token: &Addr) -> StdResult<CDP> {
     KEY_CDPS.load(storage, masset_token)
 }
 
-pub fn load_all_cdps(storage: &dyn Storage) -> StdResult<Vec<CDP>> {
+pub fn load_all_cdps(storage: &dyn Storage, start_after: Option<&Addr>, limit: Option<u32>) -> StdResult<Vec<CDP>> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let start = start_after.map(|masset_token| Bound::exclusive(masset_token.as_bytes()));
     KEY_CDPS
-        .range(storage, None, None, Order::Ascending)
+        .range(storage, start, None, Order::Ascending)
+        .take(limit)
         .map(|cdp| {
             let (_, v) = cdp?;
             Ok(CDP {
                 idx: v.idx,
                 masset_token: v.masset_token,
                 farmers: v.farmers,
+                collateral_shares: v.collateral_shares,
+                loan_shares: v.loan_shares,
+                collateral: v.collateral,
+                loan: v.loan,
             })
         })
         .collect()
@@ -101,6 +250,30 @@ pub fn save_cdp(storage: &mut dyn Storage, cdp: &CDP) -> StdResult<()> {
     KEY_CDPS.save(storage, &cdp.masset_token, cdp)
 }
 
+pub fn load_total_collateral(storage: &dyn Storage) -> StdResult<Uint128> {
+    Ok(KEY_TOTAL_COLLATERAL.may_load(storage)?.unwrap_or_default())
+}
+
+pub fn save_total_collateral(storage: &mut dyn Storage, total_collateral: Uint128) -> StdResult<()> {
+    KEY_TOTAL_COLLATERAL.save(storage, &total_collateral)
+}
+
+// Tracked amounts of CDP follow Mirror position, total collateral follows tracked amounts of CDPs
+fn sync_cdp_amounts(storage: &mut dyn Storage, cdp: &mut CDP, cdp_state: &CDPState) -> StdResult<()> {
+    let total_collateral = load_total_collateral(storage)?.saturating_sub(cdp.collateral) + cdp_state.collateral_amount;
+    save_total_collateral(storage, total_collateral)?;
+    cdp.collateral = cdp_state.collateral_amount;
+    cdp.loan = cdp_state.loan_amount;
+    Ok(())
+}
+
+pub fn update_cdp_amounts(storage: &mut dyn Storage, masset_token: &Addr, cdp_state: &CDPState) -> StdResult<CDP> {
+    let mut cdp = load_cdp(storage, masset_token)?;
+    sync_cdp_amounts(storage, &mut cdp, cdp_state)?;
+    save_cdp(storage, &cdp)?;
+    Ok(cdp)
+}
+
 pub fn remove_cdp(storage: &mut dyn Storage, masset_token: &Addr) {
     KEY_CDPS.remove(storage, masset_token)
 }
@@ -113,6 +286,10 @@ pub fn add_farmer_to_cdp(storage: &mut dyn Storage, cdp_idx: Uint128, farmer_add
                     idx: cdp_idx,
                     masset_token: masset_token.clone(),
                     farmers: vec![farmer_addr],
+                    collateral_shares: Uint128::zero(),
+                    loan_shares: Uint128::zero(),
+                    collateral: Uint128::zero(),
+                    loan: Uint128::zero(),
                 }
             ),
             Some(mut cdp) => {
@@ -137,30 +314,70 @@ pub fn remove_farmer_from_cdp(storage: &mut dyn Storage, farmer_addr: &Addr, mas
             }
         }
     };
-    KEY_CDPS.update(storage, &masset_token, action)
+    KEY_CDPS.update(storage, masset_token, action)
 }
 
-pub fn load_deposit_state(storage: &dyn Storage) -> StdResult<DepositState> {
-    KEY_DEPOSIT_STATE.load(storage)
+pub fn start_operation(storage: &mut dyn Storage, block_height: u64) -> StdResult<u64> {
+    let id = KEY_LAST_OPERATION_ID.may_load(storage)?.unwrap_or_default() + 1;
+    KEY_LAST_OPERATION_ID.save(storage, &id)?;
+    KEY_OPERATIONS.save(storage, U64Key::from(id), &OperationState {
+        id,
+        start_height: block_height,
+        is_open: false,
+        is_raw: false,
+        deposit_state: None,
+        withdraw_state: None,
+        protect_state: None,
+    })?;
+    Ok(id)
 }
 
-pub fn save_deposit_state(storage: &mut dyn Storage, data: &DepositState) -> StdResult<()> {
-    KEY_DEPOSIT_STATE.save(storage, data)
+pub fn load_operation(storage: &dyn Storage, operation_id: u64) -> StdResult<OperationState> {
+    KEY_OPERATIONS.may_load(storage, U64Key::from(operation_id))?
+        .ok_or_else(|| StdError::generic_err(format!("There isn't operation: id: {}.", operation_id)))
 }
 
-pub fn increase_iteration_index(storage: &mut dyn Storage) -> StdResult<DepositState> {
-    KEY_DEPOSIT_STATE.update(storage, |mut s: DepositState| -> StdResult<DepositState> {
-        s.cur_iteration_index += 1;
-        Ok(s)
-    })
+pub fn remove_operation(storage: &mut dyn Storage, operation_id: u64) {
+    KEY_OPERATIONS.remove(storage, U64Key::from(operation_id))
+}
+
+fn update_operation<A: FnOnce(OperationState) -> OperationState>(storage: &mut dyn Storage, operation_id: u64, action: A) -> StdResult<OperationState> {
+    let operation = action(load_operation(storage, operation_id)?);
+    KEY_OPERATIONS.save(storage, U64Key::from(operation_id), &operation)?;
+    Ok(operation)
+}
+
+pub fn load_deposit_state(storage: &dyn Storage, operation_id: u64) -> StdResult<DepositState> {
+    load_operation(storage, operation_id)?.deposit_state
+        .ok_or_else(|| StdError::generic_err(format!("Operation {} isn't deposit", operation_id)))
+}
+
+pub fn save_deposit_state(storage: &mut dyn Storage, operation_id: u64, data: &DepositState) -> StdResult<()> {
+    update_operation(storage, operation_id, |mut o| {
+        o.deposit_state = Some(data.clone());
+        o
+    })?;
+    Ok(())
 }
 
-pub fn load_withdraw_state(storage: &dyn Storage) -> StdResult<WithdrawState> {
-    KEY_WITHDRAW_STATE.load(storage)
+pub fn increase_iteration_index(storage: &mut dyn Storage, operation_id: u64) -> StdResult<DepositState> {
+    let mut state = load_deposit_state(storage, operation_id)?;
+    state.cur_iteration_index += 1;
+    save_deposit_state(storage, operation_id, &state)?;
+    Ok(state)
 }
 
-pub fn save_withdraw_state(storage: &mut dyn Storage, data: &WithdrawState) -> StdResult<()> {
-    KEY_WITHDRAW_STATE.save(storage, data)
+pub fn load_withdraw_state(storage: &dyn Storage, operation_id: u64) -> StdResult<WithdrawState> {
+    load_operation(storage, operation_id)?.withdraw_state
+        .ok_or_else(|| StdError::generic_err(format!("Operation {} isn't withdraw", operation_id)))
+}
+
+pub fn save_withdraw_state(storage: &mut dyn Storage, operation_id: u64, data: &WithdrawState) -> StdResult<()> {
+    update_operation(storage, operation_id, |mut o| {
+        o.withdraw_state = Some(data.clone());
+        o
+    })?;
+    Ok(())
 }
 
 pub fn may_load_position(storage: &dyn Storage, farmer_addr: &Addr, masset_token: &Addr) -> StdResult<Option<Position>> {
@@ -171,25 +388,90 @@ pub fn load_position(storage: &dyn Storage, farmer_addr: &Addr, masset_token: &A
     KEY_POSITIONS.load(storage, (farmer_addr, masset_token))
 }
 
-pub fn increase_position_collateral(storage: &mut dyn Storage, farmer_addr: &Addr, masset_token: &Addr, diff: Uint128) -> StdResult<Position> {
-    KEY_POSITIONS.update(storage, (farmer_addr, masset_token), |p: Option<Position>| -> StdResult<Position> {
-        if let Some(mut p) = p {
-            p.collateral += diff;
-            Ok(p)
-        } else {
-            Err(StdError::generic_err(format!(
-                "There isn't position: farmer_addr: {}, masset_token: {}.",
-                &farmer_addr.to_string(),
-                &masset_token.to_string())))
-        }
-    })
+// Every change of position amounts takes Mirror position state after the change,
+// so shares are minted or burnt against amounts which were in CDP before it.
+pub fn increase_position_collateral(storage: &mut dyn Storage, farmer_addr: &Addr, masset_token: &Addr, amount: Uint128, cdp_state: &CDPState) -> StdResult<(Position, PositionState)> {
+    let mut cdp = load_cdp(storage, masset_token)?;
+    let collateral_before = cdp_state.collateral_amount.checked_sub(amount)?;
+    let shares = amount_to_shares(amount, cdp.collateral_shares, collateral_before);
+    cdp.collateral_shares += shares;
+    sync_cdp_amounts(storage, &mut cdp, cdp_state)?;
+    save_cdp(storage, &cdp)?;
+
+    let position = update_position(storage, farmer_addr, masset_token, |mut p| {
+        p.collateral_shares += shares;
+        p
+    })?;
+    let position_state = calculate_position_state(&position, &cdp, cdp_state);
+    Ok((position, position_state))
+}
+
+pub fn decrease_position_collateral(storage: &mut dyn Storage, farmer_addr: &Addr, masset_token: &Addr, amount: Uint128, cdp_state: &CDPState) -> StdResult<(Position, PositionState)> {
+    let mut cdp = load_cdp(storage, masset_token)?;
+    let position = load_position(storage, farmer_addr, masset_token)?;
+    let collateral_before = cdp_state.collateral_amount + amount;
+    let position_collateral_before = shares_to_amount(position.collateral_shares, cdp.collateral_shares, collateral_before);
+    // withdrawing whole collateral burns all farmer's shares including rounding dust
+    let shares = if amount >= position_collateral_before {
+        position.collateral_shares
+    } else {
+        amount_to_shares_ceil(amount, cdp.collateral_shares, collateral_before).min(position.collateral_shares)
+    };
+    cdp.collateral_shares -= shares;
+    sync_cdp_amounts(storage, &mut cdp, cdp_state)?;
+    save_cdp(storage, &cdp)?;
+
+    let position = update_position(storage, farmer_addr, masset_token, |mut p| {
+        p.collateral_shares -= shares;
+        p
+    })?;
+    let position_state = calculate_position_state(&position, &cdp, cdp_state);
+    Ok((position, position_state))
+}
+
+pub fn increase_position_loan(storage: &mut dyn Storage, farmer_addr: &Addr, masset_token: &Addr, amount: Uint128, cdp_state: &CDPState) -> StdResult<(Position, PositionState)> {
+    let mut cdp = load_cdp(storage, masset_token)?;
+    let loan_before = cdp_state.loan_amount.checked_sub(amount)?;
+    let shares = amount_to_shares_ceil(amount, cdp.loan_shares, loan_before);
+    cdp.loan_shares += shares;
+    sync_cdp_amounts(storage, &mut cdp, cdp_state)?;
+    save_cdp(storage, &cdp)?;
+
+    let position = update_position(storage, farmer_addr, masset_token, |mut p| {
+        p.loan_shares += shares;
+        p
+    })?;
+    let position_state = calculate_position_state(&position, &cdp, cdp_state);
+    Ok((position, position_state))
+}
+
+pub fn decrease_position_loan(storage: &mut dyn Storage, farmer_addr: &Addr, masset_token: &Addr, amount: Uint128, cdp_state: &CDPState) -> StdResult<(Position, PositionState)> {
+    let mut cdp = load_cdp(storage, masset_token)?;
+    let position = load_position(storage, farmer_addr, masset_token)?;
+    let loan_before = cdp_state.loan_amount + amount;
+    let position_loan_before = shares_to_amount(position.loan_shares, cdp.loan_shares, loan_before);
+    // repaying whole loan burns all farmer's shares including rounding dust
+    let shares = if amount >= position_loan_before {
+        position.loan_shares
+    } else {
+        amount_to_shares(amount, cdp.loan_shares, loan_before).min(position.loan_shares)
+    };
+    cdp.loan_shares -= shares;
+    sync_cdp_amounts(storage, &mut cdp, cdp_state)?;
+    save_cdp(storage, &cdp)?;
+
+    let position = update_position(storage, farmer_addr, masset_token, |mut p| {
+        p.loan_shares -= shares;
+        p
+    })?;
+    let position_state = calculate_position_state(&position, &cdp, cdp_state);
+    Ok((position, position_state))
 }
 
-pub fn decrease_position_collateral(storage: &mut dyn Storage, farmer_addr: &Addr, masset_token: &Addr, diff: Uint128) -> StdResult<Position> {
+fn update_position<A: FnOnce(Position) -> Position>(storage: &mut dyn Storage, farmer_addr: &Addr, masset_token: &Addr, action: A) -> StdResult<Position> {
     KEY_POSITIONS.update(storage, (farmer_addr, masset_token), |p: Option<Position>| -> StdResult<Position> {
-        if let Some(mut p) = p {
-            p.collateral -= diff;
-            Ok(p)
+        if let Some(p) = p {
+            Ok(action(p))
         } else {
             Err(StdError::generic_err(format!(
                 "There isn't position: farmer_addr: {}, masset_token: {}.",
@@ -199,37 +481,46 @@ pub fn decrease_position_collateral(storage: &mut dyn Storage, farmer_addr: &Add
     })
 }
 
-pub fn increase_position_loan(storage: &mut dyn Storage, farmer_addr: &Addr, masset_token: &Addr, diff: Uint128) -> StdResult<Position> {
-    KEY_POSITIONS.update(storage, (farmer_addr, masset_token), |p: Option<Position>| -> StdResult<Position> {
-        if let Some(mut p) = p {
-            p.loan += diff;
-            Ok(p)
-        } else {
-            Err(StdError::generic_err(format!(
-                "There isn't position: farmer_addr: {}, masset_token: {}.",
-                &farmer_addr.to_string(),
-                &masset_token.to_string())))
-        }
-    })
+pub fn calculate_position_state(position: &Position, cdp: &CDP, cdp_state: &CDPState) -> PositionState {
+    PositionState {
+        collateral: shares_to_amount(position.collateral_shares, cdp.collateral_shares, cdp_state.collateral_amount),
+        loan: shares_to_amount(position.loan_shares, cdp.loan_shares, cdp_state.loan_amount),
+    }
 }
 
-pub fn decrease_position_loan(storage: &mut dyn Storage, farmer_addr: &Addr, masset_token: &Addr, diff: Uint128) -> StdResult<Position> {
-    KEY_POSITIONS.update(storage, (farmer_addr, masset_token), |p: Option<Position>| -> StdResult<Position> {
-        if let Some(mut p) = p {
-            p.loan -= diff;
-            Ok(p)
-        } else {
-            Err(StdError::generic_err(format!(
-                "There isn't position: farmer_addr: {}, masset_token: {}.",
-                &farmer_addr.to_string(),
-                &masset_token.to_string())))
-        }
-    })
+pub fn shares_to_amount(shares: Uint128, total_shares: Uint128, total_amount: Uint128) -> Uint128 {
+    if total_shares.is_zero() {
+        return Uint128::zero();
+    };
+    total_amount.multiply_ratio(shares, total_shares)
 }
 
-pub fn load_all_positions(storage: &dyn Storage) -> StdResult<Vec<Position>> {
+// First deposit (or deposit to emptied CDP) mints shares 1:1
+pub fn amount_to_shares(amount: Uint128, total_shares: Uint128, total_amount: Uint128) -> Uint128 {
+    if total_shares.is_zero() || total_amount.is_zero() {
+        return amount;
+    };
+    amount.multiply_ratio(total_shares, total_amount)
+}
+
+pub fn amount_to_shares_ceil(amount: Uint128, total_shares: Uint128, total_amount: Uint128) -> Uint128 {
+    if total_shares.is_zero() || total_amount.is_zero() {
+        return amount;
+    };
+    let shares = amount.multiply_ratio(total_shares, total_amount);
+    let remainder = amount.full_mul(total_shares).checked_rem(Uint256::from(total_amount));
+    match remainder {
+        Ok(r) if !r.is_zero() => shares + Uint128::new(1),
+        _ => shares,
+    }
+}
+
+pub fn load_all_positions(storage: &dyn Storage, start_after: Option<(&Addr, &Addr)>, limit: Option<u32>) -> StdResult<Vec<Position>> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let start = start_after.map(|key| Bound::exclusive(key.joined_key()));
     KEY_POSITIONS
-        .range(storage, None, None, Order::Ascending)
+        .range(storage, start, None, Order::Ascending)
+        .take(limit)
         .map(|position| {
             let (_, v) = position?;
             Ok(Position {
@@ -237,18 +528,22 @@ pub fn load_all_positions(storage: &dyn Storage) -> StdResult<Vec<Position>> {
                 masset_token: v.masset_token,
                 cdp_idx: v.cdp_idx,
                 leverage: v.leverage,
-                loan: v.loan,
-                collateral: v.collateral,
+                loan_shares: v.loan_shares,
+                collateral_shares: v.collateral_shares,
                 aim_collateral_ratio: v.aim_collateral_ratio,
+                target_leverage: v.target_leverage,
             })
         })
         .collect()
 }
 
-pub fn load_positions_by_farmer_addr(storage: &dyn Storage, farmer_addr: &Addr) -> StdResult<Vec<Position>> {
+pub fn load_positions_by_farmer_addr(storage: &dyn Storage, farmer_addr: &Addr, start_after: Option<&Addr>, limit: Option<u32>) -> StdResult<Vec<Position>> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let start = start_after.map(|masset_token| Bound::exclusive(masset_token.as_bytes()));
     KEY_POSITIONS
         .prefix(farmer_addr)
-        .range(storage, None, None, Order::Ascending)
+        .range(storage, start, None, Order::Ascending)
+        .take(limit)
         .map(|position| {
             let (_, v) = position?;
             Ok(Position {
@@ -256,9 +551,10 @@ pub fn load_positions_by_farmer_addr(storage: &dyn Storage, farmer_addr: &Addr)
                 masset_token: v.masset_token,
                 cdp_idx: v.cdp_idx,
                 leverage: v.leverage,
-                loan: v.loan,
-                collateral: v.collateral,
+                loan_shares: v.loan_shares,
+                collateral_shares: v.collateral_shares,
                 aim_collateral_ratio: v.aim_collateral_ratio,
+                target_leverage: v.target_leverage,
             })
         })
         .collect()
@@ -272,25 +568,38 @@ pub fn remove_position(storage: &mut dyn Storage, farmer_addr: &Addr, masset_tok
     KEY_POSITIONS.remove(storage, (farmer_addr, masset_token))
 }
 
-pub fn save_is_open(storage: &mut dyn Storage, is_open: bool) -> StdResult<()> {
-    KEY_IS_OPEN.save(storage, &is_open)
+pub fn save_protect_state(storage: &mut dyn Storage, operation_id: u64, data: &ProtectState) -> StdResult<()> {
+    update_operation(storage, operation_id, |mut o| {
+        o.protect_state = Some(data.clone());
+        o
+    })?;
+    Ok(())
 }
 
-pub fn load_is_open(storage: &dyn Storage) -> StdResult<bool> {
-    KEY_IS_OPEN.load(storage)
+pub fn may_load_protect_state(storage: &dyn Storage, operation_id: u64) -> StdResult<Option<ProtectState>> {
+    Ok(load_operation(storage, operation_id)?.protect_state)
 }
 
-pub fn update_is_open(storage: &mut dyn Storage, data: bool) -> StdResult<bool> {
-    KEY_IS_OPEN.update(storage, |mut is_open: bool| -> StdResult<_> {
-        is_open = data;
-        Ok(is_open)
-    })
+pub fn save_is_open(storage: &mut dyn Storage, operation_id: u64, is_open: bool) -> StdResult<()> {
+    update_operation(storage, operation_id, |mut o| {
+        o.is_open = is_open;
+        o
+    })?;
+    Ok(())
+}
+
+pub fn load_is_open(storage: &dyn Storage, operation_id: u64) -> StdResult<bool> {
+    Ok(load_operation(storage, operation_id)?.is_open)
 }
 
-pub fn save_is_raw(storage: &mut dyn Storage, is_open: bool) -> StdResult<()> {
-    KEY_IS_RAW.save(storage, &is_open)
+pub fn save_is_raw(storage: &mut dyn Storage, operation_id: u64, is_raw: bool) -> StdResult<()> {
+    update_operation(storage, operation_id, |mut o| {
+        o.is_raw = is_raw;
+        o
+    })?;
+    Ok(())
 }
 
-pub fn load_is_raw(storage: &dyn Storage) -> StdResult<bool> {
-    KEY_IS_RAW.load(storage)
+pub fn load_is_raw(storage: &dyn Storage, operation_id: u64) -> StdResult<bool> {
+    Ok(load_operation(storage, operation_id)?.is_raw)
 }
@@ -1,14 +1,19 @@
-use cosmwasm_std::{Addr, Coin, CosmosMsg, Deps, Env, Response, StdResult, SubMsg, to_binary, Uint128, WasmMsg};
+use cosmwasm_std::{Addr, Coin, CosmosMsg, Decimal, Deps, Env, QueryRequest, Response, SubMsg, to_binary, Uint128, WasmMsg, WasmQuery};
 use cw20::Cw20ExecuteMsg;
 use terraswap::asset::{Asset, AssetInfo, PairInfo};
 use terraswap::pair::Cw20HookMsg::Swap as Cw20HookSwap;
 use terraswap::pair::ExecuteMsg::Swap;
-use terraswap::querier::query_pair_info;
+use terraswap::pair::{PoolResponse, QueryMsg as PairQueryMsg, SimulationResponse};
+use terraswap::querier::{query_pair_info, simulate};
 
-use crate::state::{Config, DepositState, load_config, WithdrawState};
+use structured_note_package::mirror::MirrorMintConfigResponse;
+
+use crate::error::ContractError;
+use crate::state::{AssetConfig, Config, DepositState, load_config, WithdrawState};
 use crate::SubmsgIds;
+use crate::utils::{decimal_division, reverse_decimal};
 
-pub fn query_pair_addr(deps: Deps, terraswap_factory_addr: &Addr, masset_token: &Addr) -> StdResult<String> {
+pub fn query_pair_addr(deps: Deps, terraswap_factory_addr: &Addr, masset_token: &Addr) -> Result<String, ContractError> {
     let config = load_config(deps.storage)?;
     let pair_info: PairInfo = query_pair_info(
         &deps.querier,
@@ -25,7 +30,100 @@ pub fn query_pair_addr(deps: Deps, terraswap_factory_addr: &Addr, masset_token:
     Ok(pair_info.contract_addr)
 }
 
-pub fn sell_masset(env: Env, state: &DepositState, minted_amount: Uint128) -> StdResult<Response> {
+// Preferred pair of asset config or Terraswap factory pair of masset
+pub fn resolve_pair_addr(deps: Deps, mirror_mint_config: &MirrorMintConfigResponse, asset_config: &AssetConfig) -> Result<Addr, ContractError> {
+    match &asset_config.pair_addr {
+        Some(pair_addr) => Ok(pair_addr.clone()),
+        None => {
+            let terraswap_factory = deps.api.addr_validate(&mirror_mint_config.terraswap_factory)?;
+            Ok(deps.api.addr_validate(&query_pair_addr(deps, &terraswap_factory, &asset_config.masset_token)?)?)
+        }
+    }
+}
+
+// Returns (masset_reserve, stable_reserve) of the pair pool
+pub fn query_pool_reserves(deps: Deps, pair_addr: &Addr, masset_token: &Addr) -> Result<(Uint128, Uint128), ContractError> {
+    let pool: PoolResponse = deps.querier.query(&QueryRequest::Wasm(WasmQuery::Smart {
+        contract_addr: pair_addr.to_string(),
+        msg: to_binary(&PairQueryMsg::Pool {})?,
+    }))?;
+
+    let masset_info = AssetInfo::Token { contract_addr: masset_token.to_string() };
+    let (masset_asset, stable_asset) = if pool.assets[0].info.equal(&masset_info) {
+        (&pool.assets[0], &pool.assets[1])
+    } else {
+        (&pool.assets[1], &pool.assets[0])
+    };
+    if !masset_asset.info.equal(&masset_info) {
+        return Err(ContractError::PairAssetNotFound {
+            pair_addr: pair_addr.to_string(),
+            masset_token: masset_token.to_string(),
+        });
+    };
+    Ok((masset_asset.amount, stable_asset.amount))
+}
+
+// Loop sells and buys masset in the pool while valuing loan at oracle price,
+// so both prices have to agree within max_price_deviation
+pub fn check_price_deviation(deps: Deps, config: &Config, pair_addr: &Addr, masset_token: &Addr, oracle_price: Decimal) -> Result<(), ContractError> {
+    let (masset_reserve, stable_reserve) = query_pool_reserves(deps, pair_addr, masset_token)?;
+    let pool_price = if masset_reserve.is_zero() {
+        Decimal::zero()
+    } else {
+        Decimal::from_ratio(stable_reserve, masset_reserve)
+    };
+    let price_diff = if pool_price > oracle_price { pool_price - oracle_price } else { oracle_price - pool_price };
+    let deviation = decimal_division(price_diff, oracle_price)?;
+    if deviation > config.max_price_deviation {
+        return Err(ContractError::PriceDeviationExceeded {
+            pool_price,
+            oracle_price,
+            deviation,
+            max_price_deviation: config.max_price_deviation,
+        });
+    };
+    Ok(())
+}
+
+pub fn simulate_sell_masset(deps: Deps, pair_addr: &Addr, masset_token: &Addr, amount: Uint128) -> Result<SimulationResponse, ContractError> {
+    Ok(simulate(&deps.querier, pair_addr.clone(), &Asset {
+        info: AssetInfo::Token { contract_addr: masset_token.to_string() },
+        amount,
+    })?)
+}
+
+pub fn simulate_buy_masset(deps: Deps, pair_addr: &Addr, stable_denom: &str, offer_amount: Uint128) -> Result<SimulationResponse, ContractError> {
+    Ok(simulate(&deps.querier, pair_addr.clone(), &Asset {
+        info: AssetInfo::NativeToken { denom: stable_denom.to_string() },
+        amount: offer_amount,
+    })?)
+}
+
+// Same max_spread check as the pair does against belief_price, done on simulation
+// to reject the swap with a descriptive error before it is sent
+fn check_swap_spread(simulation: &SimulationResponse, expected_return: Uint128, max_spread: Decimal) -> Result<(), ContractError> {
+    let return_amount = simulation.return_amount + simulation.commission_amount;
+    if return_amount >= expected_return {
+        return Ok(());
+    };
+    let spread = Decimal::from_ratio(expected_return - return_amount, expected_return);
+    if spread > max_spread {
+        return Err(ContractError::SlippageExceeded { spread, max_spread });
+    };
+    Ok(())
+}
+
+pub fn check_sell_spread(deps: Deps, state: &DepositState, amount: Uint128) -> Result<(), ContractError> {
+    let simulation = simulate_sell_masset(deps, &state.pair_addr, &state.masset_token, amount)?;
+    check_swap_spread(&simulation, amount * state.masset_price, state.max_spread)
+}
+
+pub fn check_buy_spread(deps: Deps, stable_denom: &str, state: &WithdrawState, offer_amount: Uint128) -> Result<(), ContractError> {
+    let simulation = simulate_buy_masset(deps, &state.pair_addr, stable_denom, offer_amount)?;
+    check_swap_spread(&simulation, offer_amount * reverse_decimal(state.masset_price), state.max_spread)
+}
+
+pub fn sell_masset(env: Env, operation_id: u64, state: &DepositState, minted_amount: Uint128) -> Result<Response, ContractError> {
     Ok(Response::new()
         .add_submessage(SubMsg::reply_on_success(
             CosmosMsg::Wasm(WasmMsg::Execute {
@@ -34,43 +132,51 @@ pub fn sell_masset(env: Env, state: &DepositState, minted_amount: Uint128) -> St
                     contract: state.pair_addr.to_string(),
                     amount: minted_amount,
                     msg: to_binary(&Cw20HookSwap {
-                        belief_price: None,
-                        max_spread: None,
+                        // belief_price is offer asset amount per one ask asset
+                        belief_price: Some(reverse_decimal(state.masset_price)),
+                        max_spread: Some(state.max_spread),
                         to: Some(env.contract.address.to_string()),
                     })?,
                 })?,
                 funds: vec![],
             }),
-            SubmsgIds::SellMAsset.id(),
+            SubmsgIds::SellMAsset.reply_id(operation_id),
         ))
         .add_attributes(vec![
             ("action", "sell_masset"),
-            ("masset_token", &state.masset_token.to_string()),
+            ("masset_token", state.masset_token.as_str()),
             ("amount_to_sell", &minted_amount.to_string()),
         ]))
 }
 
-pub fn buy_masset(config: Config, state: WithdrawState, contract_addr: String, offer_amount: Uint128) -> StdResult<Response> {
+pub fn swap_stable_to_masset_msg(stable_denom: &str, state: &WithdrawState, offer_amount: Uint128, to: String) -> Result<CosmosMsg, ContractError> {
     let offer_asset = Coin {
-        denom: config.stable_denom.clone(),
+        denom: stable_denom.to_string(),
         amount: offer_amount,
     };
-    Ok(Response::new()
-        .add_submessage(SubMsg::reply_on_success(CosmosMsg::Wasm(WasmMsg::Execute {
-            contract_addr: state.pair_addr.to_string(),
-            msg: to_binary(&Swap {
-                offer_asset: Asset {
-                    info: AssetInfo::NativeToken {
-                        denom: config.stable_denom,
-                    },
-                    amount: offer_amount,
+    Ok(CosmosMsg::Wasm(WasmMsg::Execute {
+        contract_addr: state.pair_addr.to_string(),
+        msg: to_binary(&Swap {
+            offer_asset: Asset {
+                info: AssetInfo::NativeToken {
+                    denom: stable_denom.to_string(),
                 },
-                belief_price: None,
-                max_spread: None,
-                to: Some(contract_addr),
-            })?,
-            funds: vec![offer_asset],
-        }), SubmsgIds::BuyMAsset.id()))
+                amount: offer_amount,
+            },
+            belief_price: Some(state.masset_price),
+            max_spread: Some(state.max_spread),
+            to: Some(to),
+        })?,
+        funds: vec![offer_asset],
+    }))
+}
+
+pub fn buy_masset(config: Config, operation_id: u64, state: WithdrawState, contract_addr: String, offer_amount: Uint128) -> Result<Response, ContractError> {
+    Ok(Response::new()
+        .add_submessage(SubMsg::reply_on_success(
+            swap_stable_to_masset_msg(&config.stable_denom, &state, offer_amount, contract_addr)?,
+            SubmsgIds::BuyMAsset.reply_id(operation_id),
+        ))
         .add_attributes(vec![
             ("action", "buy_masset"),
             ("offered_amount", &offer_amount.to_string()),
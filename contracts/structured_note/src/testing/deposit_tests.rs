@@ -0,0 +1,300 @@
+use cosmwasm_bignumber::Uint256;
+use cosmwasm_std::testing::{mock_env, mock_info};
+use cosmwasm_std::{Addr, Attribute, BankMsg, Coin, CosmosMsg, Decimal, from_binary, Response, SubMsg, to_binary, Uint128, WasmMsg};
+use cw20::{Cw20ExecuteMsg, Cw20ReceiveMsg};
+use terraswap::asset::{Asset, AssetInfo};
+
+use structured_note_package::anchor::AnchorMarketMsg;
+use structured_note_package::mirror::{MirrorMintCW20HookMsg, MirrorMintExecuteMsg};
+use structured_note_package::structured_note::{Cw20HookMsg, ExecuteMsg, FeePreviewResponse, QueryMsg, UpdateConfigMsg};
+
+use crate::commands::deposit_stable_with_fee;
+use crate::contract::{execute, query, reply};
+use crate::error::ContractError;
+use crate::state::{load_cdp, load_config, load_deposit_state, load_position, save_deposit_state, save_position};
+use crate::SubmsgIds;
+use crate::testing::{CDP_IDX, MockDeps, open_position, reply_msg, setup, wasm_event};
+use crate::testing::mock_querier::{ANCHOR_MARKET, ATERRA, GOVERNANCE, MASSET, MIRROR_MINT, NEXUS_TREASURY, PAIR, STABLE_DENOM};
+
+const FARMER: &str = "farmer";
+
+fn set_caps(deps: &mut MockDeps, deposit_cap: Option<u128>, loan_cap: Option<u128>) {
+    execute(deps.as_mut(), mock_env(), mock_info(GOVERNANCE, &[]), ExecuteMsg::RegisterAsset {
+        masset_token: MASSET.to_string(),
+        min_over_collateralization: Decimal::percent(120),
+        max_leverage: 3,
+        deposit_cap: deposit_cap.map(Uint128::new),
+        loan_cap: loan_cap.map(Uint128::new),
+        pair_addr: Some(PAIR.to_string()),
+    }).unwrap();
+}
+
+fn deposit_msg() -> ExecuteMsg {
+    ExecuteMsg::Deposit {
+        masset_token: MASSET.to_string(),
+        leverage: Some(1),
+        target_leverage: None,
+        aim_collateral_ratio: Decimal::percent(200),
+        max_spread: None,
+    }
+}
+
+fn rebalance_msg() -> ExecuteMsg {
+    ExecuteMsg::Rebalance {
+        farmer_addr: FARMER.to_string(),
+        masset_token: MASSET.to_string(),
+    }
+}
+
+fn refund_attr(amount: u128) -> Attribute {
+    Attribute::new("refund_amount", amount.to_string())
+}
+
+#[test]
+fn deposit_keeps_target_leverage_of_position() {
+    let mut deps = setup();
+    open_position(&mut deps, FARMER, 1_000_000, 50_000);
+    let mut position = load_position(&deps.storage, &Addr::unchecked(FARMER), &Addr::unchecked(MASSET)).unwrap();
+    position.target_leverage = Some(Decimal::percent(200));
+    save_position(&mut deps.storage, &position).unwrap();
+
+    execute(deps.as_mut(), mock_env(), mock_info(FARMER, &[Coin::new(100_000, STABLE_DENOM)]), ExecuteMsg::Deposit {
+        masset_token: MASSET.to_string(),
+        leverage: None,
+        target_leverage: None,
+        aim_collateral_ratio: Decimal::percent(180),
+        max_spread: None,
+    }).unwrap();
+    // net value 1_100_000 - 50_000 * 10 at 2x exposure, reached by single mint
+    let state = load_deposit_state(&deps.storage, 1).unwrap();
+    assert_eq!(state.target_loan, Some(Uint128::new(60_000)));
+    assert_eq!(state.leverage, 1);
+    assert_eq!(state.target_leverage, Some(Decimal::percent(200)));
+}
+
+// Last DepositToCDP of 2x target leverage deposit: 100_000 stable and 10_000 masset sold at oracle price
+// bring position to 1_200_000 collateral and 60_000 loan
+fn finish_target_leverage_deposit(deps: &mut MockDeps, deposit_amount: u128) -> Result<Response, ContractError> {
+    open_position(deps, FARMER, 1_000_000, 50_000);
+    let mut position = load_position(&deps.storage, &Addr::unchecked(FARMER), &Addr::unchecked(MASSET)).unwrap();
+    position.target_leverage = Some(Decimal::percent(200));
+    save_position(&mut deps.storage, &position).unwrap();
+    execute(deps.as_mut(), mock_env(), mock_info(FARMER, &[Coin::new(100_000, STABLE_DENOM)]), ExecuteMsg::Deposit {
+        masset_token: MASSET.to_string(),
+        leverage: None,
+        target_leverage: None,
+        aim_collateral_ratio: Decimal::percent(180),
+        max_spread: None,
+    }).unwrap();
+    let mut state = load_deposit_state(&deps.storage, 1).unwrap();
+    state.cur_iteration_index = 1;
+    save_deposit_state(&mut deps.storage, 1, &state).unwrap();
+
+    deps.querier.set_cdp(CDP_IDX, 1_000_000 + deposit_amount, 60_000);
+    let events = vec![wasm_event(MIRROR_MINT, &[("deposit_amount", &format!("{}{}", deposit_amount, ATERRA))])];
+    reply(deps.as_mut(), mock_env(), reply_msg(SubmsgIds::DepositToCDP.reply_id(1), events))
+}
+
+#[test]
+fn deposit_reaches_target_leverage() {
+    let mut deps = setup();
+    finish_target_leverage_deposit(&mut deps, 200_000).unwrap();
+}
+
+#[test]
+fn deposit_fails_when_slippage_misses_target_leverage() {
+    let mut deps = setup();
+    // sell of minted masset returned 50_000 less than oracle price
+    let err = finish_target_leverage_deposit(&mut deps, 150_000).unwrap_err();
+    assert_eq!(err, ContractError::TargetLeverageMissed {
+        target_leverage: Decimal::percent(200),
+        leverage: Decimal::from_ratio(1_150_000u128, 550_000u128),
+    });
+}
+
+#[test]
+fn partially_filled_deposit_refunds_stable() {
+    let mut deps = setup();
+    set_caps(&mut deps, Some(150_000), None);
+    // 1_000_000 deposit and 500_000 of one sell exceed cap 150_000 ten times, fill is reduced by 0.1% margin
+    let res = execute(deps.as_mut(), mock_env(), mock_info(FARMER, &[Coin::new(1_000_000, STABLE_DENOM)]), deposit_msg()).unwrap();
+    assert_eq!(res.messages.last().unwrap(), &SubMsg::new(CosmosMsg::Bank(BankMsg::Send {
+        to_address: FARMER.to_string(),
+        amount: vec![Coin::new(900_100, STABLE_DENOM)],
+    })));
+    assert!(res.attributes.contains(&refund_attr(900_100)));
+}
+
+#[test]
+fn partially_filled_aterra_deposit_refunds_aterra() {
+    let mut deps = setup();
+    set_caps(&mut deps, Some(150_000), None);
+    let msg = ExecuteMsg::Receive(Cw20ReceiveMsg {
+        sender: FARMER.to_string(),
+        amount: Uint128::new(1_000_000),
+        msg: to_binary(&Cw20HookMsg::Deposit {
+            masset_token: MASSET.to_string(),
+            leverage: Some(1),
+            target_leverage: None,
+            aim_collateral_ratio: Decimal::percent(200),
+            max_spread: None,
+        }).unwrap(),
+    });
+    let res = execute(deps.as_mut(), mock_env(), mock_info(ATERRA, &[]), msg).unwrap();
+    assert_eq!(res.messages.last().unwrap(), &SubMsg::new(CosmosMsg::Wasm(WasmMsg::Execute {
+        contract_addr: ATERRA.to_string(),
+        msg: to_binary(&Cw20ExecuteMsg::Transfer {
+            recipient: FARMER.to_string(),
+            amount: Uint128::new(900_100),
+        }).unwrap(),
+        funds: vec![],
+    })));
+    assert!(res.attributes.contains(&refund_attr(900_100)));
+}
+
+#[test]
+fn partially_filled_raw_deposit_refunds_stable() {
+    let mut deps = setup();
+    open_position(&mut deps, FARMER, 1_000_000, 20_000);
+    set_caps(&mut deps, Some(1_050_000), None);
+    let msg = ExecuteMsg::RawDeposit { masset_token: MASSET.to_string() };
+    let res = execute(deps.as_mut(), mock_env(), mock_info(FARMER, &[Coin::new(100_000, STABLE_DENOM)]), msg).unwrap();
+    assert_eq!(res.messages.last().unwrap(), &SubMsg::new(CosmosMsg::Bank(BankMsg::Send {
+        to_address: FARMER.to_string(),
+        amount: vec![Coin::new(50_050, STABLE_DENOM)],
+    })));
+    assert!(res.attributes.contains(&refund_attr(50_050)));
+}
+
+#[test]
+fn rebalance_up_mints_within_loan_cap() {
+    let mut deps = setup();
+    open_position(&mut deps, FARMER, 1_000_000, 20_000);
+    set_caps(&mut deps, None, Some(35_000));
+    // aim loan 50_000 needs 30_000 mint, cap leaves 15_000 reduced by 0.1% margin
+    let res = execute(deps.as_mut(), mock_env(), mock_info("keeper", &[]), rebalance_msg()).unwrap();
+    assert_eq!(res.messages, vec![SubMsg::reply_on_success(CosmosMsg::Wasm(WasmMsg::Execute {
+        contract_addr: MIRROR_MINT.to_string(),
+        msg: to_binary(&MirrorMintExecuteMsg::Mint {
+            position_idx: Uint128::new(CDP_IDX),
+            asset: Asset {
+                info: AssetInfo::Token { contract_addr: MASSET.to_string() },
+                amount: Uint128::new(14_985),
+            },
+            short_params: None,
+        }).unwrap(),
+        funds: vec![],
+    }), SubmsgIds::MintMAsset.reply_id(1))]);
+    assert_eq!(load_deposit_state(&deps.storage, 1).unwrap().target_loan, Some(Uint128::new(34_985)));
+}
+
+#[test]
+fn rebalance_up_rejected_on_full_loan_cap() {
+    let mut deps = setup();
+    open_position(&mut deps, FARMER, 1_000_000, 20_000);
+    set_caps(&mut deps, None, Some(20_000));
+    let err = execute(deps.as_mut(), mock_env(), mock_info("keeper", &[]), rebalance_msg()).unwrap_err();
+    assert_eq!(err, ContractError::DepositCapReached { masset_token: MASSET.to_string() });
+}
+
+fn set_protocol_fee(deps: &mut MockDeps, protocol_fee: Decimal) {
+    execute(deps.as_mut(), mock_env(), mock_info(GOVERNANCE, &[]), ExecuteMsg::UpdateConfig(Box::new(UpdateConfigMsg {
+        protocol_fee: Some(protocol_fee),
+        ..UpdateConfigMsg::default()
+    }))).unwrap();
+}
+
+#[test]
+fn protocol_fee_of_deposit_goes_to_treasury() {
+    let mut deps = setup();
+    set_protocol_fee(&mut deps, Decimal::percent(1));
+    let res = deposit_stable_with_fee(load_config(&deps.storage).unwrap(), 1, Uint256::from(1_000_000u128)).unwrap();
+    assert_eq!(res.messages, vec![
+        SubMsg::reply_on_success(CosmosMsg::Wasm(WasmMsg::Execute {
+            contract_addr: ANCHOR_MARKET.to_string(),
+            msg: to_binary(&AnchorMarketMsg::DepositStable {}).unwrap(),
+            funds: vec![Coin::new(990_000, STABLE_DENOM)],
+        }), SubmsgIds::DepositStable.reply_id(1)),
+        SubMsg::new(CosmosMsg::Bank(BankMsg::Send {
+            to_address: NEXUS_TREASURY.to_string(),
+            amount: vec![Coin::new(10_000, STABLE_DENOM)],
+        })),
+    ]);
+}
+
+#[test]
+fn zero_protocol_fee_is_not_sent() {
+    let deps = setup();
+    let res = deposit_stable_with_fee(load_config(&deps.storage).unwrap(), 1, Uint256::from(1_000_000u128)).unwrap();
+    assert_eq!(res.messages.len(), 1);
+
+    let err = deposit_stable_with_fee(load_config(&deps.storage).unwrap(), 1, Uint256::zero()).unwrap_err();
+    assert_eq!(err, ContractError::ZeroDepositAfterFee {});
+}
+
+#[test]
+fn fee_preview_of_deposit() {
+    let mut deps = setup();
+    set_protocol_fee(&mut deps, Decimal::percent(1));
+    let res: FeePreviewResponse = from_binary(&query(deps.as_ref(), mock_env(), QueryMsg::FeePreview { deposit_amount: Uint128::new(1_000_000) }).unwrap()).unwrap();
+    assert_eq!(res, FeePreviewResponse {
+        protocol_fee: Decimal::percent(1),
+        fee_amount: Uint128::new(10_000),
+        net_deposit_amount: Uint128::new(990_000),
+    });
+}
+
+fn receive_aterra(deps: &mut MockDeps, token: &str, amount: u128) -> Result<Response, ContractError> {
+    execute(deps.as_mut(), mock_env(), mock_info(token, &[]), ExecuteMsg::Receive(Cw20ReceiveMsg {
+        sender: "farmer".to_string(),
+        amount: Uint128::new(amount),
+        msg: to_binary(&Cw20HookMsg::Deposit {
+            masset_token: MASSET.to_string(),
+            leverage: Some(2),
+            target_leverage: None,
+            aim_collateral_ratio: Decimal::percent(200),
+            max_spread: None,
+        }).unwrap(),
+    }))
+}
+
+#[test]
+fn aterra_deposit_goes_to_cdp_directly() {
+    let mut deps = setup();
+    set_protocol_fee(&mut deps, Decimal::percent(1));
+    open_position(&mut deps, "other_farmer", 1_000_000, 50_000);
+
+    let res = receive_aterra(&mut deps, ATERRA, 100_000).unwrap();
+    assert_eq!(res.messages, vec![
+        SubMsg::reply_on_success(CosmosMsg::Wasm(WasmMsg::Execute {
+            contract_addr: ATERRA.to_string(),
+            msg: to_binary(&Cw20ExecuteMsg::Send {
+                contract: MIRROR_MINT.to_string(),
+                amount: Uint128::new(99_000),
+                msg: to_binary(&MirrorMintCW20HookMsg::Deposit { position_idx: Uint128::new(CDP_IDX) }).unwrap(),
+            }).unwrap(),
+            funds: vec![],
+        }), SubmsgIds::DepositToCDP.reply_id(1)),
+        SubMsg::new(CosmosMsg::Wasm(WasmMsg::Execute {
+            contract_addr: ATERRA.to_string(),
+            msg: to_binary(&Cw20ExecuteMsg::Transfer {
+                recipient: NEXUS_TREASURY.to_string(),
+                amount: Uint128::new(1_000),
+            }).unwrap(),
+            funds: vec![],
+        })),
+    ]);
+    let position = load_position(&deps.storage, &Addr::unchecked("farmer"), &Addr::unchecked(MASSET)).unwrap();
+    assert_eq!(position.leverage, 2);
+    assert!(load_cdp(&deps.storage, &Addr::unchecked(MASSET)).unwrap().farmers.contains(&Addr::unchecked("farmer")));
+}
+
+#[test]
+fn receive_of_other_token_is_rejected() {
+    let mut deps = setup();
+    let err = receive_aterra(&mut deps, MASSET, 100_000).unwrap_err();
+    assert_eq!(err, ContractError::UnsupportedToken { token_addr: MASSET.to_string() });
+
+    let err = receive_aterra(&mut deps, ATERRA, 0).unwrap_err();
+    assert_eq!(err, ContractError::ZeroDeposit {});
+}
@@ -0,0 +1,155 @@
+use cosmwasm_std::testing::{mock_env, mock_info};
+use cosmwasm_std::{Coin, Decimal, Uint128};
+
+use structured_note_package::structured_note::ExecuteMsg;
+
+use crate::contract::{execute, reply};
+use crate::error::ContractError;
+use crate::SubmsgIds;
+use crate::testing::{MockDeps, open_position, reply_msg, setup, wasm_event};
+use crate::testing::mock_querier::{ATERRA, MASSET, MIRROR_MINT, STABLE_DENOM};
+
+const FARMER: &str = "farmer";
+
+fn deposit(deps: &mut MockDeps, amount: u128, leverage: u8, aim_collateral_ratio: Decimal) -> Result<(), ContractError> {
+    execute(deps.as_mut(), mock_env(), mock_info(FARMER, &[Coin::new(amount, STABLE_DENOM)]), ExecuteMsg::Deposit {
+        masset_token: MASSET.to_string(),
+        leverage: Some(leverage),
+        target_leverage: None,
+        aim_collateral_ratio,
+        max_spread: None,
+    }).map(|_| ())
+}
+
+#[test]
+fn governance_messages_from_other_sender() {
+    let mut deps = setup();
+    let err = execute(deps.as_mut(), mock_env(), mock_info(FARMER, &[]), ExecuteMsg::Pause {}).unwrap_err();
+    assert_eq!(err, ContractError::Unauthorized {});
+
+    let err = execute(deps.as_mut(), mock_env(), mock_info(FARMER, &[]), ExecuteMsg::RegisterAsset {
+        masset_token: MASSET.to_string(),
+        min_over_collateralization: Decimal::percent(150),
+        max_leverage: 5,
+        deposit_cap: None,
+        loan_cap: None,
+        pair_addr: None,
+    }).unwrap_err();
+    assert_eq!(err, ContractError::Unauthorized {});
+}
+
+#[test]
+fn withdraw_without_position() {
+    let mut deps = setup();
+    let err = execute(deps.as_mut(), mock_env(), mock_info(FARMER, &[]), ExecuteMsg::Withdraw {
+        masset_token: MASSET.to_string(),
+        aim_collateral: Uint128::new(100),
+        aim_collateral_ratio: Decimal::percent(200),
+        max_spread: None,
+        receive_as: None,
+    }).unwrap_err();
+    assert_eq!(err, ContractError::PositionNotFound {
+        farmer_addr: FARMER.to_string(),
+        masset_token: MASSET.to_string(),
+    });
+}
+
+#[test]
+fn deposit_below_safe_collateral_ratio() {
+    let mut deps = setup();
+    // safe ratio is 150% * 120%
+    let err = deposit(&mut deps, 1_000_000, 2, Decimal::percent(170)).unwrap_err();
+    assert_eq!(err, ContractError::CollateralRatioTooLow {
+        collateral_ratio: Decimal::percent(170),
+        min_collateral_ratio: Decimal::percent(180),
+    });
+}
+
+#[test]
+fn deposit_above_max_leverage() {
+    let mut deps = setup();
+    let err = deposit(&mut deps, 1_000_000, 4, Decimal::percent(200)).unwrap_err();
+    assert_eq!(err, ContractError::LeverageOutOfRange { leverage: 4, min: 1, max: 3 });
+}
+
+#[test]
+fn deposit_to_delisted_masset() {
+    let mut deps = setup();
+    deps.querier.end_price = Some(Decimal::from_ratio(10u128, 1u128));
+    let err = deposit(&mut deps, 1_000_000, 2, Decimal::percent(200)).unwrap_err();
+    assert_eq!(err, ContractError::InvalidMasset { reason: "delisted or migrated".to_string() });
+}
+
+#[test]
+fn deposit_to_shallow_pool() {
+    let mut deps = setup();
+    deps.querier.pool = (Uint128::new(10_000), Uint128::new(100_000));
+    match deposit(&mut deps, 1_000_000, 3, Decimal::percent(200)).unwrap_err() {
+        ContractError::PriceImpactExceeded { price_impact, max_price_impact } => {
+            assert_eq!(max_price_impact, Decimal::percent(5));
+            assert!(price_impact > max_price_impact);
+        }
+        err => panic!("unexpected error: {}", err),
+    }
+}
+
+#[test]
+fn sell_of_minted_masset_over_max_spread() {
+    let mut deps = setup();
+    open_position(&mut deps, FARMER, 1_000_000, 50_000);
+    deposit(&mut deps, 100_000, 2, Decimal::percent(200)).unwrap();
+
+    deps.querier.swap_spread = Decimal::percent(2);
+    let events = vec![wasm_event(MIRROR_MINT, &[("mint_amount", &format!("1000{}", MASSET))])];
+    let err = reply(deps.as_mut(), mock_env(), reply_msg(SubmsgIds::MintMAsset.reply_id(1), events)).unwrap_err();
+    assert_eq!(err, ContractError::SlippageExceeded {
+        spread: Decimal::percent(2),
+        max_spread: Decimal::percent(1),
+    });
+}
+
+#[test]
+fn deposit_without_stable() {
+    let mut deps = setup();
+    let err = deposit(&mut deps, 0, 2, Decimal::percent(200)).unwrap_err();
+    assert_eq!(err, ContractError::ZeroDeposit {});
+}
+
+#[test]
+fn protection_of_missing_cdp() {
+    let mut deps = setup();
+    let err = execute(deps.as_mut(), mock_env(), mock_info(FARMER, &[]), ExecuteMsg::ProtectCdp { masset_token: MASSET.to_string() }).unwrap_err();
+    assert_eq!(err, ContractError::CdpNotFound { masset_token: MASSET.to_string() });
+}
+
+#[test]
+fn deposit_with_stale_oracle_price() {
+    let mut deps = setup();
+    deps.querier.price_last_updated = mock_env().block.time.seconds() - 61;
+    let err = deposit(&mut deps, 1_000_000, 2, Decimal::percent(200)).unwrap_err();
+    assert_eq!(err, ContractError::OraclePriceStale {
+        asset: ATERRA.to_string(),
+        last_updated: deps.querier.price_last_updated,
+        max_oracle_age: 60,
+    });
+}
+
+#[test]
+fn deposit_with_revoked_collateral() {
+    let mut deps = setup();
+    deps.querier.collateral_revoked = true;
+    let err = deposit(&mut deps, 1_000_000, 2, Decimal::percent(200)).unwrap_err();
+    assert_eq!(err, ContractError::CollateralRevoked { asset: ATERRA.to_string() });
+}
+
+#[test]
+fn collateral_multiplier_raises_safe_collateral_ratio() {
+    let mut deps = setup();
+    // safe ratio is 150% * 120% multiplier * 120%
+    deps.querier.collateral_multiplier = Decimal::percent(120);
+    let err = deposit(&mut deps, 1_000_000, 2, Decimal::percent(200)).unwrap_err();
+    assert_eq!(err, ContractError::CollateralRatioTooLow {
+        collateral_ratio: Decimal::percent(200),
+        min_collateral_ratio: Decimal::percent(216),
+    });
+}
@@ -0,0 +1,278 @@
+use cosmwasm_std::testing::{mock_env, mock_info};
+use cosmwasm_std::{Addr, Attribute, BankMsg, Coin, CosmosMsg, Decimal, from_binary, Uint128};
+
+use structured_note_package::structured_note::{AssetConfigResponse, AssetConfigsResponse, ExecuteMsg, QueryMsg, StatusResponse, UpdateConfigMsg};
+
+use crate::contract::{execute, query};
+use crate::error::ContractError;
+use crate::state::{load_config, load_is_open, may_load_asset_config, may_load_cdp, remove_farmer_from_cdp, remove_position};
+use crate::testing::{MockDeps, open_position, setup};
+use crate::testing::mock_querier::{GOVERNANCE, MASSET, PAIR, STABLE_DENOM};
+
+const FARMER: &str = "farmer";
+
+fn remove_asset_msg() -> ExecuteMsg {
+    ExecuteMsg::RemoveAsset { masset_token: MASSET.to_string() }
+}
+
+#[test]
+fn asset_with_positions_is_not_removed() {
+    let mut deps = setup();
+    open_position(&mut deps, FARMER, 1_000_000, 50_000);
+    let err = execute(deps.as_mut(), mock_env(), mock_info(GOVERNANCE, &[]), remove_asset_msg()).unwrap_err();
+    assert_eq!(err, ContractError::AssetInUse { masset_token: MASSET.to_string() });
+}
+
+#[test]
+fn asset_with_empty_cdp_is_removed() {
+    let mut deps = setup();
+    let masset_token = Addr::unchecked(MASSET);
+    open_position(&mut deps, FARMER, 1_000_000, 50_000);
+    // the last farmer closed position, CDP record without farmers is left
+    remove_position(&mut deps.storage, &Addr::unchecked(FARMER), &masset_token);
+    remove_farmer_from_cdp(&mut deps.storage, &Addr::unchecked(FARMER), &masset_token).unwrap();
+
+    execute(deps.as_mut(), mock_env(), mock_info(GOVERNANCE, &[]), remove_asset_msg()).unwrap();
+    assert_eq!(may_load_asset_config(&deps.storage, &masset_token).unwrap(), None);
+    assert_eq!(may_load_cdp(&deps.storage, &masset_token).unwrap(), None);
+
+    execute(deps.as_mut(), mock_env(), mock_info(GOVERNANCE, &[]), ExecuteMsg::RegisterAsset {
+        masset_token: MASSET.to_string(),
+        min_over_collateralization: Decimal::percent(120),
+        max_leverage: 3,
+        deposit_cap: None,
+        loan_cap: None,
+        pair_addr: Some(PAIR.to_string()),
+    }).unwrap();
+    execute(deps.as_mut(), mock_env(), mock_info(FARMER, &[Coin::new(100_000, STABLE_DENOM)]), ExecuteMsg::Deposit {
+        masset_token: MASSET.to_string(),
+        leverage: Some(1),
+        target_leverage: None,
+        aim_collateral_ratio: Decimal::percent(200),
+        max_spread: None,
+    }).unwrap();
+    // deposit to registered again masset opens a new CDP
+    assert!(load_is_open(&deps.storage, 1).unwrap());
+}
+
+#[test]
+fn asset_without_cdp_is_removed() {
+    let mut deps = setup();
+    execute(deps.as_mut(), mock_env(), mock_info(GOVERNANCE, &[]), remove_asset_msg()).unwrap();
+    assert_eq!(may_load_asset_config(&deps.storage, &Addr::unchecked(MASSET)).unwrap(), None);
+}
+
+#[test]
+fn deposit_cap_is_set_and_cleared() {
+    let mut deps = setup();
+    execute(deps.as_mut(), mock_env(), mock_info(GOVERNANCE, &[]), ExecuteMsg::UpdateConfig(Box::new(UpdateConfigMsg {
+        deposit_cap: Some(Uint128::new(1_000_000)),
+        ..UpdateConfigMsg::default()
+    }))).unwrap();
+    assert_eq!(load_config(&deps.storage).unwrap().deposit_cap, Some(Uint128::new(1_000_000)));
+
+    let err = execute(deps.as_mut(), mock_env(), mock_info(GOVERNANCE, &[]), ExecuteMsg::UpdateConfig(Box::new(UpdateConfigMsg {
+        deposit_cap: Some(Uint128::new(2_000_000)),
+        clear_deposit_cap: Some(true),
+        ..UpdateConfigMsg::default()
+    }))).unwrap_err();
+    assert_eq!(err, ContractError::invalid_config("deposit_cap", "can't be set and cleared at once"));
+
+    execute(deps.as_mut(), mock_env(), mock_info(GOVERNANCE, &[]), ExecuteMsg::UpdateConfig(Box::new(UpdateConfigMsg {
+        clear_deposit_cap: Some(true),
+        ..UpdateConfigMsg::default()
+    }))).unwrap();
+    assert_eq!(load_config(&deps.storage).unwrap().deposit_cap, None);
+}
+
+fn governance(deps: &mut MockDeps, msg: ExecuteMsg) -> Result<(), ContractError> {
+    execute(deps.as_mut(), mock_env(), mock_info(GOVERNANCE, &[]), msg).map(|_| ())
+}
+
+fn deposit(deps: &mut MockDeps) -> Result<(), ContractError> {
+    execute(deps.as_mut(), mock_env(), mock_info("farmer", &[Coin::new(1_000_000, STABLE_DENOM)]), ExecuteMsg::Deposit {
+        masset_token: MASSET.to_string(),
+        leverage: Some(2),
+        target_leverage: None,
+        aim_collateral_ratio: Decimal::percent(200),
+        max_spread: None,
+    }).map(|_| ())
+}
+
+fn register(deps: &mut MockDeps, max_leverage: u8, deposit_cap: Option<u128>) -> Result<(), ContractError> {
+    governance(deps, ExecuteMsg::RegisterAsset {
+        masset_token: MASSET.to_string(),
+        min_over_collateralization: Decimal::percent(130),
+        max_leverage,
+        deposit_cap: deposit_cap.map(Uint128::new),
+        loan_cap: None,
+        pair_addr: None,
+    })
+}
+
+fn status(deps: &MockDeps) -> StatusResponse {
+    from_binary(&query(deps.as_ref(), mock_env(), QueryMsg::Status {}).unwrap()).unwrap()
+}
+
+#[test]
+fn config_is_updated_by_governance() {
+    let mut deps = setup();
+    let res = execute(deps.as_mut(), mock_env(), mock_info(GOVERNANCE, &[]), ExecuteMsg::UpdateConfig(Box::new(UpdateConfigMsg {
+        nexus_treasury: Some("new_treasury".to_string()),
+        protocol_fee: Some(Decimal::percent(1)),
+        ..UpdateConfigMsg::default()
+    }))).unwrap();
+    // only changed fields are reported
+    assert_eq!(res.attributes, vec![
+        Attribute::new("action", "update_config"),
+        Attribute::new("old_nexus_treasury", "nexus_treasury"),
+        Attribute::new("new_nexus_treasury", "new_treasury"),
+        Attribute::new("old_protocol_fee", "0"),
+        Attribute::new("new_protocol_fee", "0.01"),
+    ]);
+    let config = load_config(&deps.storage).unwrap();
+    assert_eq!(config.nexus_treasury, Addr::unchecked("new_treasury"));
+    assert_eq!(config.protocol_fee, Decimal::percent(1));
+}
+
+#[test]
+fn config_is_not_updated_by_other_sender() {
+    let mut deps = setup();
+    let err = execute(deps.as_mut(), mock_env(), mock_info("farmer", &[]), ExecuteMsg::UpdateConfig(Box::new(UpdateConfigMsg {
+        governance_contract: Some("farmer".to_string()),
+        ..UpdateConfigMsg::default()
+    }))).unwrap_err();
+    assert_eq!(err, ContractError::Unauthorized {});
+}
+
+#[test]
+fn config_update_with_invalid_value() {
+    let mut deps = setup();
+    let err = execute(deps.as_mut(), mock_env(), mock_info(GOVERNANCE, &[]), ExecuteMsg::UpdateConfig(Box::new(UpdateConfigMsg {
+        protocol_fee: Some(Decimal::one()),
+        ..UpdateConfigMsg::default()
+    }))).unwrap_err();
+    assert_eq!(err, ContractError::invalid_config("protocol_fee", "should be less than 1"));
+}
+
+#[test]
+fn pause_rejects_deposits_until_unpause() {
+    let mut deps = setup();
+    governance(&mut deps, ExecuteMsg::Pause {}).unwrap();
+    assert!(status(&deps).is_paused);
+    assert_eq!(deposit(&mut deps).unwrap_err(), ContractError::Paused {});
+
+    governance(&mut deps, ExecuteMsg::Unpause {}).unwrap();
+    assert!(!status(&deps).is_paused);
+    deposit(&mut deps).unwrap();
+}
+
+#[test]
+fn frozen_masset_rejects_deposits_until_unfreeze() {
+    let mut deps = setup();
+    governance(&mut deps, ExecuteMsg::FreezeAsset { masset_token: MASSET.to_string() }).unwrap();
+    assert_eq!(status(&deps), StatusResponse {
+        is_paused: false,
+        frozen_assets: vec![MASSET.to_string()],
+    });
+    assert_eq!(deposit(&mut deps).unwrap_err(), ContractError::AssetFrozen { masset_token: MASSET.to_string() });
+
+    governance(&mut deps, ExecuteMsg::UnfreezeAsset { masset_token: MASSET.to_string() }).unwrap();
+    assert!(status(&deps).frozen_assets.is_empty());
+    deposit(&mut deps).unwrap();
+}
+
+#[test]
+fn pause_keeps_withdraws_available() {
+    let mut deps = setup();
+    governance(&mut deps, ExecuteMsg::Pause {}).unwrap();
+    // withdraw passes pause check and fails on missing position
+    let err = execute(deps.as_mut(), mock_env(), mock_info("farmer", &[]), ExecuteMsg::Withdraw {
+        masset_token: MASSET.to_string(),
+        aim_collateral: Uint128::new(100),
+        aim_collateral_ratio: Decimal::percent(200),
+        max_spread: None,
+        receive_as: None,
+    }).unwrap_err();
+    assert_eq!(err, ContractError::PositionNotFound {
+        farmer_addr: "farmer".to_string(),
+        masset_token: MASSET.to_string(),
+    });
+}
+
+#[test]
+fn pause_and_freeze_by_other_sender() {
+    let mut deps = setup();
+    for msg in [ExecuteMsg::Pause {}, ExecuteMsg::Unpause {}, ExecuteMsg::FreezeAsset { masset_token: MASSET.to_string() }] {
+        let err = execute(deps.as_mut(), mock_env(), mock_info("farmer", &[]), msg).unwrap_err();
+        assert_eq!(err, ContractError::Unauthorized {});
+    }
+    assert_eq!(status(&deps), StatusResponse { is_paused: false, frozen_assets: vec![] });
+}
+
+#[test]
+fn asset_registration_updates_asset_config() {
+    let mut deps = setup();
+    register(&mut deps, 2, Some(5_000_000)).unwrap();
+    let asset_config: AssetConfigResponse = from_binary(&query(deps.as_ref(), mock_env(), QueryMsg::AssetConfig { masset_token: MASSET.to_string() }).unwrap()).unwrap();
+    assert_eq!(asset_config, AssetConfigResponse {
+        masset_token: MASSET.to_string(),
+        min_over_collateralization: Decimal::percent(130),
+        max_leverage: 2,
+        deposit_cap: Some(Uint128::new(5_000_000)),
+        loan_cap: None,
+        pair_addr: None,
+    });
+    let all: AssetConfigsResponse = from_binary(&query(deps.as_ref(), mock_env(), QueryMsg::AllAssetConfigs { start_after: None, limit: None }).unwrap()).unwrap();
+    assert_eq!(all.asset_configs, vec![asset_config]);
+}
+
+#[test]
+fn asset_registration_with_invalid_values() {
+    let mut deps = setup();
+    assert_eq!(register(&mut deps, 0, None).unwrap_err(), ContractError::invalid_config("max_leverage", "should be from 1 to 5"));
+    let err = execute(deps.as_mut(), mock_env(), mock_info("farmer", &[]), ExecuteMsg::RegisterAsset {
+        masset_token: MASSET.to_string(),
+        min_over_collateralization: Decimal::percent(120),
+        max_leverage: 3,
+        deposit_cap: None,
+        loan_cap: None,
+        pair_addr: Some(PAIR.to_string()),
+    }).unwrap_err();
+    assert_eq!(err, ContractError::Unauthorized {});
+}
+
+#[test]
+fn deposit_to_removed_masset() {
+    let mut deps = setup();
+    governance(&mut deps, ExecuteMsg::RemoveAsset { masset_token: MASSET.to_string() }).unwrap();
+    assert_eq!(deposit(&mut deps).unwrap_err(), ContractError::AssetNotRegistered { masset_token: MASSET.to_string() });
+}
+
+#[test]
+fn deposit_above_deposit_cap_is_partially_filled() {
+    let mut deps = setup();
+    register(&mut deps, 2, Some(1_500_000)).unwrap();
+    // deposit of 1_000_000 at 200% with leverage 2 sells minted masset for 500_000 and 250_000 more collateral
+    let res = execute(deps.as_mut(), mock_env(), mock_info("farmer", &[Coin::new(1_000_000, STABLE_DENOM)]), ExecuteMsg::Deposit {
+        masset_token: MASSET.to_string(),
+        leverage: Some(2),
+        target_leverage: None,
+        aim_collateral_ratio: Decimal::percent(200),
+        max_spread: None,
+    }).unwrap();
+    let refund_amount: u128 = res.attributes.iter().find(|attr| attr.key == "refund_amount").unwrap().value.parse().unwrap();
+    // filled part fits into 1_500_000 of collateral
+    assert!(refund_amount >= 1_000_000 - 1_000_000 * 1_500_000 / 1_750_000);
+    assert!(res.messages.iter().any(|msg| msg.msg == CosmosMsg::Bank(BankMsg::Send {
+        to_address: "farmer".to_string(),
+        amount: vec![Coin::new(refund_amount, STABLE_DENOM)],
+    })));
+}
+
+#[test]
+fn deposit_to_masset_with_reached_deposit_cap() {
+    let mut deps = setup();
+    register(&mut deps, 2, Some(0)).unwrap();
+    assert_eq!(deposit(&mut deps).unwrap_err(), ContractError::DepositCapReached { masset_token: MASSET.to_string() });
+}
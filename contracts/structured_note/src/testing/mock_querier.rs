@@ -0,0 +1,261 @@
+use std::collections::HashMap;
+
+use cosmwasm_bignumber::{Decimal256, Uint256};
+
+use cosmwasm_std::testing::{MOCK_CONTRACT_ADDR, mock_env, MockApi, MockQuerier, MockStorage};
+use cosmwasm_std::{Addr, Binary, Coin, ContractResult, Decimal, Empty, from_binary, from_slice, OwnedDeps, Querier, QuerierResult, QueryRequest, SystemError, SystemResult, to_binary, Uint128, WasmQuery};
+use cosmwasm_storage::to_length_prefixed;
+use cw20::{BalanceResponse as Cw20BalanceResponse, Cw20QueryMsg};
+use terraswap::asset::{Asset, AssetInfo, AssetInfoRaw, AssetRaw, PairInfo};
+use terraswap::factory::QueryMsg as FactoryQueryMsg;
+use terraswap::pair::{PoolResponse, QueryMsg as PairQueryMsg, SimulationResponse};
+
+use structured_note_package::anchor::{AnchorEpochStateResponse, AnchorMarketQueryMsg};
+
+use structured_note_package::mirror::{MirrorAssetConfigResponse, MirrorCDPResponse, MirrorCollateralOracleQueryMsg, MirrorCollateralPriceResponse, MirrorMintConfigResponse, MirrorOracleQueryMsg, MirrorPriceResponse};
+use structured_note_package::structured_note::InstantiateMsg;
+
+use crate::concat;
+
+pub const STABLE_DENOM: &str = "uusd";
+pub const GOVERNANCE: &str = "governance";
+pub const MIRROR_MINT: &str = "mirror_mint";
+pub const MIRROR_ORACLE: &str = "mirror_oracle";
+pub const COLLATERAL_ORACLE: &str = "collateral_oracle";
+pub const TERRASWAP_FACTORY: &str = "terraswap_factory";
+pub const ANCHOR_MARKET: &str = "anchor_market";
+pub const ATERRA: &str = "terra1aterra";
+pub const NEXUS_TREASURY: &str = "nexus_treasury";
+pub const MASSET: &str = "terra1masset";
+pub const PAIR: &str = "masset_pair";
+
+pub fn mock_dependencies() -> OwnedDeps<MockStorage, MockApi, WasmMockQuerier> {
+    OwnedDeps {
+        storage: MockStorage::default(),
+        api: MockApi::default(),
+        querier: WasmMockQuerier::new(),
+    }
+}
+
+pub fn instantiate_msg() -> InstantiateMsg {
+    InstantiateMsg {
+        stable_denom: STABLE_DENOM.to_string(),
+        governance_contract: GOVERNANCE.to_string(),
+        mirror_mint_contract: MIRROR_MINT.to_string(),
+        anchor_market_contract: ANCHOR_MARKET.to_string(),
+        aterra_addr: ATERRA.to_string(),
+        nexus_treasury: NEXUS_TREASURY.to_string(),
+        protocol_fee: Decimal::zero(),
+        default_max_spread: Decimal::percent(1),
+        max_price_impact: Decimal::percent(5),
+        rebalance_band: Decimal::percent(10),
+        protect_bounty: Decimal::percent(1),
+        max_oracle_age: 60,
+        max_price_deviation: Decimal::percent(5),
+        deposit_cap: None,
+    }
+}
+
+// Mirror, Anchor and Terraswap state of a single masset, prices are updated at mock_env block time
+pub struct WasmMockQuerier {
+    base: MockQuerier<Empty>,
+    pub collateral_price: Decimal,
+    pub collateral_multiplier: Decimal,
+    pub collateral_revoked: bool,
+    pub masset_price: Decimal,
+    pub price_last_updated: u64,
+    pub min_collateral_ratio: Decimal,
+    pub end_price: Option<Decimal>,
+    pub aterra_exchange_rate: Decimal256,
+    // Mirror positions as (collateral, loan) by position idx
+    pub cdps: HashMap<u128, (Uint128, Uint128)>,
+    // pool reserves as (masset, stable)
+    pub pool: (Uint128, Uint128),
+    // spread of swap simulation in percents of swap return
+    pub swap_spread: Decimal,
+    pub token_balances: HashMap<(String, String), Uint128>,
+}
+
+impl Querier for WasmMockQuerier {
+    fn raw_query(&self, bin_request: &[u8]) -> QuerierResult {
+        let request: QueryRequest<Empty> = match from_slice(bin_request) {
+            Ok(v) => v,
+            Err(e) => {
+                return SystemResult::Err(SystemError::InvalidRequest {
+                    error: format!("Parsing query request: {}", e),
+                    request: bin_request.into(),
+                })
+            }
+        };
+        self.handle_query(&request)
+    }
+}
+
+impl WasmMockQuerier {
+    pub fn new() -> Self {
+        WasmMockQuerier {
+            base: MockQuerier::new(&[]),
+            collateral_price: Decimal::one(),
+            collateral_multiplier: Decimal::one(),
+            collateral_revoked: false,
+            masset_price: Decimal::from_ratio(10u128, 1u128),
+            price_last_updated: mock_env().block.time.seconds(),
+            min_collateral_ratio: Decimal::percent(150),
+            end_price: None,
+            aterra_exchange_rate: Decimal256::one(),
+            cdps: HashMap::new(),
+            pool: (Uint128::new(1_000_000_000), Uint128::new(10_000_000_000)),
+            swap_spread: Decimal::zero(),
+            token_balances: HashMap::new(),
+        }
+    }
+
+    pub fn set_stable_balance(&mut self, amount: u128) {
+        self.base.update_balance(MOCK_CONTRACT_ADDR, vec![Coin::new(amount, STABLE_DENOM)]);
+    }
+
+    pub fn set_token_balance(&mut self, token: &str, amount: u128) {
+        self.token_balances.insert((token.to_string(), MOCK_CONTRACT_ADDR.to_string()), Uint128::new(amount));
+    }
+
+    pub fn set_cdp(&mut self, idx: u128, collateral: u128, loan: u128) {
+        self.cdps.insert(idx, (Uint128::new(collateral), Uint128::new(loan)));
+    }
+
+    fn handle_query(&self, request: &QueryRequest<Empty>) -> QuerierResult {
+        match request {
+            QueryRequest::Wasm(WasmQuery::Raw { contract_addr, key }) if contract_addr == MIRROR_MINT => self.mirror_raw_query(key),
+            QueryRequest::Wasm(WasmQuery::Smart { contract_addr, msg }) => match contract_addr.as_str() {
+                MIRROR_ORACLE => match from_binary(msg).unwrap() {
+                    MirrorOracleQueryMsg::Price { .. } => ok(&MirrorPriceResponse {
+                        rate: self.masset_price,
+                        last_updated_base: self.price_last_updated,
+                        last_updated_quote: u64::MAX,
+                    }),
+                },
+                COLLATERAL_ORACLE => match from_binary(msg).unwrap() {
+                    MirrorCollateralOracleQueryMsg::CollateralPrice { asset, .. } => ok(&MirrorCollateralPriceResponse {
+                        asset,
+                        rate: self.collateral_price,
+                        last_updated: self.price_last_updated,
+                        multiplier: self.collateral_multiplier,
+                        is_revoked: self.collateral_revoked,
+                    }),
+                },
+                ANCHOR_MARKET => match from_binary(msg).unwrap() {
+                    AnchorMarketQueryMsg::EpochState { .. } => ok(&AnchorEpochStateResponse {
+                        exchange_rate: self.aterra_exchange_rate,
+                        aterra_supply: Uint256::zero(),
+                    }),
+                },
+                TERRASWAP_FACTORY => match from_binary(msg).unwrap() {
+                    FactoryQueryMsg::Pair { asset_infos } => ok(&PairInfo {
+                        asset_infos,
+                        contract_addr: PAIR.to_string(),
+                        liquidity_token: "lp_token".to_string(),
+                    }),
+                    _ => unsupported(contract_addr),
+                },
+                PAIR => self.pair_query(from_binary(msg).unwrap()),
+                token => match from_binary(msg).unwrap() {
+                    Cw20QueryMsg::Balance { address } => ok(&Cw20BalanceResponse {
+                        balance: self.token_balances.get(&(token.to_string(), address)).cloned().unwrap_or_default(),
+                    }),
+                    _ => unsupported(contract_addr),
+                },
+            },
+            _ => self.base.handle_query(request),
+        }
+    }
+
+    fn mirror_raw_query(&self, key: &Binary) -> QuerierResult {
+        if key.as_slice() == b"config" {
+            return ok(&MirrorMintConfigResponse {
+                owner: "mirror_owner".to_string(),
+                oracle: MIRROR_ORACLE.to_string(),
+                collector: "mirror_collector".to_string(),
+                collateral_oracle: COLLATERAL_ORACLE.to_string(),
+                staking: "mirror_staking".to_string(),
+                terraswap_factory: TERRASWAP_FACTORY.to_string(),
+                lock: "mirror_lock".to_string(),
+                base_denom: STABLE_DENOM.to_string(),
+                token_code_id: 1,
+                protocol_fee_rate: Decimal::zero(),
+            });
+        };
+        if key.as_slice() == concat(&to_length_prefixed(b"asset_config"), MASSET.as_bytes()) {
+            return ok(&MirrorAssetConfigResponse {
+                token: MASSET.to_string(),
+                auction_discount: Decimal::percent(20),
+                min_collateral_ratio: self.min_collateral_ratio,
+                end_price: self.end_price,
+                ipo_params: None,
+            });
+        };
+        for (idx, (collateral, loan)) in self.cdps.iter() {
+            if key.as_slice() == concat(&to_length_prefixed(b"position"), idx.to_string().as_bytes()) {
+                // Mirror removes position with zero collateral and asset, raw query of missing key is empty
+                if collateral.is_zero() && loan.is_zero() {
+                    return SystemResult::Ok(ContractResult::Ok(Binary::default()));
+                };
+                return ok(&MirrorCDPResponse {
+                    idx: Uint128::new(*idx),
+                    owner: Addr::unchecked(MOCK_CONTRACT_ADDR),
+                    collateral: AssetRaw {
+                        info: AssetInfoRaw::NativeToken { denom: ATERRA.to_string() },
+                        amount: *collateral,
+                    },
+                    asset: AssetRaw {
+                        info: AssetInfoRaw::NativeToken { denom: MASSET.to_string() },
+                        amount: *loan,
+                    },
+                });
+            };
+        }
+        if key.as_slice().starts_with(&to_length_prefixed(b"position")) {
+            return SystemResult::Ok(ContractResult::Ok(Binary::default()));
+        };
+        SystemResult::Ok(ContractResult::Err("not found".to_string()))
+    }
+
+    fn pair_query(&self, msg: PairQueryMsg) -> QuerierResult {
+        let (masset_reserve, stable_reserve) = self.pool;
+        match msg {
+            PairQueryMsg::Pool {} => ok(&PoolResponse {
+                assets: [
+                    Asset {
+                        info: AssetInfo::NativeToken { denom: STABLE_DENOM.to_string() },
+                        amount: stable_reserve,
+                    },
+                    Asset {
+                        info: AssetInfo::Token { contract_addr: MASSET.to_string() },
+                        amount: masset_reserve,
+                    },
+                ],
+                total_share: Uint128::zero(),
+            }),
+            PairQueryMsg::Simulation { offer_asset } => {
+                let (offer_reserve, ask_reserve) = match offer_asset.info {
+                    AssetInfo::Token { .. } => (masset_reserve, stable_reserve),
+                    AssetInfo::NativeToken { .. } => (stable_reserve, masset_reserve),
+                };
+                let spot_return = offer_asset.amount.multiply_ratio(ask_reserve, offer_reserve);
+                let spread_amount = spot_return * self.swap_spread;
+                ok(&SimulationResponse {
+                    return_amount: spot_return - spread_amount,
+                    spread_amount,
+                    commission_amount: Uint128::zero(),
+                })
+            }
+            _ => unsupported(PAIR),
+        }
+    }
+}
+
+fn ok<T: serde::Serialize>(response: &T) -> QuerierResult {
+    SystemResult::Ok(ContractResult::Ok(to_binary(response).unwrap()))
+}
+
+fn unsupported(contract_addr: &str) -> QuerierResult {
+    SystemResult::Err(SystemError::NoSuchContract { addr: contract_addr.to_string() })
+}
@@ -0,0 +1,86 @@
+use cosmwasm_std::testing::{mock_env, mock_info, MockApi, MockStorage};
+use cosmwasm_std::{Addr, ContractResult, Decimal, Event, OwnedDeps, Reply, SubMsgExecutionResponse, Uint128};
+
+use structured_note_package::mirror::CDPState;
+use structured_note_package::structured_note::ExecuteMsg;
+
+use crate::contract::{execute, instantiate};
+use crate::state::{add_farmer_to_cdp, increase_position_collateral, increase_position_loan, Position, save_position};
+use crate::testing::mock_querier::{GOVERNANCE, instantiate_msg, MASSET, mock_dependencies, PAIR, WasmMockQuerier};
+
+mod deposit_tests;
+mod error_tests;
+mod governance_tests;
+mod mock_querier;
+mod protect_tests;
+mod query_tests;
+mod reply_parser_tests;
+mod simulation_tests;
+mod state_tests;
+mod withdraw_tests;
+
+pub const CDP_IDX: u128 = 1;
+
+pub type MockDeps = OwnedDeps<MockStorage, MockApi, WasmMockQuerier>;
+
+// Instantiated contract with registered masset
+pub fn setup() -> MockDeps {
+    let mut deps = mock_dependencies();
+    instantiate(deps.as_mut(), mock_env(), mock_info("creator", &[]), instantiate_msg()).unwrap();
+    execute(deps.as_mut(), mock_env(), mock_info(GOVERNANCE, &[]), ExecuteMsg::RegisterAsset {
+        masset_token: MASSET.to_string(),
+        min_over_collateralization: Decimal::percent(120),
+        max_leverage: 3,
+        deposit_cap: None,
+        loan_cap: None,
+        pair_addr: Some(PAIR.to_string()),
+    }).unwrap();
+    deps
+}
+
+// Adds farmer's collateral and loan to the CDP of masset, in contract and in Mirror
+pub fn open_position(deps: &mut MockDeps, farmer: &str, collateral: u128, loan: u128) {
+    let farmer_addr = Addr::unchecked(farmer);
+    let masset_token = Addr::unchecked(MASSET);
+    save_position(&mut deps.storage, &Position {
+        farmer_addr: farmer_addr.clone(),
+        masset_token: masset_token.clone(),
+        cdp_idx: Uint128::new(CDP_IDX),
+        leverage: 1,
+        loan_shares: Uint128::zero(),
+        collateral_shares: Uint128::zero(),
+        aim_collateral_ratio: Decimal::percent(200),
+        target_leverage: None,
+    }).unwrap();
+    add_farmer_to_cdp(&mut deps.storage, Uint128::new(CDP_IDX), farmer_addr.clone(), masset_token.clone()).unwrap();
+    let (cdp_collateral, cdp_loan) = deps.querier.cdps.get(&CDP_IDX).cloned().unwrap_or_default();
+    let cdp_state = CDPState {
+        collateral_amount: cdp_collateral + Uint128::new(collateral),
+        loan_amount: cdp_loan,
+    };
+    increase_position_collateral(&mut deps.storage, &farmer_addr, &masset_token, Uint128::new(collateral), &cdp_state).unwrap();
+    let cdp_state = CDPState {
+        collateral_amount: cdp_state.collateral_amount,
+        loan_amount: cdp_loan + Uint128::new(loan),
+    };
+    increase_position_loan(&mut deps.storage, &farmer_addr, &masset_token, Uint128::new(loan), &cdp_state).unwrap();
+    deps.querier.cdps.insert(CDP_IDX, (cdp_state.collateral_amount, cdp_state.loan_amount));
+}
+
+pub fn wasm_event(contract_addr: &str, attrs: &[(&str, &str)]) -> Event {
+    let mut event = Event::new("wasm").add_attribute("_contract_address", contract_addr);
+    for (key, value) in attrs {
+        event = event.add_attribute(*key, *value);
+    }
+    event
+}
+
+pub fn reply_msg(reply_id: u64, events: Vec<Event>) -> Reply {
+    Reply {
+        id: reply_id,
+        result: ContractResult::Ok(SubMsgExecutionResponse {
+            events,
+            data: None,
+        }),
+    }
+}
@@ -0,0 +1,144 @@
+use cosmwasm_bignumber::Decimal256;
+use cosmwasm_std::testing::{mock_env, mock_info};
+use cosmwasm_std::{Addr, BankMsg, Coin, CosmosMsg, Decimal, SubMsg, to_binary, Uint128, WasmMsg};
+use cw20::Cw20ExecuteMsg;
+use terraswap::asset::{Asset, AssetInfo};
+
+use structured_note_package::anchor::AnchorMarketMsg;
+use structured_note_package::mirror::{MirrorMintCW20HookMsg, MirrorMintExecuteMsg};
+use structured_note_package::structured_note::ExecuteMsg;
+
+use crate::commands::pay_protect_bounty;
+use crate::contract::{execute, reply};
+use crate::error::ContractError;
+use crate::state::{load_cdp, load_operation, load_position, load_total_collateral};
+use crate::SubmsgIds;
+use crate::testing::{CDP_IDX, MockDeps, open_position, reply_msg, setup, wasm_event};
+use crate::testing::mock_querier::{ANCHOR_MARKET, ATERRA, MASSET, MIRROR_MINT, NEXUS_TREASURY, STABLE_DENOM};
+
+const KEEPER: &str = "keeper";
+
+fn send_stable(to: &str, amount: u128) -> SubMsg {
+    SubMsg::new(CosmosMsg::Bank(BankMsg::Send {
+        to_address: to.to_string(),
+        amount: vec![Coin::new(amount, STABLE_DENOM)],
+    }))
+}
+
+// CDP at 1_000_000 / (60_000 * 10) is below safe ratio 150% * 120%
+fn protect() -> (MockDeps, Uint128) {
+    let mut deps = setup();
+    open_position(&mut deps, "farmer", 1_000_000, 60_000);
+    execute(deps.as_mut(), mock_env(), mock_info(KEEPER, &[]), ExecuteMsg::ProtectCdp { masset_token: MASSET.to_string() }).unwrap();
+    let bounty = load_operation(&deps.storage, 1).unwrap().protect_state.unwrap().bounty;
+    assert!(!bounty.is_zero());
+    (deps, bounty)
+}
+
+#[test]
+fn protection_withdraws_collateral_above_mirror_min_collateral_ratio() {
+    let mut deps = setup();
+    open_position(&mut deps, "farmer", 1_000_000, 60_000);
+    let position_before = load_position(&deps.storage, &Addr::unchecked("farmer"), &Addr::unchecked(MASSET)).unwrap();
+
+    let res = execute(deps.as_mut(), mock_env(), mock_info(KEEPER, &[]), ExecuteMsg::ProtectCdp { masset_token: MASSET.to_string() }).unwrap();
+    // delever to 190% needs 155_555 of collateral, withdraw is limited by 150% of Mirror
+    assert_eq!(res.messages, vec![
+        SubMsg::reply_on_success(CosmosMsg::Wasm(WasmMsg::Execute {
+            contract_addr: MIRROR_MINT.to_string(),
+            msg: to_binary(&MirrorMintExecuteMsg::Withdraw {
+                position_idx: Uint128::new(CDP_IDX),
+                collateral: Some(Asset {
+                    info: AssetInfo::Token { contract_addr: ATERRA.to_string() },
+                    amount: Uint128::new(100_000),
+                }),
+            }).unwrap(),
+            funds: vec![],
+        }), SubmsgIds::WithdrawCollateral.reply_id(1)),
+    ]);
+    let protect_state = load_operation(&deps.storage, 1).unwrap().protect_state.unwrap();
+    assert_eq!(protect_state.caller, Addr::unchecked(KEEPER));
+    assert_eq!(protect_state.bounty, Uint128::new(1_555));
+    assert_eq!(load_position(&deps.storage, &Addr::unchecked("farmer"), &Addr::unchecked(MASSET)).unwrap(), position_before);
+}
+
+#[test]
+fn protection_of_safe_cdp_is_rejected() {
+    let mut deps = setup();
+    open_position(&mut deps, "farmer", 1_000_000, 50_000);
+    let err = execute(deps.as_mut(), mock_env(), mock_info(KEEPER, &[]), ExecuteMsg::ProtectCdp { masset_token: MASSET.to_string() }).unwrap_err();
+    assert_eq!(err, ContractError::CdpNotInDanger {
+        collateral_ratio: Decimal::percent(200),
+        safe_collateral_ratio: Decimal::percent(180),
+    });
+}
+
+#[test]
+fn protection_leftover_goes_back_to_cdp() {
+    let (mut deps, bounty) = protect();
+    // masset is bought and burnt, stable above bounty is left in contract
+    deps.querier.set_cdp(CDP_IDX, 900_000, 50_000);
+    deps.querier.set_stable_balance(bounty.u128() + 5_000);
+    deps.querier.aterra_exchange_rate = Decimal256::percent(125);
+
+    let res = pay_protect_bounty(deps.as_mut(), mock_env(), 1).unwrap();
+    assert_eq!(res.messages, vec![
+        SubMsg::reply_on_success(CosmosMsg::Wasm(WasmMsg::Execute {
+            contract_addr: ANCHOR_MARKET.to_string(),
+            msg: to_binary(&AnchorMarketMsg::DepositStable {}).unwrap(),
+            funds: vec![Coin::new(5_000, STABLE_DENOM)],
+        }), SubmsgIds::DepositStable.reply_id(1)),
+        send_stable(KEEPER, bounty.u128()),
+    ]);
+
+    let events = vec![wasm_event(ANCHOR_MARKET, &[("mint_amount", "4000")])];
+    let res = reply(deps.as_mut(), mock_env(), reply_msg(SubmsgIds::DepositStable.reply_id(1), events)).unwrap();
+    assert_eq!(res.messages, vec![
+        SubMsg::reply_on_success(CosmosMsg::Wasm(WasmMsg::Execute {
+            contract_addr: ATERRA.to_string(),
+            msg: to_binary(&Cw20ExecuteMsg::Send {
+                contract: MIRROR_MINT.to_string(),
+                amount: Uint128::new(4_000),
+                msg: to_binary(&MirrorMintCW20HookMsg::Deposit { position_idx: Uint128::new(CDP_IDX) }).unwrap(),
+            }).unwrap(),
+            funds: vec![],
+        }), SubmsgIds::DepositToCDP.reply_id(1)),
+    ]);
+
+    deps.querier.set_cdp(CDP_IDX, 904_000, 50_000);
+    deps.querier.set_stable_balance(0);
+    let events = vec![wasm_event(MIRROR_MINT, &[("deposit_amount", &format!("4000{}", ATERRA))])];
+    let res = reply(deps.as_mut(), mock_env(), reply_msg(SubmsgIds::DepositToCDP.reply_id(1), events)).unwrap();
+    assert!(res.messages.is_empty());
+
+    assert!(load_operation(&deps.storage, 1).is_err());
+    let cdp = load_cdp(&deps.storage, &Addr::unchecked(MASSET)).unwrap();
+    assert_eq!(cdp.collateral, Uint128::new(904_000));
+    assert_eq!(cdp.loan, Uint128::new(50_000));
+    assert_eq!(load_total_collateral(&deps.storage).unwrap(), Uint128::new(904_000));
+}
+
+#[test]
+fn protection_dust_goes_to_treasury() {
+    let (mut deps, bounty) = protect();
+    deps.querier.set_cdp(CDP_IDX, 900_000, 50_000);
+    // 1 uusd mints no aterra at exchange rate 1.25
+    deps.querier.set_stable_balance(bounty.u128() + 1);
+    deps.querier.aterra_exchange_rate = Decimal256::percent(125);
+
+    let res = pay_protect_bounty(deps.as_mut(), mock_env(), 1).unwrap();
+    assert_eq!(res.messages, vec![send_stable(NEXUS_TREASURY, 1), send_stable(KEEPER, bounty.u128())]);
+    assert!(load_operation(&deps.storage, 1).is_err());
+    assert_eq!(load_cdp(&deps.storage, &Addr::unchecked(MASSET)).unwrap().collateral, Uint128::new(900_000));
+}
+
+#[test]
+fn protection_without_leftover_pays_bounty_only() {
+    let (mut deps, bounty) = protect();
+    deps.querier.set_cdp(CDP_IDX, 900_000, 50_000);
+    deps.querier.set_stable_balance(bounty.u128() - 10);
+
+    let res = pay_protect_bounty(deps.as_mut(), mock_env(), 1).unwrap();
+    assert_eq!(res.messages, vec![send_stable(KEEPER, bounty.u128() - 10)]);
+    assert!(load_operation(&deps.storage, 1).is_err());
+}
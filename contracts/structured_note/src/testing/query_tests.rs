@@ -0,0 +1,283 @@
+use cosmwasm_bignumber::Decimal256;
+use cosmwasm_std::testing::{mock_env, mock_info};
+use cosmwasm_std::{Addr, Decimal, from_binary, Uint128};
+
+use structured_note_package::structured_note::{CdpResponse, CdpsResponse, ExecuteMsg, PositionHealthResponse, PositionsResponse, QueryMsg, SimulateWithdrawResponse, WithdrawRoundResponse};
+
+use crate::contract::{execute, query};
+use crate::error::ContractError;
+use crate::queries::{query_position_health, query_simulate_deposit, query_simulate_withdraw};
+use crate::state::{add_farmer_to_cdp, Position, save_position};
+use crate::testing::{CDP_IDX, MockDeps, open_position, setup};
+use crate::testing::mock_querier::{ATERRA, GOVERNANCE, MASSET, PAIR};
+
+const FARMER: &str = "farmer";
+
+// Oracle prices are updated 120 seconds ago, max_oracle_age is 60
+fn stale_prices() -> MockDeps {
+    let mut deps = setup();
+    open_position(&mut deps, FARMER, 1_000_000, 50_000);
+    deps.querier.price_last_updated = mock_env().block.time.seconds() - 120;
+    deps
+}
+
+#[test]
+fn queries_report_stale_price_age() {
+    let deps = stale_prices();
+    let health = query_position_health(deps.as_ref(), mock_env(), FARMER.to_string(), MASSET.to_string()).unwrap();
+    assert_eq!(health.price_age, 120);
+    assert_eq!(health.collateral_ratio, Some(Decimal::percent(200)));
+
+    let deposit = query_simulate_deposit(deps.as_ref(), mock_env(), FARMER.to_string(), MASSET.to_string(), Uint128::new(100_000), None, None, Decimal::percent(200)).unwrap();
+    assert_eq!(deposit.price_age, 120);
+
+    let withdraw = query_simulate_withdraw(deps.as_ref(), mock_env(), FARMER.to_string(), MASSET.to_string(), Uint128::new(500_000), Decimal::percent(200)).unwrap();
+    assert_eq!(withdraw.price_age, 120);
+    assert!(withdraw.reaches_aim);
+}
+
+#[test]
+fn queries_report_fresh_price_age() {
+    let mut deps = stale_prices();
+    deps.querier.price_last_updated = mock_env().block.time.seconds();
+    let health = query_position_health(deps.as_ref(), mock_env(), FARMER.to_string(), MASSET.to_string()).unwrap();
+    assert_eq!(health.price_age, 0);
+}
+
+#[test]
+fn withdraw_rejects_stale_price() {
+    let mut deps = stale_prices();
+    let err = execute(deps.as_mut(), mock_env(), mock_info(FARMER, &[]), ExecuteMsg::Withdraw {
+        masset_token: MASSET.to_string(),
+        aim_collateral: Uint128::new(500_000),
+        aim_collateral_ratio: Decimal::percent(200),
+        max_spread: None,
+        receive_as: None,
+    }).unwrap_err();
+    assert_eq!(err, ContractError::OraclePriceStale {
+        asset: ATERRA.to_string(),
+        last_updated: mock_env().block.time.seconds() - 120,
+        max_oracle_age: 60,
+    });
+}
+
+#[test]
+fn simulate_deposit_applies_deposit_caps() {
+    let mut deps = setup();
+    execute(deps.as_mut(), mock_env(), mock_info(GOVERNANCE, &[]), ExecuteMsg::RegisterAsset {
+        masset_token: MASSET.to_string(),
+        min_over_collateralization: Decimal::percent(120),
+        max_leverage: 3,
+        deposit_cap: Some(Uint128::new(150_000)),
+        loan_cap: None,
+        pair_addr: Some(PAIR.to_string()),
+    }).unwrap();
+    // the same partial fill as Deposit of 1_000_000 with one sell
+    let deposit = query_simulate_deposit(deps.as_ref(), mock_env(), FARMER.to_string(), MASSET.to_string(), Uint128::new(1_000_000), Some(1), None, Decimal::percent(200)).unwrap();
+    assert_eq!(deposit.accepted_amount, Uint128::new(99_900));
+    assert_eq!(deposit.refund_amount, Uint128::new(900_100));
+    assert_eq!(deposit.fee_amount + deposit.net_deposit_amount, Uint128::new(99_900));
+}
+
+fn save_farmer_position(deps: &mut MockDeps, farmer: &str, masset_token: &str) {
+    save_position(&mut deps.storage, &Position {
+        farmer_addr: Addr::unchecked(farmer),
+        masset_token: Addr::unchecked(masset_token),
+        cdp_idx: Uint128::new(1),
+        leverage: 1,
+        loan_shares: Uint128::zero(),
+        collateral_shares: Uint128::zero(),
+        aim_collateral_ratio: Decimal::percent(200),
+        target_leverage: None,
+    }).unwrap();
+    add_farmer_to_cdp(&mut deps.storage, Uint128::new(1), Addr::unchecked(farmer), Addr::unchecked(masset_token)).unwrap();
+    deps.querier.set_cdp(1, 0, 0);
+}
+
+fn query_positions(deps: &MockDeps, msg: QueryMsg) -> Vec<(String, String)> {
+    let res: PositionsResponse = from_binary(&query(deps.as_ref(), mock_env(), msg).unwrap()).unwrap();
+    res.positions.into_iter().map(|p| (p.farmer_addr, p.masset_token)).collect()
+}
+
+fn pair(farmer: &str, masset_token: &str) -> (String, String) {
+    (farmer.to_string(), masset_token.to_string())
+}
+
+#[test]
+fn positions_of_farmer_are_paginated() {
+    let mut deps = setup();
+    for masset_token in ["masset_a", "masset_b", "masset_c"] {
+        save_farmer_position(&mut deps, "farmer_a", masset_token);
+    }
+    save_farmer_position(&mut deps, "farmer_b", "masset_a");
+
+    let page = query_positions(&deps, QueryMsg::FarmerPositions { farmer_addr: "farmer_a".to_string(), start_after: None, limit: Some(2) });
+    assert_eq!(page, vec![pair("farmer_a", "masset_a"), pair("farmer_a", "masset_b")]);
+    let page = query_positions(&deps, QueryMsg::FarmerPositions { farmer_addr: "farmer_a".to_string(), start_after: Some("masset_b".to_string()), limit: Some(2) });
+    assert_eq!(page, vec![pair("farmer_a", "masset_c")]);
+}
+
+#[test]
+fn all_positions_are_paginated() {
+    let mut deps = setup();
+    save_farmer_position(&mut deps, "farmer_a", "masset_a");
+    save_farmer_position(&mut deps, "farmer_a", "masset_b");
+    save_farmer_position(&mut deps, "farmer_b", "masset_a");
+
+    let page = query_positions(&deps, QueryMsg::AllPositions { start_after: None, limit: Some(2) });
+    assert_eq!(page, vec![pair("farmer_a", "masset_a"), pair("farmer_a", "masset_b")]);
+    let page = query_positions(&deps, QueryMsg::AllPositions { start_after: Some(pair("farmer_a", "masset_b")), limit: None });
+    assert_eq!(page, vec![pair("farmer_b", "masset_a")]);
+}
+
+#[test]
+fn positions_page_is_limited() {
+    let mut deps = setup();
+    for idx in 0..40 {
+        save_farmer_position(&mut deps, "farmer_a", &format!("masset_{:02}", idx));
+    }
+    let page = query_positions(&deps, QueryMsg::FarmerPositions { farmer_addr: "farmer_a".to_string(), start_after: None, limit: None });
+    assert_eq!(page.len(), 10);
+    let page = query_positions(&deps, QueryMsg::FarmerPositions { farmer_addr: "farmer_a".to_string(), start_after: None, limit: Some(100) });
+    assert_eq!(page.len(), 30);
+}
+
+#[test]
+fn cdp_reports_mirror_state_and_farmers_sums() {
+    let mut deps = setup();
+    open_position(&mut deps, "farmer_a", 1_000_000, 50_000);
+    open_position(&mut deps, "farmer_b", 1_000_000, 50_000);
+    // Anchor yield accrued to the CDP collateral
+    deps.querier.set_cdp(CDP_IDX, 2_000_010, 100_000);
+
+    let res: CdpResponse = from_binary(&query(deps.as_ref(), mock_env(), QueryMsg::Cdp { masset_token: MASSET.to_string() }).unwrap()).unwrap();
+    assert_eq!(res, CdpResponse {
+        idx: Uint128::new(CDP_IDX),
+        masset_token: MASSET.to_string(),
+        farmers: vec!["farmer_a".to_string(), "farmer_b".to_string()],
+        mirror_collateral: Uint128::new(2_000_010),
+        mirror_loan: Uint128::new(100_000),
+        farmers_collateral: Uint128::new(2_000_010),
+        farmers_loan: Uint128::new(100_000),
+        collateral_shares: Uint128::new(2_000_000),
+        loan_shares: Uint128::new(100_000),
+        farmers_collateral_shares: Uint128::new(2_000_000),
+        farmers_loan_shares: Uint128::new(100_000),
+    });
+}
+
+#[test]
+fn all_cdps_are_paginated() {
+    let mut deps = setup();
+    for (idx, masset_token) in ["masset_a", "masset_b", "masset_c"].iter().enumerate() {
+        add_farmer_to_cdp(&mut deps.storage, Uint128::from(idx as u128 + 1), Addr::unchecked("farmer_a"), Addr::unchecked(*masset_token)).unwrap();
+        deps.querier.set_cdp(idx as u128 + 1, 1_000_000, 50_000);
+    }
+
+    let res: CdpsResponse = from_binary(&query(deps.as_ref(), mock_env(), QueryMsg::AllCdps { start_after: None, limit: Some(2) }).unwrap()).unwrap();
+    assert_eq!(res.cdps.iter().map(|cdp| cdp.masset_token.as_str()).collect::<Vec<&str>>(), vec!["masset_a", "masset_b"]);
+    let res: CdpsResponse = from_binary(&query(deps.as_ref(), mock_env(), QueryMsg::AllCdps { start_after: Some("masset_b".to_string()), limit: None }).unwrap()).unwrap();
+    assert_eq!(res.cdps.len(), 1);
+    assert_eq!(res.cdps[0].idx, Uint128::new(3));
+    assert_eq!(res.cdps[0].farmers_collateral, Uint128::zero());
+}
+
+fn simulate_withdraw(deps: &MockDeps, aim_collateral: u128, aim_collateral_ratio: Decimal) -> Result<SimulateWithdrawResponse, ContractError> {
+    query(deps.as_ref(), mock_env(), QueryMsg::SimulateWithdraw {
+        farmer_addr: "farmer".to_string(),
+        masset_token: MASSET.to_string(),
+        aim_collateral: Uint128::new(aim_collateral),
+        aim_collateral_ratio,
+    }).map(|res| from_binary(&res).unwrap())
+}
+
+fn withdraw_round(withdraw_amount: u128, offer_amount: u128, burn_amount: u128, collateral: u128, loan: u128) -> WithdrawRoundResponse {
+    WithdrawRoundResponse {
+        withdraw_amount: Uint128::new(withdraw_amount),
+        stable_redeemed: Uint128::new(withdraw_amount),
+        offer_amount: Uint128::new(offer_amount),
+        masset_bought: Uint128::new(burn_amount),
+        burn_amount: Uint128::new(burn_amount),
+        protocol_fee: Uint128::zero(),
+        collateral: Uint128::new(collateral),
+        loan: Uint128::new(loan),
+    }
+}
+
+#[test]
+fn simulate_withdraw_replays_rounds_until_aim() {
+    let mut deps = setup();
+    open_position(&mut deps, "farmer", 1_000_000, 50_000);
+
+    // every withdraw is limited by safe ratio 180%, masset is bought on pool price 10
+    let res = simulate_withdraw(&deps, 500_000, Decimal::percent(200)).unwrap();
+    assert_eq!(res, SimulateWithdrawResponse {
+        aim_loan: Uint128::new(25_000),
+        rounds: vec![
+            withdraw_round(100_000, 100_000, 10_000, 900_000, 40_000),
+            withdraw_round(180_000, 150_000, 15_000, 720_000, 25_000),
+            withdraw_round(220_000, 0, 0, 500_000, 25_000),
+        ],
+        collateral: Uint128::new(500_000),
+        loan: Uint128::new(25_000),
+        reaches_aim: true,
+        spread_amount: Uint128::zero(),
+        commission_amount: Uint128::zero(),
+        return_amount: Uint128::new(250_000),
+        return_masset_amount: Uint128::zero(),
+        price_age: 0,
+    });
+}
+
+#[test]
+fn simulate_withdraw_rejects_aim_ratio_above_current() {
+    let mut deps = setup();
+    open_position(&mut deps, "farmer", 1_000_000, 50_000);
+    let err = simulate_withdraw(&deps, 500_000, Decimal::percent(250)).unwrap_err();
+    assert_eq!(err, ContractError::AimCollateralRatioTooHigh {
+        aim_collateral_ratio: Decimal::percent(250),
+        current_collateral_ratio: Decimal::percent(200),
+    });
+}
+
+fn position_health(deps: &MockDeps) -> PositionHealthResponse {
+    from_binary(&query(deps.as_ref(), mock_env(), QueryMsg::PositionHealth {
+        farmer_addr: "farmer".to_string(),
+        masset_token: MASSET.to_string(),
+    }).unwrap()).unwrap()
+}
+
+#[test]
+fn position_health_reports_liquidation_distance_and_nav() {
+    let mut deps = setup();
+    open_position(&mut deps, "farmer", 1_000_000, 50_000);
+    deps.querier.min_collateral_ratio = Decimal::percent(160);
+    deps.querier.aterra_exchange_rate = Decimal256::percent(125);
+
+    assert_eq!(position_health(&deps), PositionHealthResponse {
+        collateral: Uint128::new(1_000_000),
+        loan: Uint128::new(50_000),
+        collateral_ratio: Some(Decimal::percent(200)),
+        mirror_min_collateral_ratio: Decimal::percent(160),
+        min_over_collateralization: Decimal::percent(120),
+        safe_collateral_ratio: Decimal::percent(192),
+        liquidation_price_move: Some(Decimal::percent(25)),
+        liquidation_price: Some(Decimal::percent(1250)),
+        collateral_value: Uint128::new(1_250_000),
+        loan_value: Uint128::new(500_000),
+        net_asset_value: Uint128::new(750_000),
+        price_age: 0,
+    });
+}
+
+#[test]
+fn position_health_without_loan() {
+    let mut deps = setup();
+    open_position(&mut deps, "farmer", 1_000_000, 0);
+
+    let res = position_health(&deps);
+    assert_eq!(res.collateral_ratio, None);
+    assert_eq!(res.liquidation_price_move, None);
+    assert_eq!(res.liquidation_price, None);
+    assert_eq!(res.net_asset_value, Uint128::new(1_000_000));
+}
@@ -0,0 +1,150 @@
+use cosmwasm_std::{Addr, Event, Uint128};
+use terraswap::asset::{Asset, AssetInfo};
+
+use crate::reply_parser::{find_wasm_attr, may_parse_asset_attr, parse_amount_attr, parse_asset, parse_asset_attr, ReplyParseError, token_asset_info};
+use crate::testing::wasm_event;
+
+const MINT: &str = "mirror_mint";
+const TOKEN: &str = "terra1dzhzukyezv0etz22ud940z7adyv7xgcjkahuun";
+const IBC_DENOM: &str = "ibc/27394FB092D2ECCD56123C74F36E4C1F926001CEADA9CA97EA622B25F41E5EB2";
+
+#[test]
+fn parse_native_asset() {
+    assert_eq!(parse_asset("amount", "123uusd").unwrap(), Asset {
+        info: AssetInfo::NativeToken { denom: "uusd".to_string() },
+        amount: Uint128::new(123),
+    });
+}
+
+#[test]
+fn parse_token_asset() {
+    assert_eq!(parse_asset("amount", &format!("123{}", TOKEN)).unwrap(), Asset {
+        info: AssetInfo::Token { contract_addr: TOKEN.to_string() },
+        amount: Uint128::new(123),
+    });
+}
+
+#[test]
+fn parse_ibc_asset() {
+    assert_eq!(parse_asset("amount", &format!("123{}", IBC_DENOM)).unwrap(), Asset {
+        info: AssetInfo::NativeToken { denom: IBC_DENOM.to_string() },
+        amount: Uint128::new(123),
+    });
+}
+
+#[test]
+fn parse_invalid_asset() {
+    for value in ["uusd", "123", ""] {
+        assert_eq!(parse_asset("amount", value).unwrap_err(), ReplyParseError::InvalidAsset {
+            key: "amount".to_string(),
+            value: value.to_string(),
+        });
+    }
+    // amount overflows Uint128
+    let value = format!("{}0uusd", u128::MAX);
+    assert_eq!(parse_asset("amount", &value).unwrap_err(), ReplyParseError::InvalidAmount {
+        key: "amount".to_string(),
+        value: format!("{}0", u128::MAX),
+    });
+}
+
+#[test]
+fn attr_of_missing_emitter_is_not_found() {
+    let events = vec![wasm_event(TOKEN, &[("amount", "100")])];
+    assert_eq!(find_wasm_attr(&events, &Addr::unchecked(MINT), "amount").unwrap_err(), ReplyParseError::AttrNotFound {
+        contract_addr: MINT.to_string(),
+        key: "amount".to_string(),
+    });
+}
+
+#[test]
+fn attr_of_wrong_contract_address_is_not_found() {
+    // attribute of another contract called in the same submessage isn't picked up
+    let events = vec![
+        wasm_event(TOKEN, &[("mint_amount", "1")]),
+        Event::new("wasm").add_attribute("_contract_address", "another_contract").add_attribute("mint_amount", "2"),
+        Event::new("transfer").add_attribute("_contract_address", MINT).add_attribute("mint_amount", "3"),
+    ];
+    assert_eq!(parse_amount_attr(&events, &Addr::unchecked(MINT), "mint_amount").unwrap_err(), ReplyParseError::AttrNotFound {
+        contract_addr: MINT.to_string(),
+        key: "mint_amount".to_string(),
+    });
+}
+
+#[test]
+fn attr_of_emitter_is_found_by_both_address_keys() {
+    let events = vec![
+        wasm_event(TOKEN, &[("mint_amount", "1")]),
+        Event::new("wasm").add_attribute("contract_address", MINT).add_attribute("mint_amount", "2"),
+    ];
+    assert_eq!(parse_amount_attr(&events, &Addr::unchecked(MINT), "mint_amount").unwrap(), Uint128::new(2));
+
+    let events = vec![wasm_event(MINT, &[("mint_amount", "3")])];
+    assert_eq!(parse_amount_attr(&events, &Addr::unchecked(MINT), "mint_amount").unwrap(), Uint128::new(3));
+}
+
+#[test]
+fn attr_of_emitter_is_found_in_its_section_of_event() {
+    // contract address key starts section of attributes of each contract
+    let event = Event::new("wasm")
+        .add_attribute("_contract_address", MINT)
+        .add_attribute("action", "burn")
+        .add_attribute("_contract_address", TOKEN)
+        .add_attribute("amount", "1")
+        .add_attribute("_contract_address", MINT)
+        .add_attribute("burn_amount", "2");
+    let events = vec![event];
+    assert_eq!(find_wasm_attr(&events, &Addr::unchecked(MINT), "amount").unwrap_err(), ReplyParseError::AttrNotFound {
+        contract_addr: MINT.to_string(),
+        key: "amount".to_string(),
+    });
+    assert_eq!(parse_amount_attr(&events, &Addr::unchecked(MINT), "burn_amount").unwrap(), Uint128::new(2));
+    assert_eq!(parse_amount_attr(&events, &Addr::unchecked(TOKEN), "amount").unwrap(), Uint128::new(1));
+}
+
+#[test]
+fn attr_of_emitter_is_found_in_from_contract_event() {
+    let event = Event::new("from_contract")
+        .add_attribute("contract_address", TOKEN)
+        .add_attribute("mint_amount", "1")
+        .add_attribute("contract_address", MINT)
+        .add_attribute("mint_amount", "2");
+    assert_eq!(parse_amount_attr(&[event], &Addr::unchecked(MINT), "mint_amount").unwrap(), Uint128::new(2));
+}
+
+#[test]
+fn invalid_amount_attr() {
+    let events = vec![wasm_event(MINT, &[("position_idx", "1uusd")])];
+    assert_eq!(parse_amount_attr(&events, &Addr::unchecked(MINT), "position_idx").unwrap_err(), ReplyParseError::InvalidAmount {
+        key: "position_idx".to_string(),
+        value: "1uusd".to_string(),
+    });
+}
+
+#[test]
+fn asset_attr_of_unexpected_asset() {
+    let expected = token_asset_info(&Addr::unchecked(TOKEN));
+    let events = vec![wasm_event(MINT, &[("mint_amount", "100uusd")])];
+    assert_eq!(parse_asset_attr(&events, &Addr::unchecked(MINT), "mint_amount", &expected).unwrap_err(), ReplyParseError::UnexpectedAsset {
+        key: "mint_amount".to_string(),
+        expected: TOKEN.to_string(),
+        actual: "uusd".to_string(),
+    });
+
+    let events = vec![wasm_event(MINT, &[("mint_amount", &format!("100{}", TOKEN))])];
+    assert_eq!(parse_asset_attr(&events, &Addr::unchecked(MINT), "mint_amount", &expected).unwrap(), Uint128::new(100));
+}
+
+#[test]
+fn optional_asset_attr() {
+    let expected = token_asset_info(&Addr::unchecked(TOKEN));
+    let events = vec![wasm_event(MINT, &[("burn_amount", &format!("100{}", TOKEN))])];
+    assert_eq!(may_parse_asset_attr(&events, &Addr::unchecked(MINT), "protocol_fee", &expected).unwrap(), None);
+
+    let events = vec![wasm_event(MINT, &[("protocol_fee", "1uusd")])];
+    assert_eq!(may_parse_asset_attr(&events, &Addr::unchecked(MINT), "protocol_fee", &expected).unwrap_err(), ReplyParseError::UnexpectedAsset {
+        key: "protocol_fee".to_string(),
+        expected: TOKEN.to_string(),
+        actual: "uusd".to_string(),
+    });
+}
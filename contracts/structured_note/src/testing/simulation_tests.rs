@@ -0,0 +1,121 @@
+use cosmwasm_std::{Addr, Decimal, StdError, Uint128};
+
+use crate::commands::{calculate_aim_loan, plan_target_leverage};
+use crate::error::ContractError;
+use crate::simulation::{estimate_sell_price_impact, plan_deposit_collateral, plan_deposit_mints};
+use crate::state::{DepositState, PositionState};
+
+fn deposit_state(leverage: u8, target_loan: Option<Uint128>) -> DepositState {
+    DepositState {
+        farmer_addr: Addr::unchecked("farmer"),
+        masset_token: Addr::unchecked("masset"),
+        leverage,
+        cur_iteration_index: 0,
+        asset_price_in_collateral_asset: Decimal::from_ratio(10u128, 1u128),
+        pair_addr: Addr::unchecked("pair"),
+        aim_collateral_ratio: Decimal::from_ratio(2u128, 1u128),
+        masset_price: Decimal::from_ratio(10u128, 1u128),
+        max_spread: Decimal::percent(1),
+        target_loan,
+        target_leverage: None,
+    }
+}
+
+#[test]
+fn aim_loan_of_large_collateral_does_not_overflow() {
+    let collateral = Uint128::new(10u128.pow(24));
+    let aim_loan = calculate_aim_loan(collateral, Decimal::from_ratio(2u128, 1u128), Decimal::percent(50)).unwrap();
+    assert_eq!(aim_loan, collateral);
+}
+
+#[test]
+fn aim_loan_rejects_zero_price() {
+    let err = calculate_aim_loan(Uint128::new(1000), Decimal::from_ratio(2u128, 1u128), Decimal::zero()).unwrap_err();
+    assert_eq!(err, StdError::generic_err("Division by zero"));
+}
+
+#[test]
+fn plan_deposit_mints_loops_leverage_iterations() {
+    let state = deposit_state(3, None);
+    let mints = plan_deposit_mints(&state, Uint128::zero(), Uint128::zero(), Uint128::new(1000), Decimal::one()).unwrap();
+    // aim loans 1000 / 20, 1500 / 20 and 1750 / 20 rounded down
+    assert_eq!(mints, vec![Uint128::new(50), Uint128::new(25), Uint128::new(12)]);
+
+    let collateral = plan_deposit_collateral(&state, Uint128::new(1000), &mints, Decimal::one()).unwrap();
+    assert_eq!(collateral, Uint128::new(1870));
+}
+
+#[test]
+fn plan_deposit_mints_stops_on_target_loan() {
+    let state = deposit_state(3, Some(Uint128::new(60)));
+    let mints = plan_deposit_mints(&state, Uint128::zero(), Uint128::zero(), Uint128::new(1000), Decimal::one()).unwrap();
+    assert_eq!(mints, vec![Uint128::new(50), Uint128::new(10)]);
+}
+
+#[test]
+fn plan_deposit_mints_of_existing_position() {
+    let state = deposit_state(1, None);
+    let mints = plan_deposit_mints(&state, Uint128::new(1000), Uint128::new(50), Uint128::new(1000), Decimal::one()).unwrap();
+    assert_eq!(mints, vec![Uint128::new(50)]);
+
+    // loan above aim loan isn't burned by deposit
+    let mints = plan_deposit_mints(&state, Uint128::new(1000), Uint128::new(100), Uint128::zero(), Decimal::one()).unwrap();
+    assert!(mints.is_empty());
+}
+
+#[test]
+fn plan_deposit_mints_rejects_zero_price() {
+    let mut state = deposit_state(3, None);
+    state.asset_price_in_collateral_asset = Decimal::zero();
+    let err = plan_deposit_mints(&state, Uint128::zero(), Uint128::zero(), Uint128::new(1000), Decimal::one()).unwrap_err();
+    assert_eq!(err, StdError::generic_err("Division by zero"));
+}
+
+#[test]
+fn target_leverage_reached_by_iterations() {
+    let state = deposit_state(0, None);
+    let (iterations, target_loan) = plan_target_leverage(&state, &PositionState::default(), Uint128::new(1_000_000), Decimal::one(), Decimal::percent(150), 3).unwrap();
+    assert_eq!((iterations, target_loan), (1, Uint128::new(50_000)));
+}
+
+#[test]
+fn target_leverage_within_tolerance() {
+    let state = deposit_state(0, None);
+    // 3 iterations reach loan 87_500, which is less than 1% short of target loan 88_000
+    let (iterations, target_loan) = plan_target_leverage(&state, &PositionState::default(), Uint128::new(1_000_000), Decimal::one(), Decimal::percent(188), 3).unwrap();
+    assert_eq!((iterations, target_loan), (3, Uint128::new(88_000)));
+}
+
+#[test]
+fn target_leverage_unreachable_within_max_leverage() {
+    let state = deposit_state(0, None);
+    let err = plan_target_leverage(&state, &PositionState::default(), Uint128::new(1_000_000), Decimal::one(), Decimal::percent(190), 3).unwrap_err();
+    assert_eq!(err, ContractError::TargetLeverageUnreachable {
+        target_leverage: Decimal::percent(190),
+        max_leverage: Decimal::permille(1875),
+    });
+
+    let err = plan_target_leverage(&state, &PositionState::default(), Uint128::new(1_000_000), Decimal::one(), Decimal::one(), 3).unwrap_err();
+    assert_eq!(err, ContractError::InvalidTargetLeverage { target_leverage: Decimal::one() });
+}
+
+#[test]
+fn sell_price_impact_of_single_sell() {
+    let impact = estimate_sell_price_impact(Uint128::new(1000), Uint128::new(10000), &[Uint128::new(100)]);
+    // returns 10000 * 100 / 1100 = 909 instead of 1000 at spot price
+    assert_eq!(impact, Decimal::permille(91));
+}
+
+#[test]
+fn sell_price_impact_accumulates_sells() {
+    let impact = estimate_sell_price_impact(Uint128::new(1000), Uint128::new(10000), &[Uint128::new(50), Uint128::new(50)]);
+    // returns 476 and 432 of reserves moved by the first sell
+    assert_eq!(impact, Decimal::permille(92));
+}
+
+#[test]
+fn sell_price_impact_of_empty_pool_and_no_sells() {
+    assert_eq!(estimate_sell_price_impact(Uint128::zero(), Uint128::new(10000), &[Uint128::new(100)]), Decimal::one());
+    assert_eq!(estimate_sell_price_impact(Uint128::new(1000), Uint128::zero(), &[Uint128::new(100)]), Decimal::one());
+    assert_eq!(estimate_sell_price_impact(Uint128::new(1000), Uint128::new(10000), &[]), Decimal::zero());
+}
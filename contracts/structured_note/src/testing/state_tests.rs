@@ -0,0 +1,142 @@
+use cosmwasm_std::testing::MockStorage;
+use cosmwasm_std::{Addr, Decimal, Storage, Uint128};
+
+use structured_note_package::mirror::CDPState;
+
+use crate::state::{add_farmer_to_cdp, amount_to_shares, amount_to_shares_ceil, calculate_position_state, decrease_position_collateral, decrease_position_loan, increase_position_collateral, increase_position_loan, load_cdp, load_position, load_total_collateral, Position, PositionState, save_position};
+
+const MASSET: &str = "masset";
+
+fn cdp_state(collateral: u128, loan: u128) -> CDPState {
+    CDPState {
+        collateral_amount: Uint128::new(collateral),
+        loan_amount: Uint128::new(loan),
+    }
+}
+
+fn join(storage: &mut dyn Storage, farmer: &str) -> Addr {
+    let farmer_addr = Addr::unchecked(farmer);
+    save_position(storage, &Position {
+        farmer_addr: farmer_addr.clone(),
+        masset_token: Addr::unchecked(MASSET),
+        cdp_idx: Uint128::new(1),
+        leverage: 1,
+        loan_shares: Uint128::zero(),
+        collateral_shares: Uint128::zero(),
+        aim_collateral_ratio: Decimal::from_ratio(2u128, 1u128),
+        target_leverage: None,
+    }).unwrap();
+    add_farmer_to_cdp(storage, Uint128::new(1), farmer_addr.clone(), Addr::unchecked(MASSET)).unwrap();
+    farmer_addr
+}
+
+fn position_state(storage: &dyn Storage, farmer_addr: &Addr, cdp_state: &CDPState) -> PositionState {
+    let masset_token = Addr::unchecked(MASSET);
+    let position = load_position(storage, farmer_addr, &masset_token).unwrap();
+    calculate_position_state(&position, &load_cdp(storage, &masset_token).unwrap(), cdp_state)
+}
+
+#[test]
+fn first_depositor_mints_shares_one_to_one() {
+    let mut storage = MockStorage::new();
+    let masset_token = Addr::unchecked(MASSET);
+    let alice = join(&mut storage, "alice");
+
+    let (position, state) = increase_position_collateral(&mut storage, &alice, &masset_token, Uint128::new(1000), &cdp_state(1000, 0)).unwrap();
+    assert_eq!(position.collateral_shares, Uint128::new(1000));
+    let (position, state_after_mint) = increase_position_loan(&mut storage, &alice, &masset_token, Uint128::new(50), &cdp_state(1000, 50)).unwrap();
+    assert_eq!(position.loan_shares, Uint128::new(50));
+
+    assert_eq!(state, PositionState { collateral: Uint128::new(1000), loan: Uint128::zero() });
+    assert_eq!(state_after_mint, PositionState { collateral: Uint128::new(1000), loan: Uint128::new(50) });
+    assert_eq!(load_total_collateral(&storage).unwrap(), Uint128::new(1000));
+}
+
+#[test]
+fn yield_accrues_pro_rata() {
+    let mut storage = MockStorage::new();
+    let masset_token = Addr::unchecked(MASSET);
+    let alice = join(&mut storage, "alice");
+    increase_position_collateral(&mut storage, &alice, &masset_token, Uint128::new(1000), &cdp_state(1000, 0)).unwrap();
+
+    // collateral earned 100 before bob joined, so it belongs to alice only
+    let bob = join(&mut storage, "bob");
+    let (position, _) = increase_position_collateral(&mut storage, &bob, &masset_token, Uint128::new(1000), &cdp_state(2100, 0)).unwrap();
+    assert_eq!(position.collateral_shares, Uint128::new(909));
+    assert_eq!(position_state(&storage, &alice, &cdp_state(2100, 0)).collateral, Uint128::new(1100));
+    assert_eq!(position_state(&storage, &bob, &cdp_state(2100, 0)).collateral, Uint128::new(999));
+
+    // the next 100 is split by shares
+    assert_eq!(position_state(&storage, &alice, &cdp_state(2200, 0)).collateral, Uint128::new(1152));
+    assert_eq!(position_state(&storage, &bob, &cdp_state(2200, 0)).collateral, Uint128::new(1047));
+}
+
+#[test]
+fn shares_round_against_farmer() {
+    assert_eq!(amount_to_shares(Uint128::new(10), Uint128::new(3), Uint128::new(7)), Uint128::new(4));
+    assert_eq!(amount_to_shares_ceil(Uint128::new(10), Uint128::new(3), Uint128::new(7)), Uint128::new(5));
+    assert_eq!(amount_to_shares_ceil(Uint128::new(14), Uint128::new(3), Uint128::new(7)), Uint128::new(6));
+    assert_eq!(amount_to_shares(Uint128::new(10), Uint128::zero(), Uint128::zero()), Uint128::new(10));
+    assert_eq!(amount_to_shares_ceil(Uint128::new(10), Uint128::zero(), Uint128::zero()), Uint128::new(10));
+
+    let mut storage = MockStorage::new();
+    let masset_token = Addr::unchecked(MASSET);
+    let alice = join(&mut storage, "alice");
+    let bob = join(&mut storage, "bob");
+    increase_position_collateral(&mut storage, &alice, &masset_token, Uint128::new(3), &cdp_state(3, 0)).unwrap();
+    increase_position_loan(&mut storage, &alice, &masset_token, Uint128::new(3), &cdp_state(3, 3)).unwrap();
+
+    // collateral shares are rounded down and loan shares up
+    let (position, _) = increase_position_collateral(&mut storage, &bob, &masset_token, Uint128::new(10), &cdp_state(17, 7)).unwrap();
+    assert_eq!(position.collateral_shares, Uint128::new(4));
+    let (position, state) = increase_position_loan(&mut storage, &bob, &masset_token, Uint128::new(10), &cdp_state(17, 17)).unwrap();
+    assert_eq!(position.loan_shares, Uint128::new(5));
+    assert_eq!(state, PositionState { collateral: Uint128::new(9), loan: Uint128::new(10) });
+    // rounded down claims leave dust in CDP instead of overdrawing it
+    assert_eq!(position_state(&storage, &alice, &cdp_state(17, 17)).collateral + state.collateral, Uint128::new(16));
+
+    // withdraw burns collateral shares rounded up, repay burns loan shares rounded down
+    let (position, _) = decrease_position_collateral(&mut storage, &bob, &masset_token, Uint128::new(2), &cdp_state(15, 17)).unwrap();
+    assert_eq!(position.collateral_shares, Uint128::new(3));
+    let (position, _) = decrease_position_loan(&mut storage, &bob, &masset_token, Uint128::new(2), &cdp_state(15, 15)).unwrap();
+    // 2 of 17 is less than one of 8 shares
+    assert_eq!(position.loan_shares, Uint128::new(5));
+}
+
+#[test]
+fn last_farmer_exit_burns_all_shares() {
+    let mut storage = MockStorage::new();
+    let masset_token = Addr::unchecked(MASSET);
+    let alice = join(&mut storage, "alice");
+    let bob = join(&mut storage, "bob");
+    increase_position_collateral(&mut storage, &alice, &masset_token, Uint128::new(1000), &cdp_state(1000, 0)).unwrap();
+    increase_position_loan(&mut storage, &alice, &masset_token, Uint128::new(33), &cdp_state(1000, 33)).unwrap();
+    increase_position_collateral(&mut storage, &bob, &masset_token, Uint128::new(777), &cdp_state(1801, 34)).unwrap();
+    increase_position_loan(&mut storage, &bob, &masset_token, Uint128::new(29), &cdp_state(1801, 63)).unwrap();
+
+    let state = position_state(&storage, &bob, &cdp_state(1801, 63));
+    decrease_position_loan(&mut storage, &bob, &masset_token, state.loan, &cdp_state(1801, 63 - state.loan.u128())).unwrap();
+    let remaining = 1801 - state.collateral.u128();
+    let (position, state) = decrease_position_collateral(&mut storage, &bob, &masset_token, state.collateral, &cdp_state(remaining, 63 - state.loan.u128())).unwrap();
+    assert!(position.collateral_shares.is_zero());
+    assert!(position.loan_shares.is_zero());
+    assert_eq!(state, PositionState::default());
+
+    // alice owns everything left in CDP, rounding dust included
+    let cdp = cdp_state(remaining, load_cdp(&storage, &masset_token).unwrap().loan.u128());
+    let state = position_state(&storage, &alice, &cdp);
+    assert_eq!(state.collateral, cdp.collateral_amount);
+    assert_eq!(state.loan, cdp.loan_amount);
+    decrease_position_loan(&mut storage, &alice, &masset_token, state.loan, &cdp_state(remaining, 0)).unwrap();
+    decrease_position_collateral(&mut storage, &alice, &masset_token, state.collateral, &cdp_state(0, 0)).unwrap();
+
+    let cdp = load_cdp(&storage, &masset_token).unwrap();
+    assert!(cdp.collateral_shares.is_zero());
+    assert!(cdp.loan_shares.is_zero());
+    assert!(cdp.collateral.is_zero());
+    assert!(load_total_collateral(&storage).unwrap().is_zero());
+
+    // emptied CDP mints shares 1:1 again
+    let (position, _) = increase_position_collateral(&mut storage, &bob, &masset_token, Uint128::new(500), &cdp_state(500, 0)).unwrap();
+    assert_eq!(position.collateral_shares, Uint128::new(500));
+}
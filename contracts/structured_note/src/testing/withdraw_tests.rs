@@ -0,0 +1,203 @@
+use cosmwasm_std::testing::{mock_env, mock_info};
+use cosmwasm_std::{Addr, BankMsg, Coin, CosmosMsg, Decimal, SubMsg, to_binary, Uint128, WasmMsg};
+use cw20::Cw20ExecuteMsg;
+use terraswap::asset::{Asset, AssetInfo};
+use terraswap::pair::ExecuteMsg as PairExecuteMsg;
+
+use structured_note_package::structured_note::{ExecuteMsg, ReceiveAs};
+
+use crate::commands::{pay_protect_bounty, return_stable};
+use crate::contract::{execute, reply};
+use crate::error::ContractError;
+use crate::state::{Balances, load_is_open, load_operation, load_total_collateral, load_withdraw_state, may_load_cdp, may_load_position};
+use crate::SubmsgIds;
+use crate::testing::{CDP_IDX, MockDeps, open_position, reply_msg, setup, wasm_event};
+use crate::testing::mock_querier::{ATERRA, MASSET, MIRROR_MINT, PAIR, STABLE_DENOM};
+
+const FARMER: &str = "farmer";
+
+fn transfer(token: &str, recipient: &str, amount: u128) -> SubMsg {
+    SubMsg::new(CosmosMsg::Wasm(WasmMsg::Execute {
+        contract_addr: token.to_string(),
+        msg: to_binary(&Cw20ExecuteMsg::Transfer {
+            recipient: recipient.to_string(),
+            amount: Uint128::new(amount),
+        }).unwrap(),
+        funds: vec![],
+    }))
+}
+
+#[test]
+fn withdraw_returns_only_amounts_received_by_operation() {
+    let mut deps = setup();
+    open_position(&mut deps, FARMER, 1_000_000, 20_000);
+    // left in contract by someone else, it doesn't belong to the farmer
+    deps.querier.set_stable_balance(777);
+    deps.querier.set_token_balance(ATERRA, 3);
+    deps.querier.set_token_balance(MASSET, 5);
+
+    execute(deps.as_mut(), mock_env(), mock_info(FARMER, &[]), ExecuteMsg::Withdraw {
+        masset_token: MASSET.to_string(),
+        aim_collateral: Uint128::new(500_000),
+        aim_collateral_ratio: Decimal::percent(250),
+        max_spread: None,
+        receive_as: Some(ReceiveAs::Aterra),
+    }).unwrap();
+    assert_eq!(load_withdraw_state(&deps.storage, 1).unwrap().balances_before, Balances {
+        stable: Uint128::new(777),
+        aterra: Uint128::new(3),
+        masset: Uint128::new(5),
+    });
+
+    deps.querier.set_stable_balance(10_777);
+    deps.querier.set_token_balance(ATERRA, 43);
+    deps.querier.set_token_balance(MASSET, 7);
+    let res = return_stable(deps.as_mut(), mock_env(), 1).unwrap();
+    assert_eq!(res.messages, vec![
+        SubMsg::new(CosmosMsg::Bank(BankMsg::Send {
+            to_address: FARMER.to_string(),
+            amount: vec![Coin::new(10_000, STABLE_DENOM)],
+        })),
+        transfer(ATERRA, FARMER, 40),
+        transfer(MASSET, FARMER, 2),
+    ]);
+}
+
+#[test]
+fn protection_ignores_stable_held_before_it() {
+    let mut deps = setup();
+    open_position(&mut deps, FARMER, 1_000_000, 60_000);
+    deps.querier.set_stable_balance(777);
+    execute(deps.as_mut(), mock_env(), mock_info("keeper", &[]), ExecuteMsg::ProtectCdp { masset_token: MASSET.to_string() }).unwrap();
+    let bounty = load_operation(&deps.storage, 1).unwrap().protect_state.unwrap().bounty;
+
+    deps.querier.set_cdp(CDP_IDX, 900_000, 50_000);
+    deps.querier.set_stable_balance(777 + bounty.u128());
+    let res = pay_protect_bounty(deps.as_mut(), mock_env(), 1).unwrap();
+    assert_eq!(res.messages, vec![SubMsg::new(CosmosMsg::Bank(BankMsg::Send {
+        to_address: "keeper".to_string(),
+        amount: vec![Coin::new(bounty.u128(), STABLE_DENOM)],
+    }))]);
+}
+
+#[test]
+fn close_position_keeps_mirror_min_collateral_ratio() {
+    let mut deps = setup();
+    open_position(&mut deps, FARMER, 1_000_000, 20_000);
+    execute(deps.as_mut(), mock_env(), mock_info(FARMER, &[]), ExecuteMsg::ClosePosition { masset_token: MASSET.to_string() }).unwrap();
+    assert_eq!(load_withdraw_state(&deps.storage, 1).unwrap().safe_collateral_ratio, Decimal::percent(150));
+}
+
+#[test]
+fn close_position_of_cdp_in_danger_zone_succeeds() {
+    let mut deps = setup();
+    // collateral ratio 1_000_000 / (60_000 * 10) is above Mirror min ratio but below safe one
+    open_position(&mut deps, FARMER, 1_000_000, 60_000);
+    let res = execute(deps.as_mut(), mock_env(), mock_info(FARMER, &[]), ExecuteMsg::ClosePosition { masset_token: MASSET.to_string() }).unwrap();
+    // collateral above 60_000 * 10 * 150% can be withdrawn
+    assert!(res.attributes.iter().any(|attr| attr.key == "amount" && attr.value == "100000"));
+}
+
+#[test]
+fn withdraw_is_stopped_by_pool_price_deviation() {
+    let mut deps = setup();
+    open_position(&mut deps, FARMER, 1_000_000, 20_000);
+    // pool price 12 is 20% above oracle price
+    deps.querier.pool = (Uint128::new(1_000_000_000), Uint128::new(12_000_000_000));
+
+    let err = execute(deps.as_mut(), mock_env(), mock_info(FARMER, &[]), ExecuteMsg::Withdraw {
+        masset_token: MASSET.to_string(),
+        aim_collateral: Uint128::new(500_000),
+        aim_collateral_ratio: Decimal::percent(250),
+        max_spread: None,
+        receive_as: None,
+    }).unwrap_err();
+    assert_eq!(err, ContractError::PriceDeviationExceeded {
+        pool_price: Decimal::from_ratio(12u128, 1u128),
+        oracle_price: Decimal::from_ratio(10u128, 1u128),
+        deviation: Decimal::percent(20),
+        max_price_deviation: Decimal::percent(5),
+    });
+}
+
+#[test]
+fn single_farmer_closes_position_and_deposits_again() {
+    let mut deps = setup();
+    let masset_token = Addr::unchecked(MASSET);
+    open_position(&mut deps, FARMER, 1_000_000, 0);
+    execute(deps.as_mut(), mock_env(), mock_info(FARMER, &[]), ExecuteMsg::ClosePosition { masset_token: MASSET.to_string() }).unwrap();
+
+    // Mirror removes position after all collateral is withdrawn
+    deps.querier.set_cdp(CDP_IDX, 0, 0);
+    let events = vec![wasm_event(MIRROR_MINT, &[("withdraw_amount", &format!("1000000{}", ATERRA))])];
+    reply(deps.as_mut(), mock_env(), reply_msg(SubmsgIds::Exit.reply_id(1), events)).unwrap();
+    deps.querier.set_stable_balance(1_000_000);
+    let res = reply(deps.as_mut(), mock_env(), reply_msg(SubmsgIds::RedeemStable.reply_id(1), vec![])).unwrap();
+    assert_eq!(res.messages, vec![SubMsg::new(CosmosMsg::Bank(BankMsg::Send {
+        to_address: FARMER.to_string(),
+        amount: vec![Coin::new(1_000_000, STABLE_DENOM)],
+    }))]);
+    assert_eq!(may_load_position(&deps.storage, &Addr::unchecked(FARMER), &masset_token).unwrap(), None);
+    assert_eq!(may_load_cdp(&deps.storage, &masset_token).unwrap(), None);
+    assert_eq!(load_total_collateral(&deps.storage).unwrap(), Uint128::zero());
+
+    deps.querier.set_stable_balance(0);
+    execute(deps.as_mut(), mock_env(), mock_info(FARMER, &[Coin::new(100_000, STABLE_DENOM)]), ExecuteMsg::Deposit {
+        masset_token: MASSET.to_string(),
+        leverage: Some(1),
+        target_leverage: None,
+        aim_collateral_ratio: Decimal::percent(200),
+        max_spread: None,
+    }).unwrap();
+    // new CDP is opened by the deposit loop
+    assert!(load_is_open(&deps.storage, 2).unwrap());
+}
+
+// Raw withdraw of 50_000 aterra from position 1_000_000 / 50_000
+fn raw_withdraw(deps: &mut MockDeps, receive_as: ReceiveAs) -> Vec<SubMsg> {
+    open_position(deps, FARMER, 1_000_000, 50_000);
+    execute(deps.as_mut(), mock_env(), mock_info(FARMER, &[]), ExecuteMsg::RawWithdraw {
+        masset_token: MASSET.to_string(),
+        amount: Uint128::new(50_000),
+        receive_as: Some(receive_as),
+    }).unwrap();
+
+    deps.querier.set_cdp(CDP_IDX, 950_000, 50_000);
+    deps.querier.set_token_balance(ATERRA, 50_000);
+    let events = vec![wasm_event(MIRROR_MINT, &[("withdraw_amount", &format!("50000{}", ATERRA))])];
+    reply(deps.as_mut(), mock_env(), reply_msg(SubmsgIds::WithdrawCollateral.reply_id(1), events)).unwrap().messages
+}
+
+#[test]
+fn withdrawn_collateral_is_returned_as_aterra() {
+    let mut deps = setup();
+    // aterra isn't redeemed, so withdraw finishes on WithdrawCollateral reply
+    assert_eq!(raw_withdraw(&mut deps, ReceiveAs::Aterra), vec![transfer(ATERRA, FARMER, 50_000)]);
+}
+
+#[test]
+fn withdrawn_collateral_is_returned_as_masset() {
+    let mut deps = setup();
+    let messages = raw_withdraw(&mut deps, ReceiveAs::Masset);
+    assert_eq!(messages.len(), 1);
+    assert_eq!(messages[0].id, SubmsgIds::RedeemStable.reply_id(1));
+
+    deps.querier.set_stable_balance(50_000);
+    let res = reply(deps.as_mut(), mock_env(), reply_msg(SubmsgIds::RedeemStable.reply_id(1), vec![])).unwrap();
+    // stable is swapped to masset which pair sends to farmer directly
+    assert_eq!(res.messages, vec![
+        SubMsg::new(CosmosMsg::Wasm(WasmMsg::Execute {
+            contract_addr: PAIR.to_string(),
+            msg: to_binary(&PairExecuteMsg::Swap {
+                offer_asset: Asset {
+                    info: AssetInfo::NativeToken { denom: STABLE_DENOM.to_string() },
+                    amount: Uint128::new(50_000),
+                },
+                belief_price: Some(Decimal::from_ratio(10u128, 1u128)),
+                max_spread: Some(Decimal::percent(1)),
+                to: Some(FARMER.to_string()),
+            }).unwrap(),
+            funds: vec![Coin::new(50_000, STABLE_DENOM)],
+        })),
+    ]);
+}
@@ -1,4 +1,5 @@
-use cosmwasm_std::{Addr, BalanceResponse, BankQuery, Decimal, Event, QuerierWrapper, QueryRequest, StdError, StdResult, Uint128};
+use cosmwasm_std::{Addr, BalanceResponse, BankQuery, Decimal, Fraction, QuerierWrapper, QueryRequest, StdError, StdResult, to_binary, Uint128, WasmQuery};
+use cw20::{BalanceResponse as Cw20BalanceResponse, Cw20QueryMsg};
 
 // Math
 const DECIMAL_FRACTIONAL: Uint128 = Uint128::new(1_000_000_000u128);
@@ -19,50 +20,26 @@ pub fn reverse_decimal(decimal: Decimal) -> Decimal {
     Decimal::from_ratio(DECIMAL_FRACTIONAL, decimal * DECIMAL_FRACTIONAL)
 }
 
-//Paring
-pub fn get_amount_from_response_raw_attr(events: Vec<Event>, raw_attr_name: String) -> StdResult<String> {
-    events
-        .into_iter()
-        .map(|event| event.attributes)
-        .flatten()
-        .find(|attr| attr.key == raw_attr_name.clone())
-        .map(|attr| attr.value)
-        .ok_or_else(|| {
-            StdError::generic_err(format!("Attr '{}' not found", &raw_attr_name))
-        })
-}
-
-pub fn get_amount_from_response_asset_as_string_attr(events: Vec<Event>, attr_name: String) -> StdResult<String> {
-    let attr_value = events
-        .into_iter()
-        .map(|event| event.attributes)
-        .flatten()
-        .find(|attr| attr.key == attr_name.clone())
-        .map(|attr| attr.value)
-        .ok_or_else(|| {
-            StdError::generic_err(format!("Attr '{}' not found", &attr_name))
-        })?;
+pub fn divide_by_decimal(amount: Uint128, decimal: Decimal) -> StdResult<Uint128> {
+    if decimal.is_zero() {
+        return Err(StdError::generic_err("Division by zero"));
+    }
 
-    let result = get_amount_from_asset_as_string(&attr_value);
-    return match result {
-        None => {
-            Err(StdError::generic_err(format!("Fail to parse attr. Attr value: '{}'", attr_value)))
-        }
-        Some(a) => {
-            Ok(a)
-        }
-    };
+    Ok(amount.multiply_ratio(decimal.denominator(), decimal.numerator()))
 }
 
-// asset as string format is 0123terra1..... or 0123uusd(amount + token_addr or denom without spaces)
-// split mint_amount by the first met 't' or 'u'
-pub fn get_amount_from_asset_as_string(data: &str) -> Option<String> {
-    for (i, c) in data.chars().enumerate() {
-        if c == 't' || c == 'u' {
-            return Some(data[..i].to_string());
-        }
-    }
-    None
+pub fn query_token_balance(
+    querier: &QuerierWrapper,
+    token_addr: &Addr,
+    account_addr: &Addr,
+) -> StdResult<Uint128> {
+    let balance: Cw20BalanceResponse = querier.query(&QueryRequest::Wasm(WasmQuery::Smart {
+        contract_addr: token_addr.to_string(),
+        msg: to_binary(&Cw20QueryMsg::Balance {
+            address: account_addr.to_string(),
+        })?,
+    }))?;
+    Ok(balance.balance)
 }
 
 pub fn query_balance(
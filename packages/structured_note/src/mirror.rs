@@ -67,7 +67,7 @@ pub struct MirrorCDPResponse {
     pub asset: AssetRaw,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, JsonSchema)]
 pub struct CDPState {
     pub collateral_amount: Uint128,
     pub loan_amount: Uint128,
@@ -1,4 +1,7 @@
+use std::fmt;
+
 use cosmwasm_std::{Decimal, Uint128};
+use cw20::Cw20ReceiveMsg;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
@@ -11,7 +14,13 @@ pub struct InstantiateMsg {
     pub aterra_addr: String,
     pub nexus_treasury: String,
     pub protocol_fee: Decimal,
-    pub min_over_collateralization: Decimal,
+    pub default_max_spread: Decimal,
+    pub max_price_impact: Decimal,
+    pub rebalance_band: Decimal,
+    pub protect_bounty: Decimal,
+    pub max_oracle_age: u64,
+    pub max_price_deviation: Decimal,
+    pub deposit_cap: Option<Uint128>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
@@ -20,7 +29,11 @@ pub enum ExecuteMsg {
     Deposit {
         masset_token: String,
         leverage: Option<u8>,
+        // exposure as collateral value to position net value, overrides leverage iterations.
+        // Target of position opening deposit is kept for later deposits and rebalances of the position
+        target_leverage: Option<Decimal>,
         aim_collateral_ratio: Decimal,
+        max_spread: Option<Decimal>,
     },
     RawDeposit {
         masset_token: String,
@@ -29,18 +42,340 @@ pub enum ExecuteMsg {
         masset_token: String,
         aim_collateral: Uint128,
         aim_collateral_ratio: Decimal,
+        max_spread: Option<Decimal>,
+        receive_as: Option<ReceiveAs>,
     },
     RawWithdraw {
         masset_token: String,
         amount: Uint128,
+        receive_as: Option<ReceiveAs>,
+    },
+    // Repays whole loan, withdraws all collateral and returns stable and leftover masset to farmer
+    ClosePosition {
+        masset_token: String,
+    },
+    // Anyone can bring position back to its aim_collateral_ratio once it drifts out of rebalance_band
+    Rebalance {
+        farmer_addr: String,
+        masset_token: String,
+    },
+    // Anyone can delever shared CDP which fell below safe collateral ratio and get protect_bounty share of repaid value
+    ProtectCdp {
+        masset_token: String,
+    },
+    UpdateConfig(Box<UpdateConfigMsg>),
+    // Adds masset to the list of allowed ones or updates its parameters
+    RegisterAsset {
+        masset_token: String,
+        min_over_collateralization: Decimal,
+        max_leverage: u8,
+        deposit_cap: Option<Uint128>,
+        loan_cap: Option<Uint128>,
+        pair_addr: Option<String>,
+    },
+    // Masset can be removed only when it has no positions, use FreezeAsset to stop deposits
+    RemoveAsset {
+        masset_token: String,
+    },
+    // Governance stops new deposits to all or single masset, withdraws and exits stay available
+    Pause {},
+    Unpause {},
+    FreezeAsset {
+        masset_token: String,
+    },
+    UnfreezeAsset {
+        masset_token: String,
+    },
+    // aterra sent by cw20 Send with Cw20HookMsg
+    Receive(Cw20ReceiveMsg),
+}
+
+// Asset of withdrawn collateral returned to farmer, leftover masset is returned as is
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ReceiveAs {
+    #[default]
+    Stable,
+    Aterra,
+    Masset,
+}
+
+impl fmt::Display for ReceiveAs {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReceiveAs::Stable => write!(f, "stable"),
+            ReceiveAs::Aterra => write!(f, "aterra"),
+            ReceiveAs::Masset => write!(f, "masset"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Cw20HookMsg {
+    // the same as ExecuteMsg::Deposit, received aterra is deposited to CDP directly
+    Deposit {
+        masset_token: String,
+        leverage: Option<u8>,
+        target_leverage: Option<Decimal>,
+        aim_collateral_ratio: Decimal,
+        max_spread: Option<Decimal>,
     },
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, JsonSchema)]
+pub struct UpdateConfigMsg {
+    pub governance_contract: Option<String>,
+    pub mirror_mint_contract: Option<String>,
+    pub anchor_market_contract: Option<String>,
+    pub aterra_addr: Option<String>,
+    pub nexus_treasury: Option<String>,
+    pub protocol_fee: Option<Decimal>,
+    pub default_max_spread: Option<Decimal>,
+    pub max_price_impact: Option<Decimal>,
+    pub rebalance_band: Option<Decimal>,
+    pub protect_bounty: Option<Decimal>,
+    pub max_oracle_age: Option<u64>,
+    pub max_price_deviation: Option<Decimal>,
+    pub deposit_cap: Option<Uint128>,
+    // Removes global deposit cap, can't be combined with deposit_cap
+    pub clear_deposit_cap: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct MigrateMsg {}
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum QueryMsg {
     Config {},
+    Status {},
+    AssetConfig {
+        masset_token: String,
+    },
+    // Collateral and loan which deposits can add to masset CDP under per-asset and global caps
+    DepositCapacity {
+        masset_token: String,
+    },
+    AllAssetConfigs {
+        start_after: Option<String>,
+        limit: Option<u32>,
+    },
     Position {
+        farmer_addr: String,
         masset_token: String,
-    }
+    },
+    FarmerPositions {
+        farmer_addr: String,
+        start_after: Option<String>,
+        limit: Option<u32>,
+    },
+    AllPositions {
+        // (farmer_addr, masset_token) of the last position on previous page
+        start_after: Option<(String, String)>,
+        limit: Option<u32>,
+    },
+    FeePreview {
+        deposit_amount: Uint128,
+    },
+    Cdp {
+        masset_token: String,
+    },
+    AllCdps {
+        start_after: Option<String>,
+        limit: Option<u32>,
+    },
+    PositionHealth {
+        farmer_addr: String,
+        masset_token: String,
+    },
+    // Replays Deposit loop with current Anchor exchange rate, Mirror oracle prices and Terraswap pool
+    SimulateDeposit {
+        farmer_addr: String,
+        masset_token: String,
+        deposit_amount: Uint128,
+        leverage: Option<u8>,
+        target_leverage: Option<Decimal>,
+        aim_collateral_ratio: Decimal,
+    },
+    // Replays Withdraw loop with current Anchor exchange rate, Mirror oracle prices and Terraswap pool
+    SimulateWithdraw {
+        farmer_addr: String,
+        masset_token: String,
+        aim_collateral: Uint128,
+        aim_collateral_ratio: Decimal,
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct StatusResponse {
+    pub is_paused: bool,
+    pub frozen_assets: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct AssetConfigResponse {
+    pub masset_token: String,
+    pub min_over_collateralization: Decimal,
+    pub max_leverage: u8,
+    pub deposit_cap: Option<Uint128>,
+    pub loan_cap: Option<Uint128>,
+    pub pair_addr: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct AssetConfigsResponse {
+    pub asset_configs: Vec<AssetConfigResponse>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct DepositCapacityResponse {
+    pub masset_token: String,
+    // tracked amounts of masset CDP and of all CDPs
+    pub cdp_collateral: Uint128,
+    pub cdp_loan: Uint128,
+    pub total_collateral: Uint128,
+    pub asset_deposit_cap: Option<Uint128>,
+    pub asset_loan_cap: Option<Uint128>,
+    pub global_deposit_cap: Option<Uint128>,
+    // None if not capped
+    pub collateral_capacity: Option<Uint128>,
+    pub loan_capacity: Option<Uint128>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct FeePreviewResponse {
+    pub protocol_fee: Decimal,
+    pub fee_amount: Uint128,
+    pub net_deposit_amount: Uint128,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct PositionResponse {
+    pub farmer_addr: String,
+    pub masset_token: String,
+    pub cdp_idx: Uint128,
+    pub leverage: u8,
+    // pro-rata claims on Mirror position
+    pub loan: Uint128,
+    pub collateral: Uint128,
+    pub loan_shares: Uint128,
+    pub collateral_shares: Uint128,
+    pub aim_collateral_ratio: Decimal,
+    pub target_leverage: Option<Decimal>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct PositionsResponse {
+    pub positions: Vec<PositionResponse>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct CdpResponse {
+    pub idx: Uint128,
+    pub masset_token: String,
+    pub farmers: Vec<String>,
+    // Mirror position state
+    pub mirror_collateral: Uint128,
+    pub mirror_loan: Uint128,
+    // sums of farmers pro-rata claims
+    pub farmers_collateral: Uint128,
+    pub farmers_loan: Uint128,
+    // total shares of CDP and sums of farmers shares
+    pub collateral_shares: Uint128,
+    pub loan_shares: Uint128,
+    pub farmers_collateral_shares: Uint128,
+    pub farmers_loan_shares: Uint128,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct CdpsResponse {
+    pub cdps: Vec<CdpResponse>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct DepositIterationResponse {
+    pub mint_amount: Uint128,
+    pub stable_received: Uint128,
+    pub collateral_received: Uint128,
+    // position state after iteration
+    pub collateral: Uint128,
+    pub loan: Uint128,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct SimulateDepositResponse {
+    pub leverage: u8,
+    // part of deposit amount which fits into deposit caps, the rest is refunded
+    pub accepted_amount: Uint128,
+    pub refund_amount: Uint128,
+    pub fee_amount: Uint128,
+    pub net_deposit_amount: Uint128,
+    // aterra received for net deposit before the first mint
+    pub deposit_collateral: Uint128,
+    pub iterations: Vec<DepositIterationResponse>,
+    pub collateral: Uint128,
+    pub loan: Uint128,
+    // none if nothing is minted
+    pub collateral_ratio: Option<Decimal>,
+    // collateral value to position net value
+    pub effective_leverage: Decimal,
+    pub spread_amount: Uint128,
+    pub commission_amount: Uint128,
+    // the same estimation which Deposit checks against max_price_impact
+    pub price_impact: Decimal,
+    // seconds since the oldest oracle price update, Deposit and Withdraw fail when it's above max_oracle_age
+    pub price_age: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct WithdrawRoundResponse {
+    pub withdraw_amount: Uint128,
+    pub stable_redeemed: Uint128,
+    pub offer_amount: Uint128,
+    pub masset_bought: Uint128,
+    pub burn_amount: Uint128,
+    // Mirror protocol fee charged from collateral on burn
+    pub protocol_fee: Uint128,
+    // position state after round
+    pub collateral: Uint128,
+    pub loan: Uint128,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct SimulateWithdrawResponse {
+    pub aim_loan: Uint128,
+    pub rounds: Vec<WithdrawRoundResponse>,
+    pub collateral: Uint128,
+    pub loan: Uint128,
+    // false if loop gets stuck before reaching aim, so Withdraw would fail
+    pub reaches_aim: bool,
+    // swap costs in masset
+    pub spread_amount: Uint128,
+    pub commission_amount: Uint128,
+    pub return_amount: Uint128,
+    pub return_masset_amount: Uint128,
+    // seconds since the oldest oracle price update, Deposit and Withdraw fail when it's above max_oracle_age
+    pub price_age: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct PositionHealthResponse {
+    pub collateral: Uint128,
+    pub loan: Uint128,
+    // none if position has no loan
+    pub collateral_ratio: Option<Decimal>,
+    pub mirror_min_collateral_ratio: Decimal,
+    pub min_over_collateralization: Decimal,
+    pub safe_collateral_ratio: Decimal,
+    // relative rise of masset price in collateral asset which brings position to Mirror min collateral ratio
+    pub liquidation_price_move: Option<Decimal>,
+    // masset price in stable_denom at which position gets liquidated, collateral price being unchanged
+    pub liquidation_price: Option<Decimal>,
+    // values in stable_denom
+    pub collateral_value: Uint128,
+    pub loan_value: Uint128,
+    pub net_asset_value: Uint128,
+    // seconds since the oldest oracle price update, Deposit and Withdraw fail when it's above max_oracle_age
+    pub price_age: u64,
 }